@@ -0,0 +1,123 @@
+//Guards and role assignments shared by the admin endpoints.
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::storage::{self, impl_storable_json, Memory};
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    // Controllers are always admins, the role cannot be assigned.
+    Admin,
+    // May upload model versions to the registry.
+    Uploader,
+    // A clinic device taking part in federated learning.
+    Clinic,
+    // Everybody else.
+    User,
+}
+
+impl_storable_json!(Role);
+
+thread_local! {
+    static ROLES: RefCell<StableBTreeMap<Principal, Role, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::ROLES_MEMORY_ID))
+    );
+}
+
+// The system API traps outside of a canister, so native tests act as the anonymous principal.
+#[cfg(target_arch = "wasm32")]
+pub fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn caller() -> Principal {
+    Principal::anonymous()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn is_controller_principal(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_controller_principal(_principal: &Principal) -> bool {
+    false
+}
+
+pub fn role_of(principal: &Principal) -> Role {
+    if is_controller_principal(principal) {
+        return Role::Admin;
+    }
+    ROLES.with(|r| r.borrow().get(principal)).unwrap_or(Role::User)
+}
+
+pub fn roles() -> Vec<(Principal, Role)> {
+    ROLES.with(|r| r.borrow().iter().collect())
+}
+
+/// Replaces all role assignments, used when a snapshot is restored.
+pub fn replace_roles(assignments: Vec<(Principal, Role)>) {
+    ROLES.with(|r| {
+        let mut r = r.borrow_mut();
+        let existing: Vec<Principal> = r.iter().map(|(principal, _)| principal).collect();
+        for principal in existing {
+            r.remove(&principal);
+        }
+        for (principal, role) in assignments {
+            r.insert(principal, role);
+        }
+    });
+}
+
+/// Only controllers of the canister may call the guarded method.
+pub fn is_controller() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        Ok(())
+    } else {
+        Err("user is not a controller".to_string())
+    }
+}
+
+/// Controllers and principals holding the `Uploader` role may call the guarded method.
+pub fn is_uploader() -> Result<(), String> {
+    match role_of(&ic_cdk::caller()) {
+        Role::Admin | Role::Uploader => Ok(()),
+        _ => Err("user is not an uploader".to_string()),
+    }
+}
+
+/// Controllers, uploaders and clinics may take part in federated learning.
+pub fn is_participant() -> Result<(), String> {
+    match role_of(&ic_cdk::caller()) {
+        Role::Admin | Role::Uploader | Role::Clinic => Ok(()),
+        Role::User => Err("user is not a federated learning participant".to_string()),
+    }
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn assign_role(principal: Principal, role: Role) -> Result<(), String> {
+    if role == Role::Admin {
+        return Err("The admin role is reserved for controllers.".to_string());
+    }
+    ROLES.with(|r| r.borrow_mut().insert(principal, role));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn revoke_role(principal: Principal) {
+    ROLES.with(|r| r.borrow_mut().remove(&principal));
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn list_roles() -> Vec<(Principal, Role)> {
+    roles()
+}
+
+#[ic_cdk::query]
+pub fn my_role() -> Role {
+    role_of(&ic_cdk::caller())
+}
@@ -1,60 +1,1325 @@
-use crate::client::{MODEL_WEIGHTS, get_model_weights, Model};
-use candle_core::{Tensor, Result, DType, Device};
-use std::error::Error;
-use postcard;
-use serde::{Serialize, Deserialize};
-use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
-};
+use crate::access::{is_controller, is_participant};
+use crate::aggregation::{self, Aggregator, WeightedUpdate};
+use crate::anomaly::{self, AnomalyConfig, AnomalyScore, Submission};
+use crate::checkpoint::Checkpoint;
+use crate::deadlines;
+use crate::delta::{self, UpdateEncoding};
+use crate::hierarchy::{self, ClinicContribution};
+use crate::incentives::{self, ContributionMethod, ContributionScore, RoundContributions, ScoringJob};
+use crate::lineage::VersionOrigin;
+use crate::rng::Rng;
+use crate::fedbuff::{self, BufferedUpdate, StalenessFunction};
+use crate::optimizer::{self, ServerOptimizer};
+use crate::clinics::{self, SelectionStrategy};
+use crate::client::{ClientUpdate, TrainingConfig, MALARIA_MODEL, MALARIA_TASK, MODEL_CONFIG};
+use crate::client_type::{MALARIA_MODEL_MAL, MALARIA_STAGE_TASK, MODEL_CONFIG_MAL};
+use crate::malaria_types::{MALARIA_MODEL_TYPES, MALARIA_TYPE_TASK, MODEL_CONFIG_TYPES};
+use crate::privacy::{self, DifferentialPrivacy};
+use crate::quota;
+use crate::validation::{self, ValidationGate, ValidationReport};
+use crate::registry::{self, ArtifactKind, ResolvedModel};
+use crate::secure_aggregation::{self, SecAggPhase, SecAggSession, SecureAggregationConfig};
+use crate::storage::{self, impl_storable_json, Memory};
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
-const DEVICE: Device = Device::Cpu;
+//Federated training runs in rounds per task. A round is opened against the current global
+//version and collects client updates until its quorum is reached or its deadline passes.
+//In buffered mode a round is the FedBuff buffer instead: it has no deadline, any active node
+//may add to it at any time, and it closes after a fixed number of arrivals.
+//The aggregate is then published and promoted as the next global version, and the
+//next round opens right away.
 
-#[derive(Serialize, Deserialize, Clone)]
-struct WeightsSerialized {
-    ln1_weight: Vec<f32>,
+// Kind of the deadline timers of federated rounds.
+const DEADLINE: &str = "round";
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GlobalModelChunk {
+    pub version: u64,
+    pub total_size: u64,
+    pub chunk_count: u64,
+    pub data: Vec<u8>,
+    // The config is small enough to come with every chunk.
+    pub config: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum TrainingMode {
+    // Synchronous rounds with invitations, quorum and deadline.
+    #[default]
+    Rounds,
+    // Asynchronous FedBuff, aggregating every `buffer_size` arrivals.
+    Buffered {
+        buffer_size: u64,
+        server_lr: f64,
+        staleness: StalenessFunction,
+        // Versions an update may lag behind the buffer. Every flush publishes a version, so
+        // this has to be positive for `staleness` to ever apply.
+        #[serde(default)]
+        max_staleness: u64,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoundConfig {
+    // Participants after which the round closes before its deadline.
+    pub quorum: u64,
+    // Participants needed at the deadline, otherwise the round is abandoned.
+    pub min_participants: u64,
+    pub deadline_seconds: u64,
+    // Versions an update may lag behind a synchronous round, 0 rejects every stale update.
+    pub max_staleness: u64,
+    // Each version of lag multiplies the weight of an update by this factor.
+    pub staleness_decay: f64,
+    #[serde(default)]
+    pub selection: SelectionStrategy,
+    #[serde(default)]
+    pub aggregator: Aggregator,
+    // Applied to the aggregate before it is published.
+    #[serde(default)]
+    pub server_optimizer: ServerOptimizer,
+    // Local training spec handed to the invited clients.
+    #[serde(default)]
+    pub training: TrainingConfig,
+    // Rounds aggregate masked updates the canister can only sum, see `secure_aggregation`.
+    #[serde(default)]
+    pub secure_aggregation: Option<SecureAggregationConfig>,
+    // Clips updates and adds noise to the aggregate, replacing the aggregator.
+    #[serde(default)]
+    pub differential_privacy: Option<DifferentialPrivacy>,
+    // Candidates that regress on the validation set versus production are not published.
+    #[serde(default)]
+    pub validation_gate: Option<ValidationGate>,
+    // Submissions that stand out from the cohort are quarantined before aggregation.
+    #[serde(default)]
+    pub anomaly_detection: Option<AnomalyConfig>,
+    #[serde(default)]
+    pub mode: TrainingMode,
+    // How the updates of a published round are scored for the contribution ledger.
+    #[serde(default)]
+    pub contribution: ContributionMethod,
+}
+
+impl Default for RoundConfig {
+    fn default() -> Self {
+        RoundConfig {
+            quorum: 3,
+            min_participants: 1,
+            deadline_seconds: 24 * 60 * 60,
+            max_staleness: 0,
+            staleness_decay: 0.5,
+            selection: SelectionStrategy::default(),
+            aggregator: Aggregator::default(),
+            server_optimizer: ServerOptimizer::default(),
+            training: TrainingConfig::default(),
+            secure_aggregation: None,
+            differential_privacy: None,
+            validation_gate: None,
+            anomaly_detection: None,
+            mode: TrainingMode::default(),
+            contribution: ContributionMethod::default(),
+        }
+    }
+}
+
+impl_storable_json!(RoundConfig);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RoundState {
+    Open,
+    Published,
+    // The deadline passed without enough participants, or before a secure round got to
+    // unmasking.
+    Abandoned,
+    Failed(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Participation {
+    pub principal: Principal,
+    pub num_samples: u64,
+    pub loss: f64,
+    pub model_version: u64,
+    // Sample count after the staleness discount.
+    pub weight: f64,
+    pub submitted_at: u64,
+    #[serde(default)]
+    pub proximal_mu: f64,
+    #[serde(default)]
+    pub encoding: UpdateEncoding,
+    #[serde(default)]
+    pub uploaded_bytes: u64,
+    // Size of the reconstructed checkpoint over the uploaded size.
+    #[serde(default)]
+    pub compression_ratio: f64,
+    // Clinics behind a district update, empty for updates submitted by a clinic itself.
+    #[serde(default)]
+    pub contributors: Vec<ClinicContribution>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoundStatus {
+    pub task: String,
+    pub round: u64,
+    pub state: RoundState,
+    // Version updates have to be trained from.
+    pub base_version: u64,
+    pub opened_at: u64,
+    pub deadline: u64,
+    pub closed_at: Option<u64>,
+    // Registered nodes selected for this round, only they may submit.
+    #[serde(default)]
+    pub invited: Vec<Principal>,
+    pub participants: Vec<Participation>,
+    pub num_samples: u64,
+    pub mean_loss: Option<f64>,
+    // Limits of the round config when the round opened, so that a config change only
+    // applies from the next round on.
+    #[serde(default = "default_quorum")]
+    pub quorum: u64,
+    #[serde(default = "default_min_participants")]
+    pub min_participants: u64,
+    #[serde(default)]
+    pub max_staleness: u64,
+    #[serde(default = "default_staleness_decay")]
+    pub staleness_decay: f64,
+    // Aggregator chosen when the round opened.
+    #[serde(default)]
+    pub aggregator: Aggregator,
+    #[serde(default)]
+    pub server_optimizer: ServerOptimizer,
+    #[serde(default)]
+    pub training: TrainingConfig,
+    #[serde(default)]
+    pub mode: TrainingMode,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub differential_privacy: Option<DifferentialPrivacy>,
+    // Checks and scoring applied when the round closes, also fixed when it opened.
+    #[serde(default)]
+    pub validation_gate: Option<ValidationGate>,
+    #[serde(default)]
+    pub anomaly_detection: Option<AnomalyConfig>,
+    #[serde(default)]
+    pub contribution: ContributionMethod,
+    // Participants the aggregator left out, and those whose update it scaled down.
+    #[serde(default)]
+    pub excluded: Vec<Principal>,
+    #[serde(default)]
+    pub clipped: Vec<Principal>,
+    // Scores of every submission when anomaly detection is on, and the quarantined principals.
+    #[serde(default)]
+    pub anomaly_scores: Vec<AnomalyScore>,
+    #[serde(default)]
+    pub quarantined: Vec<Principal>,
+    // Validation of the candidate when the task has a validation gate.
+    #[serde(default)]
+    pub validation: Option<ValidationReport>,
+    // Global version published when the round closed.
+    pub result_version: Option<u64>,
+}
+
+impl_storable_json!(RoundStatus);
+
+// Limits of rounds stored before they were frozen, those of the default config.
+fn default_quorum() -> u64 {
+    RoundConfig::default().quorum
+}
+
+fn default_min_participants() -> u64 {
+    RoundConfig::default().min_participants
+}
+
+fn default_staleness_decay() -> f64 {
+    RoundConfig::default().staleness_decay
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Invitation {
+    pub task: String,
+    pub round: u64,
+    pub invited: bool,
+    pub base_version: u64,
+    pub deadline: u64,
+    pub training: TrainingConfig,
+    // Updates have to go through the secure aggregation protocol.
+    pub secure: bool,
 }
 
-//Define a heap memory to store the aggregated model weights which are then to be sent to the server. 
 thread_local! {
-    pub static AGGREGATED_MODEL: RefCell<Option<(WeightsSerialized, usize)>> = RefCell::new(None);
-}
-
-/// Aggregates a list of serialized model weights using federated averaging.
-pub fn update_aggregated_model(new_serialized: Vec<u8>, new_samples: usize) -> candle_core::Result<()> {
-    // Deserialize the new client model.
-    let new_model: WeightsSerialized = postcard::from_bytes(&new_serialized)
-        .map_err(|e| candle_core::Error::msg(format!("Deserialization error: {:?}", e)))?;
-        
-    AGGREGATED_MODEL.with(|cell| {
-        let mut agg = cell.borrow_mut();
-        if let Some((current_model, current_samples)) = agg.take() {
-            // Ensure both models have the same weight vector length.
-            if current_model.ln1_weight.len() != new_model.ln1_weight.len() {
-                return Err(candle_core::Error::msg("Mismatched weight lengths"));
+    static ROUNDS: RefCell<StableBTreeMap<String, RoundStatus, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::ROUNDS_MEMORY_ID))
+    );
+
+    static ROUND_CONFIGS: RefCell<StableBTreeMap<String, RoundConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::ROUND_CONFIG_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_rounds() -> Vec<(String, RoundStatus)> {
+    ROUNDS.with(storage::entries)
+}
+
+/// Replaces every round, used when a snapshot is restored.
+pub(crate) fn replace_rounds(rounds: Vec<(String, RoundStatus)>) {
+    ROUNDS.with(|m| storage::replace_entries(m, rounds))
+}
+
+pub(crate) fn export_round_configs() -> Vec<(String, RoundConfig)> {
+    ROUND_CONFIGS.with(storage::entries)
+}
+
+/// Replaces every round config, used when a snapshot is restored.
+pub(crate) fn replace_round_configs(round_configs: Vec<(String, RoundConfig)>) {
+    ROUND_CONFIGS.with(|m| storage::replace_entries(m, round_configs))
+}
+
+/// The model clients train against: the production version of `task`,
+/// or the legacy upload before the first promotion.
+pub fn global_model(task: &str) -> Result<ResolvedModel, String> {
+    let (model, config) = legacy_keys(task)?;
+    let resolved = registry::resolve(task, model, config);
+    if resolved.weights.is_empty() {
+        return Err("Model weights not found in stable storage.".to_string());
+    }
+    Ok(resolved)
+}
+
+/// Keys of the model and config `task` was served from before its first promotion.
+fn legacy_keys(task: &str) -> Result<(&'static str, &'static str), String> {
+    match task {
+        MALARIA_TASK => Ok((MALARIA_MODEL, MODEL_CONFIG)),
+        MALARIA_STAGE_TASK => Ok((MALARIA_MODEL_MAL, MODEL_CONFIG_MAL)),
+        MALARIA_TYPE_TASK => Ok((MALARIA_MODEL_TYPES, MODEL_CONFIG_TYPES)),
+        _ => Err(format!("Unknown task {}.", task)),
+    }
+}
+
+/// Weights of `version` of `task`, the base a delta update was computed against.
+pub(crate) fn version_weights(task: &str, version: u64) -> Result<Vec<u8>, String> {
+    let global = global_model(task)?;
+    if version == global.version {
+        return Ok(global.weights);
+    }
+    let weights = match version {
+        0 => storage::bytes(legacy_keys(task)?.0.to_string()),
+        _ => storage::bytes(registry::artifact_key(task, version, ArtifactKind::Model)),
+    };
+    if weights.is_empty() {
+        return Err(format!("Base version {} of {} is no longer available.", version, task));
+    }
+    Ok(weights)
+}
+
+/// Model config of `version` of `task`.
+pub(crate) fn version_config(task: &str, version: u64) -> Result<Vec<u8>, String> {
+    let global = global_model(task)?;
+    if version == global.version {
+        return Ok(global.config);
+    }
+    let config = match version {
+        0 => storage::bytes(legacy_keys(task)?.1.to_string()),
+        _ => storage::bytes(registry::artifact_key(task, version, ArtifactKind::Config)),
+    };
+    if config.is_empty() {
+        return Err(format!("Version {} of {} is no longer available.", version, task));
+    }
+    Ok(config)
+}
+
+/// Loads the checkpoint of the current global model of `task`.
+fn global_checkpoint(task: &str) -> Result<Checkpoint, String> {
+    Checkpoint::from_safetensors(&global_model(task)?.weights)
+}
+
+/// Aggregates the submissions of `round` with its aggregator, applies its server optimiser
+/// and publishes the result as a new committed version, reusing the config of the global model.
+/// With a validation gate the result is only published if it does not regress.
+fn publish_round(round: &mut RoundStatus, now: u64) -> Result<u64, String> {
+    let global = global_model(&round.task)?;
+    let base = Checkpoint::from_safetensors(&global.weights)?;
+    let aggregate = match &round.mode {
+        TrainingMode::Buffered { server_lr, .. } => buffered_round(round, &base, *server_lr)?,
+        TrainingMode::Rounds if round.secure => unmask_round(round, &base)?,
+        TrainingMode::Rounds => aggregate_round(round, &base)?,
+    };
+    // The noise is spent once the aggregate exists, whether or not the candidate is published.
+    if let Some(dp) = &round.differential_privacy {
+        let aggregated = round.participants.len() - round.quarantined.len();
+        privacy::record(&round.task, round.round, dp, aggregated as u64, now);
+    }
+    let next = optimizer::step(&round.task, &round.server_optimizer, &base, aggregate)?;
+    if let Some(gate) = round.validation_gate.clone() {
+        let report = validation::evaluate(&round.task, &gate, &global.config, &base, &next)?;
+        let regressions = report.regressions.join(" ");
+        round.validation = Some(report);
+        if !regressions.is_empty() {
+            return Err(format!("Validation gate rejected the candidate: {}", regressions));
+        }
+    }
+    let origin = VersionOrigin::FederatedRound {
+        round: round.round,
+        aggregator: round.aggregator.clone(),
+        server_optimizer: round.server_optimizer.clone(),
+        training: round.training.clone(),
+        mode: round.mode.clone(),
+        secure: round.secure,
+        differential_privacy: round.differential_privacy.clone(),
+        contributors: hierarchy::contributors(round),
+    };
+    let weights = next.to_safetensors()?;
+    let version =
+        registry::publish(&round.task, weights, global.config.clone(), storage::canister_id(), Some(global.version), origin, now)?;
+    score_contributions(round, global.version, version, now);
+    Ok(version)
+}
+
+/// Scores the aggregated updates of `round` for the contribution ledger. Sample shares are
+/// recorded right away, measured methods start a scoring job.
+fn score_contributions(round: &RoundStatus, base_version: u64, version: u64, now: u64) {
+    let method = round.contribution.clone();
+    if !method.needs_validation() {
+        let shares = incentives::sample_shares(&sample_counts(&kept(round)));
+        record_contributions(round, method, Ok(shares), version, now);
+        return;
+    }
+    incentives::save_scoring_job(&ScoringJob {
+        task: round.task.clone(),
+        round: round.round,
+        version,
+        base_version,
+        method,
+        utilities: Vec::new(),
+        started_at: now,
+    });
+    schedule_scoring(round.task.clone(), round.round);
+}
+
+/// Updates of `round` that made it into the aggregate.
+fn kept(round: &RoundStatus) -> Vec<&Participation> {
+    round
+        .participants
+        .iter()
+        .filter(|p| !round.quarantined.contains(&p.principal) && !round.excluded.contains(&p.principal))
+        .collect()
+}
+
+fn sample_counts(kept: &[&Participation]) -> Vec<f64> {
+    kept.iter().map(|p| p.num_samples as f64).collect()
+}
+
+/// Records the `scores` of the aggregated updates of `round`, measured by `method`. A failed
+/// measurement falls back to sample shares.
+fn record_contributions(
+    round: &RoundStatus,
+    mut method: ContributionMethod,
+    scores: Result<Vec<f64>, String>,
+    version: u64,
+    now: u64,
+) {
+    let kept = kept(round);
+    let mut fallback = None;
+    let scores = match scores {
+        Ok(scores) => scores,
+        Err(e) => {
+            fallback = Some(e);
+            method = ContributionMethod::SampleShare;
+            incentives::sample_shares(&sample_counts(&kept))
+        }
+    };
+    let credits = incentives::credits(&scores);
+    // District scores and credit are split over their clinics by samples.
+    let scores = kept
+        .iter()
+        .zip(scores.iter().zip(&credits))
+        .flat_map(|(p, (score, credit))| {
+            if p.contributors.is_empty() {
+                return vec![ContributionScore {
+                    principal: p.principal,
+                    district: None,
+                    num_samples: p.num_samples,
+                    score: *score,
+                    credit: *credit,
+                }];
             }
-            let total_samples = current_samples + new_samples;
-            // Perform a weighted average update.
-            let updated_weights = current_model.ln1_weight.iter()
-                .zip(new_model.ln1_weight.iter())
-                .map(|(cw, nw)| {
-                    (cw * current_samples as f32 + nw * new_samples as f32) / total_samples as f32
+            let share = |c: &ClinicContribution| c.num_samples as f64 / p.num_samples.max(1) as f64;
+            p.contributors
+                .iter()
+                .map(|c| ContributionScore {
+                    principal: c.clinic,
+                    district: Some(p.principal),
+                    num_samples: c.num_samples,
+                    score: score * share(c),
+                    credit: credit * share(c),
                 })
-                .collect::<Vec<f32>>();
-            *agg = Some((WeightsSerialized { ln1_weight: updated_weights }, total_samples));
+                .collect()
+        })
+        .collect();
+    incentives::record(RoundContributions {
+        task: round.task.clone(),
+        round: round.round,
+        version,
+        method,
+        fallback,
+        scores,
+        recorded_at: now,
+    });
+}
+
+// Why a scoring call stopped before it had every score.
+enum Scoring {
+    // The call used its instruction budget, the next one continues from the cached subsets.
+    Paused,
+    Failed(String),
+}
+
+impl From<String> for Scoring {
+    fn from(e: String) -> Self {
+        Scoring::Failed(e)
+    }
+}
+
+/// Scores of the `kept` updates of the round of `job`, evaluating the subsets that are not
+/// cached in `job` yet. Counterfactual models are the sample-weighted mean of a subset of the
+/// updates, before the server optimiser.
+fn measured_scores(job: &mut ScoringJob, kept: &[&Participation]) -> Result<Vec<f64>, Scoring> {
+    let config = version_config(&job.task, job.base_version)?;
+    let base = Checkpoint::from_safetensors(&version_weights(&job.task, job.base_version)?)?;
+    let (features, labels) = validation::validation_set(&job.task)?;
+    let flats = kept
+        .iter()
+        .map(|p| {
+            let bytes = storage::bytes(submission_key(&job.task, job.round, &p.principal));
+            aggregation::flatten(&Checkpoint::from_safetensors(&bytes)?)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut cache: BTreeMap<Vec<usize>, f64> = job.utilities.drain(..).collect();
+    let utility = |subset: &[usize]| -> Result<f64, Scoring> {
+        if let Some(value) = cache.get(subset) {
+            return Ok(*value);
+        }
+        if storage::instructions() > storage::INSTRUCTION_BUDGET {
+            return Err(Scoring::Paused);
+        }
+        let model = if subset.is_empty() {
+            base.clone()
         } else {
-            // If no aggregated model exists, initialize it with the new model.
-            *agg = Some((new_model, new_samples));
+            let members: Vec<_> = subset.iter().map(|i| &flats[*i]).collect();
+            let weights: Vec<f64> = subset.iter().map(|i| kept[*i].num_samples as f64).collect();
+            aggregation::rebuild(&base, aggregation::weighted_mean(&members, &weights))?
+        };
+        let value = validation::accuracy(&job.task, &config, &model, &features, &labels)?;
+        cache.insert(subset.to_vec(), value);
+        Ok(value)
+    };
+    let scores = match &job.method {
+        ContributionMethod::Shapley { permutations } => {
+            let mut rng = Rng::from_seed(format!("{}/{}/shapley", job.task, job.round).as_bytes());
+            incentives::shapley(kept.len(), *permutations, &mut rng, utility)
         }
-        Ok(())
+        _ => incentives::leave_one_out(kept.len(), utility),
+    };
+    job.utilities = cache.into_iter().collect();
+    scores
+}
+
+/// Runs the scoring job of `round` of `task` until it is done or the call used its
+/// instruction budget. The submissions of the round are kept until the job is done.
+fn advance_scoring(task: &str, round: u64, now: u64) {
+    let Some(mut job) = incentives::scoring_job(task, round) else {
+        return;
+    };
+    let Some(status) = ROUNDS.with(|r| r.borrow().get(&round_key(task, round))) else {
+        incentives::remove_scoring_job(task, round);
+        return;
+    };
+    let kept = kept(&status);
+    let scores = match measured_scores(&mut job, &kept) {
+        Err(Scoring::Paused) => {
+            incentives::save_scoring_job(&job);
+            schedule_scoring(job.task, job.round);
+            return;
+        }
+        Err(Scoring::Failed(e)) => Err(e),
+        Ok(scores) => Ok(scores),
+    };
+    record_contributions(&status, job.method, scores, job.version, now);
+    incentives::remove_scoring_job(task, round);
+    remove_submissions(&status);
+}
+
+fn schedule_scoring(task: String, round: u64) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || advance_scoring(&task, round, ic_cdk::api::time()));
+}
+
+/// Continues the scoring jobs after an upgrade, which dropped their timers.
+pub fn resume_scoring() {
+    for job in incentives::scoring_jobs() {
+        schedule_scoring(job.task, job.round);
+    }
+}
+
+/// Combines the submitted checkpoints of `round`, with differential privacy if it asks for it.
+/// Quarantined submissions are left out.
+fn aggregate_round(round: &mut RoundStatus, base: &Checkpoint) -> Result<Checkpoint, String> {
+    let mut updates = round
+        .participants
+        .iter()
+        .map(|p| {
+            let bytes = storage::bytes(submission_key(&round.task, round.round, &p.principal));
+            Ok(WeightedUpdate { checkpoint: Checkpoint::from_safetensors(&bytes)?, weight: p.weight })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    // Principals of the updates that are aggregated.
+    let mut kept: Vec<Principal> = round.participants.iter().map(|p| p.principal).collect();
+    if let Some(config) = round.anomaly_detection.clone() {
+        round.anomaly_scores = screen_round(round, &config, base, &updates)?;
+        let quarantined: Vec<bool> = round.anomaly_scores.iter().map(|score| !score.reasons.is_empty()).collect();
+        round.quarantined = kept.iter().zip(&quarantined).filter(|(_, q)| **q).map(|(p, _)| *p).collect();
+        kept.retain(|p| !round.quarantined.contains(p));
+        updates = updates.into_iter().zip(&quarantined).filter(|(_, q)| !**q).map(|(u, _)| u).collect();
+        if updates.is_empty() {
+            return Err("Every update of the round was quarantined.".to_string());
+        }
+    }
+    let principal = |i: &usize| kept[*i];
+    if let Some(dp) = &round.differential_privacy {
+        let checkpoints: Vec<Checkpoint> = updates.into_iter().map(|u| u.checkpoint).collect();
+        let mut rng = privacy::noise_rng(&round.task, round.round)?;
+        let (noisy, clipped) = privacy::privatize(dp, base, &checkpoints, &mut rng)?;
+        round.clipped = clipped.iter().map(principal).collect();
+        return Ok(noisy);
+    }
+    let result = aggregation::aggregate(&round.aggregator, base, &updates)?;
+    round.excluded = result.excluded.iter().map(principal).collect();
+    round.clipped = result.clipped.iter().map(principal).collect();
+    Ok(result.checkpoint)
+}
+
+/// Applies the buffered changes of `round`, each against the version it was trained from.
+fn buffered_round(round: &RoundStatus, global: &Checkpoint, server_lr: f64) -> Result<Checkpoint, String> {
+    let updates = round
+        .participants
+        .iter()
+        .map(|p| {
+            let bytes = storage::bytes(submission_key(&round.task, round.round, &p.principal));
+            Ok(BufferedUpdate {
+                checkpoint: Checkpoint::from_safetensors(&bytes)?,
+                base: Checkpoint::from_safetensors(&version_weights(&round.task, p.model_version)?)?,
+                weight: p.weight,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    fedbuff::apply(global, &updates, server_lr)
+}
+
+/// Scores the `updates` of `round` against each other and the loss history of their clients.
+fn screen_round(
+    round: &RoundStatus,
+    config: &AnomalyConfig,
+    base: &Checkpoint,
+    updates: &[WeightedUpdate],
+) -> Result<Vec<AnomalyScore>, String> {
+    let base = aggregation::flatten(base)?;
+    let flats = updates.iter().map(|u| aggregation::flatten(&u.checkpoint)).collect::<Result<Vec<_>, _>>()?;
+    let submissions: Vec<Submission> = round
+        .participants
+        .iter()
+        .zip(&flats)
+        .map(|(p, flat)| Submission {
+            principal: p.principal,
+            flat,
+            loss: p.loss,
+            historical_loss: clinics::node(&p.principal).and_then(|node| node.mean_loss),
+        })
+        .collect();
+    Ok(anomaly::screen(config, &base, &submissions))
+}
+
+/// Sample-weighted average of the masked submissions of a secure round.
+fn unmask_round(round: &RoundStatus, base: &Checkpoint) -> Result<Checkpoint, String> {
+    let session = secure_aggregation::session(&round.task, round.round)
+        .ok_or_else(|| format!("Round {} of {} has no secure aggregation session.", round.round, round.task))?;
+    let masked: Vec<(Principal, Vec<u64>)> = round
+        .participants
+        .iter()
+        .map(|p| {
+            let bytes = storage::bytes(submission_key(&round.task, round.round, &p.principal));
+            let values = bytes.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).collect();
+            (p.principal, values)
+        })
+        .collect();
+    let sum = session.unmask(&masked)?;
+    let total: u64 = round
+        .participants
+        .iter()
+        .filter(|p| session.survivors.contains(&p.principal))
+        .map(|p| p.num_samples)
+        .sum();
+    let mut sum = sum.into_iter();
+    let mut flat = aggregation::flatten(base)?;
+    for values in flat.values_mut() {
+        for (value, s) in values.iter_mut().zip(sum.by_ref()) {
+            *value = secure_aggregation::decode_mean(s, total);
+        }
+    }
+    aggregation::rebuild(base, flat)
+}
+
+fn update_key(task: &str, participant: &Principal) -> String {
+    format!("{}{}/{}", storage::UPDATE_PREFIX, task, participant.to_text())
+}
+
+// Where a submitted update is kept until its round closes.
+fn submission_key(task: &str, round: u64, participant: &Principal) -> String {
+    format!("{}{}/round/{}/{}", storage::UPDATE_PREFIX, task, round, participant.to_text())
+}
+
+fn remove_submissions(round: &RoundStatus) {
+    for participation in &round.participants {
+        storage::remove_raw(&submission_key(&round.task, round.round, &participation.principal));
+    }
+}
+
+fn round_key(task: &str, round: u64) -> String {
+    format!("{}/{:020}", task, round)
+}
+
+pub fn round_config(task: &str) -> RoundConfig {
+    ROUND_CONFIGS.with(|c| c.borrow().get(&task.to_string())).unwrap_or_default()
+}
+
+/// Rounds of `task`, oldest first.
+pub fn rounds(task: &str) -> Vec<RoundStatus> {
+    let prefix = format!("{}/", task);
+    ROUNDS.with(|r| {
+        r.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, round)| round)
+            .collect()
     })
 }
 
+pub fn latest_round(task: &str) -> Option<RoundStatus> {
+    rounds(task).pop()
+}
+
+fn save_round(round: &RoundStatus) {
+    ROUNDS.with(|r| r.borrow_mut().insert(round_key(&round.task, round.round), round.clone()));
+}
+
+pub(crate) fn open_round(task: &str) -> Option<RoundStatus> {
+    latest_round(task).filter(|round| round.state == RoundState::Open)
+}
+
+/// Opens the next round of `task` against the current global version.
+pub fn start_round(task: &str, now: u64) -> Result<RoundStatus, String> {
+    if let Some(round) = open_round(task) {
+        return Err(format!("Round {} of {} is still open.", round.round, task));
+    }
+    let global = global_model(task)?;
+    let config = round_config(task);
+    if let Some(dp) = &config.differential_privacy {
+        privacy::check_budget(task, dp)?;
+    }
+    let number = latest_round(task).map(|r| r.round + 1).unwrap_or(1);
+    let buffered = config.mode != TrainingMode::Rounds;
+    let round = RoundStatus {
+        task: task.to_string(),
+        round: number,
+        state: RoundState::Open,
+        base_version: global.version,
+        opened_at: now,
+        deadline: if buffered {
+            u64::MAX
+        } else {
+            now.saturating_add(config.deadline_seconds.saturating_mul(storage::NANOS_PER_SECOND))
+        },
+        closed_at: None,
+        invited: if buffered {
+            clinics::select(&SelectionStrategy::All, task, number, now)
+        } else {
+            clinics::select(&config.selection, task, number, now)
+        },
+        participants: Vec::new(),
+        num_samples: 0,
+        mean_loss: None,
+        quorum: config.quorum,
+        min_participants: config.min_participants,
+        max_staleness: config.max_staleness,
+        staleness_decay: config.staleness_decay,
+        aggregator: config.aggregator.clone(),
+        server_optimizer: config.server_optimizer.clone(),
+        training: config.training.clone(),
+        mode: config.mode.clone(),
+        secure: config.secure_aggregation.is_some(),
+        differential_privacy: config.differential_privacy.clone(),
+        validation_gate: config.validation_gate.clone(),
+        anomaly_detection: config.anomaly_detection.clone(),
+        contribution: config.contribution.clone(),
+        excluded: Vec::new(),
+        clipped: Vec::new(),
+        anomaly_scores: Vec::new(),
+        quarantined: Vec::new(),
+        validation: None,
+        result_version: None,
+    };
+    // Nobody could submit to a synchronous round without invitations.
+    if !buffered && round.invited.is_empty() {
+        return Err(format!("No active clinic was selected for round {} of {}.", number, task));
+    }
+    if let Some(secure) = &config.secure_aggregation {
+        secure_aggregation::open_session(SecAggSession::new(task, number, round.invited.clone(), secure));
+    }
+    save_round(&round);
+    if !buffered {
+        deadlines::schedule(DEADLINE, &round.task, round.deadline, now, close_round);
+    }
+    Ok(round)
+}
+
+/// Versions an update to `round` may lag behind its base version.
+pub(crate) fn max_staleness(round: &RoundStatus) -> u64 {
+    match &round.mode {
+        TrainingMode::Rounds => round.max_staleness,
+        TrainingMode::Buffered { max_staleness, .. } => *max_staleness,
+    }
+}
+
+/// Whether `principal` may submit to `round`. Buffered rounds take any eligible node,
+/// including those registered after the buffer opened.
+fn may_submit(round: &RoundStatus, principal: &Principal) -> bool {
+    round.invited.contains(principal)
+        || (round.mode != TrainingMode::Rounds && clinics::node(principal).as_ref().is_some_and(clinics::eligible))
+}
+
+/// Whether `principal` already took part in `round`, directly or through its district.
+fn has_contributed(round: &RoundStatus, principal: &Principal) -> bool {
+    round
+        .participants
+        .iter()
+        .any(|p| p.principal == *principal || p.contributors.iter().any(|c| c.clinic == *principal))
+}
+
+/// Validates an update and stores it with the open round of `task`, closing the round
+/// when its quorum is reached. `weights` are the safetensors bytes of the update.
+/// District aggregators submit on behalf of the invited clinics listed in `contributors`.
+pub fn submit(
+    update: &ClientUpdate,
+    weights: Vec<u8>,
+    participant: Principal,
+    contributors: Vec<ClinicContribution>,
+    now: u64,
+) -> Result<RoundStatus, String> {
+    let mut round = open_round(&update.task)
+        .ok_or_else(|| format!("No round of {} is open.", update.task))?;
+    let staleness = update.check(round.base_version, max_staleness(&round))?;
+    let clinics: Vec<Principal> = if contributors.is_empty() {
+        vec![participant]
+    } else {
+        let district = hierarchy::district(&participant)
+            .ok_or_else(|| format!("{} is not a district aggregator.", participant))?;
+        hierarchy::check_contributions(&district, &contributors, update.num_samples)?;
+        if round.secure {
+            return Err("District updates cannot take part in secure rounds.".to_string());
+        }
+        contributors.iter().map(|c| c.clinic).collect()
+    };
+    for clinic in &clinics {
+        if !may_submit(&round, clinic) {
+            return Err(format!("{} is not invited to round {} of {}.", clinic, round.round, update.task));
+        }
+        if has_contributed(&round, clinic) {
+            return Err(format!("{} already submitted an update for round {}.", clinic, round.round));
+        }
+    }
+    if has_contributed(&round, &participant) {
+        return Err(format!("{} already submitted an update for round {}.", participant, round.round));
+    }
+    for clinic in clinics {
+        if !round.invited.contains(&clinic) {
+            round.invited.push(clinic);
+        }
+    }
+    if round.secure {
+        return submit_masked(round, update, weights, participant, now);
+    }
+    let weight = match &round.mode {
+        TrainingMode::Rounds => update.num_samples as f64 * round.staleness_decay.powi(staleness as i32),
+        TrainingMode::Buffered { staleness: function, .. } => function.weight(staleness),
+    };
+    let uploaded_bytes = weights.len() as u64;
+    let weights = match update.encoding {
+        UpdateEncoding::Full => weights,
+        UpdateEncoding::Delta => {
+            let base = Checkpoint::from_safetensors(&version_weights(&update.task, update.model_version)?)?;
+            delta::reconstruct(&base, &weights)?.to_safetensors()?
+        }
+        UpdateEncoding::Masked => return Err("Masked updates are only accepted in secure rounds.".to_string()),
+    };
+    let full_size = weights.len() as u64;
+    // Every tensor must match the global model by name, dtype and shape.
+    Checkpoint::from_update(&weights, &global_checkpoint(&update.task)?)?;
+    storage::insert_raw(submission_key(&update.task, round.round, &participant), weights);
 
-//Retrieve current aggregated model weights. 
-pub fn get_aggregated_model() -> Option<WeightsSerialized> {
-    AGGREGATED_MODEL.with(|cell| {
-        cell.borrow().as_ref().map(|(model, _)| model.clone())
+    record_participation(&mut round, update, participant, contributors, weight, now, (uploaded_bytes, full_size));
+
+    let threshold = match &round.mode {
+        TrainingMode::Rounds => round.quorum,
+        TrainingMode::Buffered { buffer_size, .. } => *buffer_size,
+    };
+    if round.participants.len() as u64 >= threshold.max(1) {
+        return close_round(&update.task, now);
+    }
+    Ok(round)
+}
+
+// `sizes` are the uploaded size of the update and the size of the checkpoint it encodes.
+fn record_participation(
+    round: &mut RoundStatus,
+    update: &ClientUpdate,
+    participant: Principal,
+    contributors: Vec<ClinicContribution>,
+    weight: f64,
+    now: u64,
+    (uploaded_bytes, full_size): (u64, u64),
+) {
+    round.participants.push(Participation {
+        principal: participant,
+        num_samples: update.num_samples,
+        loss: update.loss,
+        model_version: update.model_version,
+        weight,
+        submitted_at: now,
+        proximal_mu: update.proximal_mu,
+        encoding: update.encoding,
+        uploaded_bytes,
+        compression_ratio: full_size as f64 / uploaded_bytes as f64,
+        contributors,
+    });
+    round.num_samples += update.num_samples;
+    let loss_sum: f64 = round.participants.iter().map(|p| p.loss * p.num_samples as f64).sum();
+    round.mean_loss = Some(loss_sum / round.num_samples as f64);
+    save_round(round);
+}
+
+/// Stores the masked update of a secure round. The round closes once the survivors revealed
+/// their shares rather than at its quorum.
+fn submit_masked(
+    mut round: RoundStatus,
+    update: &ClientUpdate,
+    masked: Vec<u8>,
+    participant: Principal,
+    now: u64,
+) -> Result<RoundStatus, String> {
+    if update.encoding != UpdateEncoding::Masked {
+        return Err(format!("Round {} of {} only accepts masked updates.", round.round, update.task));
+    }
+    // Masks only cancel when every client encodes against the same model.
+    if update.model_version != round.base_version {
+        return Err(format!(
+            "Masked updates have to be trained from version {}, not {}.",
+            round.base_version, update.model_version
+        ));
+    }
+    let parameters: usize = aggregation::flatten(&global_checkpoint(&update.task)?)?.values().map(|v| v.len()).sum();
+    if masked.len() != parameters * 8 {
+        return Err(format!("Expected {} masked bytes, got {}.", parameters * 8, masked.len()));
+    }
+    secure_aggregation::record_submission(&update.task, participant)?;
+    let uploaded_bytes = masked.len() as u64;
+    storage::insert_raw(submission_key(&update.task, round.round, &participant), masked);
+    let weight = update.num_samples as f64;
+    record_participation(&mut round, update, participant, Vec::new(), weight, now, (uploaded_bytes, uploaded_bytes));
+    Ok(round)
+}
+
+/// Closes the open round of `task`: publishes and promotes its average when enough clients
+/// took part, abandons it otherwise, and opens the next round.
+pub fn close_round(task: &str, now: u64) -> Result<RoundStatus, String> {
+    let mut round = open_round(task).ok_or_else(|| format!("No round of {} is open.", task))?;
+    deadlines::cancel(DEADLINE, task);
+
+    round.state = if (round.participants.len() as u64) < round.min_participants.max(1) || !unmasking(&round) {
+        RoundState::Abandoned
+    } else {
+        match publish_round(&mut round, now)
+            .and_then(|version| registry::promote_version(task, version).map(|_| version))
+        {
+            Ok(version) => {
+                round.result_version = Some(version);
+                RoundState::Published
+            }
+            Err(e) => {
+                RoundState::Failed(e)
+            }
+        }
+    };
+    round.closed_at = Some(now);
+    save_round(&round);
+    // A scoring job still needs the submissions and removes them when it is done.
+    if incentives::scoring_job(task, round.round).is_none() {
+        remove_submissions(&round);
+    }
+    secure_aggregation::close_session(task, round.round);
+    // Quarantined updates count as missed rounds and stay out of the loss history.
+    // District updates are credited to their clinics.
+    let submissions: Vec<(Principal, u64, f64)> = round
+        .participants
+        .iter()
+        .filter(|p| !round.quarantined.contains(&p.principal))
+        .flat_map(|p| {
+            if p.contributors.is_empty() {
+                return vec![(p.principal, p.num_samples, p.loss)];
+            }
+            p.contributors.iter().map(|c| (c.clinic, c.num_samples, p.loss)).collect()
+        })
+        .collect();
+    clinics::record_round(&round.invited, &submissions, now);
+    if let Some(config) = &round.anomaly_detection {
+        clinics::record_quarantine(&round.quarantined, config.flag_after);
+    }
+
+    if let Err(e) = start_round(task, now) {
+        ic_cdk::println!("Failed to open the next round of {}: {}", task, e);
+    }
+    Ok(round)
+}
+
+/// Whether `round` can be aggregated as far as secure aggregation goes. Before unmasking the
+/// canister cannot remove any mask, so a secure round that runs out of time is abandoned.
+fn unmasking(round: &RoundStatus) -> bool {
+    !round.secure
+        || secure_aggregation::session(&round.task, round.round).is_some_and(|s| s.phase == SecAggPhase::Unmasking)
+}
+
+/// Re-arms the deadlines of open rounds. Timers do not survive upgrades,
+/// so this runs from `post_upgrade`.
+pub fn schedule_deadlines() {
+    let now = ic_cdk::api::time();
+    let open: Vec<RoundStatus> = ROUNDS.with(|r| {
+        r.borrow()
+            .iter()
+            .map(|(_, round)| round)
+            .filter(|round| round.state == RoundState::Open && round.mode == TrainingMode::Rounds)
+            .collect()
+    });
+    for round in open {
+        deadlines::schedule(DEADLINE, &round.task, round.deadline, now, close_round);
+    }
+}
+
+/// Uploads the safetensors weights of an update in chunks, before calling `submit_update`.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn append_update_bytes(task: String, bytes: Vec<u8>) -> Result<(), String> {
+    global_model(&task)?;
+    let caller = ic_cdk::caller();
+    quota::check(&caller, bytes.len() as u64)?;
+    storage::append_raw(update_key(&task, &caller), bytes);
+    Ok(())
+}
+
+/// Drops a partially uploaded update.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn discard_update(task: String) {
+    storage::remove_raw(&update_key(&task, &ic_cdk::caller()));
+}
+
+/// Aggregates the update staged by the caller into the open round. Updates trained from an
+/// older global version are down-weighted, or rejected beyond `max_staleness`.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn submit_update(update: ClientUpdate) -> Result<RoundStatus, String> {
+    let caller = ic_cdk::caller();
+    let key = update_key(&update.task, &caller);
+    let weights = storage::bytes(key.clone());
+    if weights.is_empty() {
+        return Err("No update weights uploaded, call append_update_bytes first.".to_string());
+    }
+    let result = submit(&update, weights, caller, Vec::new(), ic_cdk::api::time());
+    storage::remove_raw(&key);
+    result
+}
+
+/// Submits the update a district aggregator combined from its clinics, staged like
+/// `submit_update`. `num_samples` of the update is the sum over `contributors`.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn submit_district_update(update: ClientUpdate, contributors: Vec<ClinicContribution>) -> Result<RoundStatus, String> {
+    let caller = ic_cdk::caller();
+    let key = update_key(&update.task, &caller);
+    let weights = storage::bytes(key.clone());
+    if weights.is_empty() {
+        return Err("No update weights uploaded, call append_update_bytes first.".to_string());
+    }
+    if contributors.is_empty() {
+        return Err("A district update needs at least one contributing clinic.".to_string());
+    }
+    let result = submit(&update, weights, caller, contributors, ic_cdk::api::time());
+    storage::remove_raw(&key);
+    result
+}
+
+#[ic_cdk::query(guard = "is_participant")]
+pub fn get_global_model(task: String, chunk: u64) -> Result<GlobalModelChunk, String> {
+    let global = global_model(&task)?;
+    let data = global
+        .weights
+        .chunks(storage::CHUNK_SIZE)
+        .nth(chunk as usize)
+        .map(|data| data.to_vec())
+        .ok_or_else(|| format!("Chunk {} of the {} model does not exist.", chunk, task))?;
+    Ok(GlobalModelChunk {
+        version: global.version,
+        total_size: global.weights.len() as u64,
+        chunk_count: global.weights.len().div_ceil(storage::CHUNK_SIZE) as u64,
+        data,
+        config: global.config,
     })
 }
+
+/// The open round of `task`, or the last one when training is stopped.
+#[ic_cdk::query]
+pub fn get_round_status(task: String) -> Result<RoundStatus, String> {
+    latest_round(&task).ok_or_else(|| format!("No round of {} was started yet.", task))
+}
+
+/// Tells the calling node whether it is invited to the open round of `task`.
+#[ic_cdk::query]
+pub fn get_invitation(task: String) -> Result<Invitation, String> {
+    let round = open_round(&task).ok_or_else(|| format!("No round of {} is open.", task))?;
+    Ok(Invitation {
+        invited: may_submit(&round, &ic_cdk::caller()),
+        task,
+        round: round.round,
+        base_version: round.base_version,
+        deadline: round.deadline,
+        training: round.training,
+        secure: round.secure,
+    })
+}
+
+#[ic_cdk::query]
+pub fn list_rounds(task: String) -> Vec<RoundStatus> {
+    rounds(&task)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn start_training_round(task: String) -> Result<RoundStatus, String> {
+    start_round(&task, ic_cdk::api::time())
+}
+
+/// Closes the open round of `task` before its quorum or deadline is reached.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn finalize_round(task: String) -> Result<RoundStatus, String> {
+    close_round(&task, ic_cdk::api::time())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn set_round_config(task: String, config: RoundConfig) -> Result<(), String> {
+    global_model(&task)?;
+    if !(config.staleness_decay > 0.0 && config.staleness_decay <= 1.0) {
+        return Err("The staleness decay must lie in (0, 1].".to_string());
+    }
+    config.selection.validate()?;
+    config.aggregator.validate()?;
+    config.server_optimizer.validate()?;
+    config.training.validate()?;
+    if let Some(secure) = &config.secure_aggregation {
+        secure.validate()?;
+        // The canister only ever sees the sum of a secure round.
+        if config.aggregator != Aggregator::FedAvg {
+            return Err("Secure aggregation only supports FedAvg.".to_string());
+        }
+    }
+    if let Some(dp) = &config.differential_privacy {
+        dp.validate()?;
+        // Clipping needs the individual updates, and sensitivity assumes equal weights.
+        if config.secure_aggregation.is_some() || config.aggregator != Aggregator::FedAvg {
+            return Err("Differential privacy needs FedAvg without secure aggregation.".to_string());
+        }
+    }
+    if let Some(gate) = &config.validation_gate {
+        gate.validate()?;
+    }
+    if let TrainingMode::Buffered { buffer_size, server_lr, staleness, max_staleness } = &config.mode {
+        staleness.validate()?;
+        if *buffer_size == 0 || *server_lr <= 0.0 {
+            return Err("The buffer size and server learning rate must be positive.".to_string());
+        }
+        // The buffer moves to a new version on every flush, updates in flight are one behind.
+        if *max_staleness == 0 {
+            return Err("Buffered mode has to accept updates at least one version behind.".to_string());
+        }
+        // These assume that every update starts from the same global model.
+        if config.secure_aggregation.is_some() || config.differential_privacy.is_some() || config.anomaly_detection.is_some() {
+            return Err("Buffered mode does not support secure aggregation, differential privacy or anomaly detection.".to_string());
+        }
+    }
+    config.contribution.validate()?;
+    if config.contribution.needs_validation() {
+        // Counterfactual aggregates need the individual updates, all trained from one model,
+        // and would reveal more about each client than differential privacy allows.
+        if config.secure_aggregation.is_some() || config.differential_privacy.is_some() || config.mode != TrainingMode::Rounds {
+            return Err("Validation-based contribution scores need synchronous rounds without secure aggregation or differential privacy.".to_string());
+        }
+    }
+    if let Some(anomaly) = &config.anomaly_detection {
+        anomaly.validate()?;
+        if config.secure_aggregation.is_some() {
+            return Err("Anomaly detection needs the individual updates, which secure aggregation hides.".to_string());
+        }
+        // The privacy accounting assumes that every clipped update is aggregated.
+        if config.differential_privacy.is_some() {
+            return Err("Anomaly detection cannot be combined with differential privacy.".to_string());
+        }
+    }
+    ROUND_CONFIGS.with(|c| c.borrow_mut().insert(task, config));
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn get_round_config(task: String) -> RoundConfig {
+    round_config(&task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint as checkpoint;
+    use crate::clinics::{DeviceClass, NodeProfile};
+
+    // Serves a two-parameter malaria model at version 0 and registers three clinics.
+    fn setup(config: RoundConfig) -> Vec<Principal> {
+        storage::insert_raw(MALARIA_MODEL.to_string(), checkpoint(&[0.0, 0.0]).to_safetensors().unwrap());
+        storage::insert_raw(MODEL_CONFIG.to_string(), b"{}".to_vec());
+        ROUND_CONFIGS.with(|c| c.borrow_mut().insert(MALARIA_TASK.to_string(), config));
+        (1..=3)
+            .map(|i| {
+                let clinic = Principal::from_slice(&[i]);
+                let profile = NodeProfile {
+                    clinic_name: format!("Clinic {}", i),
+                    region: "North".to_string(),
+                    device_class: DeviceClass::Workstation,
+                    dataset_size: 100,
+                };
+                clinics::register(clinic, profile, 0).unwrap();
+                clinic
+            })
+            .collect()
+    }
+
+    fn send(clinic: Principal, model_version: u64, num_samples: u64, values: &[f32]) -> Result<RoundStatus, String> {
+        let update = ClientUpdate {
+            task: MALARIA_TASK.to_string(),
+            num_samples,
+            loss: 0.5,
+            model_version,
+            proximal_mu: 0.0,
+            encoding: UpdateEncoding::Full,
+        };
+        submit(&update, checkpoint(values).to_safetensors().unwrap(), clinic, Vec::new(), 0)
+    }
+
+    fn global_values() -> Vec<f32> {
+        let global = Checkpoint::from_safetensors(&global_model(MALARIA_TASK).unwrap().weights).unwrap();
+        aggregation::flatten(&global).unwrap().remove("w").unwrap()
+    }
+
+    #[test]
+    fn rounds_close_at_quorum_and_discount_stale_updates() {
+        let config = RoundConfig { quorum: 2, max_staleness: 1, staleness_decay: 0.5, ..RoundConfig::default() };
+        let clinics = setup(config);
+        start_round(MALARIA_TASK, 0).unwrap();
+
+        assert_eq!(send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap().state, RoundState::Open);
+        assert!(send(clinics[0], 0, 10, &[1.0, 1.0]).is_err());
+        let first = send(clinics[1], 0, 30, &[3.0, 3.0]).unwrap();
+        assert_eq!(first.state, RoundState::Published);
+        assert_eq!(first.result_version, Some(1));
+        assert_eq!(global_values(), vec![2.5, 2.5]);
+
+        // The next round runs on version 1, an update from version 0 counts half.
+        let second = send(clinics[0], 0, 10, &[4.0, 4.0]).unwrap();
+        assert_eq!((second.round, second.base_version), (2, 1));
+        assert_eq!(second.participants[0].weight, 5.0);
+        assert!(send(clinics[1], 2, 10, &[0.0, 0.0]).is_err());
+        assert_eq!(send(clinics[1], 1, 10, &[1.0, 1.0]).unwrap().result_version, Some(2));
+        assert_eq!(global_values(), vec![2.0, 2.0]);
+    }
+
+    #[test]
+    fn config_changes_apply_from_the_next_round() {
+        let clinics = setup(RoundConfig { quorum: 2, min_participants: 2, ..RoundConfig::default() });
+        let opened = start_round(MALARIA_TASK, 0).unwrap();
+        let changed = RoundConfig { quorum: 1, min_participants: 1, ..RoundConfig::default() };
+        set_round_config(MALARIA_TASK.to_string(), changed).unwrap();
+
+        assert_eq!(send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap().state, RoundState::Open);
+        assert_eq!(close_round(MALARIA_TASK, opened.deadline).unwrap().state, RoundState::Abandoned);
+        let next = open_round(MALARIA_TASK).unwrap();
+        assert_eq!((next.quorum, next.min_participants), (1, 1));
+        assert_eq!(send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap().state, RoundState::Published);
+    }
+
+    #[test]
+    fn buffered_rounds_down_weight_stale_updates() {
+        let buffered = |max_staleness| RoundConfig {
+            mode: TrainingMode::Buffered {
+                buffer_size: 2,
+                server_lr: 1.0,
+                staleness: StalenessFunction::default(),
+                max_staleness,
+            },
+            ..RoundConfig::default()
+        };
+        let clinics = setup(RoundConfig::default());
+        assert!(set_round_config(MALARIA_TASK.to_string(), buffered(0)).is_err());
+        set_round_config(MALARIA_TASK.to_string(), buffered(1)).unwrap();
+        start_round(MALARIA_TASK, 0).unwrap();
+
+        send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap();
+        // Flagged nodes are left out of the next buffer.
+        clinics::record_quarantine(&clinics[2..], 1);
+        assert_eq!(send(clinics[1], 0, 10, &[1.0, 1.0]).unwrap().result_version, Some(1));
+        assert!(send(clinics[2], 1, 10, &[1.0, 1.0]).is_err());
+
+        // Trained before the flush, the update is one version behind the buffer.
+        let buffer = send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap();
+        assert_eq!(buffer.base_version, 1);
+        assert!((buffer.participants[0].weight - 0.5f64.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rounds_below_min_participants_are_abandoned_at_the_deadline() {
+        let config = RoundConfig { quorum: 3, min_participants: 2, ..RoundConfig::default() };
+        let clinics = setup(config);
+        let opened = start_round(MALARIA_TASK, 0).unwrap();
+        assert_eq!(opened.invited, clinics);
+
+        send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap();
+        // Without active nodes the next round cannot be opened.
+        clinics::replace_nodes(Vec::new());
+        let abandoned = close_round(MALARIA_TASK, opened.deadline).unwrap();
+        assert_eq!(abandoned.state, RoundState::Abandoned);
+        assert_eq!(abandoned.result_version, None);
+        assert_eq!(storage::len(&submission_key(MALARIA_TASK, 1, &clinics[0])), 0);
+        assert!(open_round(MALARIA_TASK).is_none());
+        assert!(start_round(MALARIA_TASK, opened.deadline).is_err());
+
+        // Once nodes registered again it publishes at its deadline without reaching quorum.
+        setup(RoundConfig { quorum: 3, min_participants: 2, ..RoundConfig::default() });
+        let reopened = start_round(MALARIA_TASK, opened.deadline).unwrap();
+        assert_eq!((reopened.round, reopened.base_version), (2, 0));
+        send(clinics[0], 0, 10, &[1.0, 1.0]).unwrap();
+        send(clinics[1], 0, 10, &[3.0, 3.0]).unwrap();
+        let published = close_round(MALARIA_TASK, reopened.deadline).unwrap();
+        assert_eq!(published.state, RoundState::Published);
+        assert_eq!(global_values(), vec![2.0, 2.0]);
+        assert_eq!(open_round(MALARIA_TASK).unwrap().base_version, 1);
+    }
+}
@@ -0,0 +1,325 @@
+use candid::CandidType;
+use candle_core::{DType, Device, Tensor};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::checkpoint::{is_float, Checkpoint};
+
+const DEVICE: Device = Device::Cpu;
+
+//Aggregators combining the client checkpoints of a round. Apart from FedAvg they are meant
+//to bound the influence of compromised clients on the global model.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum Aggregator {
+    // Sample-weighted average.
+    #[default]
+    FedAvg,
+    // Coordinate-wise median, ignores the sample counts.
+    Median,
+    // Coordinate-wise mean after dropping the `trim_fraction` largest and smallest values.
+    TrimmedMean { trim_fraction: f64 },
+    // The single update closest to its neighbours, tolerating `byzantine` attackers.
+    Krum { byzantine: u64 },
+    // Sample-weighted average of the `select` updates with the best Krum scores.
+    MultiKrum { byzantine: u64, select: u64 },
+    // FedAvg after clipping every update to an L2 distance of `max_norm` from the global model.
+    NormBounded { max_norm: f64 },
+}
+
+impl Aggregator {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Aggregator::TrimmedMean { trim_fraction } if !(0.0..0.5).contains(trim_fraction) => {
+                Err("The trim fraction must lie in [0, 0.5).".to_string())
+            }
+            Aggregator::MultiKrum { select: 0, .. } => Err("Multi-Krum must select at least one update.".to_string()),
+            Aggregator::NormBounded { max_norm } if *max_norm <= 0.0 => Err("The norm bound must be positive.".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A client checkpoint and its aggregation weight.
+pub struct WeightedUpdate {
+    pub checkpoint: Checkpoint,
+    pub weight: f64,
+}
+
+pub struct Aggregate {
+    pub checkpoint: Checkpoint,
+    // Indexes of the updates left out of the result.
+    pub excluded: Vec<usize>,
+    // Indexes of the updates that were scaled down.
+    pub clipped: Vec<usize>,
+}
+
+// Float tensors as flat f32 vectors, ordered by name.
+pub(crate) type Flat = BTreeMap<String, Vec<f32>>;
+
+pub(crate) fn flatten(checkpoint: &Checkpoint) -> Result<Flat, String> {
+    checkpoint
+        .tensors
+        .iter()
+        .filter(|(_, tensor)| is_float(tensor.dtype()))
+        .map(|(name, tensor)| {
+            tensor
+                .to_dtype(DType::F32)
+                .and_then(|t| t.flatten_all())
+                .and_then(|t| t.to_vec1::<f32>())
+                .map(|values| (name.clone(), values))
+                .map_err(|e| format!("Failed to read {}: {:?}", name, e))
+        })
+        .collect()
+}
+
+/// Puts `values` back into the shape and dtype of `template`. Non-float tensors are copied.
+pub(crate) fn rebuild(template: &Checkpoint, mut values: Flat) -> Result<Checkpoint, String> {
+    let mut tensors = BTreeMap::new();
+    for (name, reference) in &template.tensors {
+        let tensor = match values.remove(name) {
+            Some(flat) => Tensor::from_vec(flat, reference.dims(), &DEVICE)
+                .and_then(|t| t.to_dtype(reference.dtype()))
+                .map_err(|e| format!("Failed to rebuild {}: {:?}", name, e))?,
+            None => reference.clone(),
+        };
+        tensors.insert(name.clone(), tensor);
+    }
+    Ok(Checkpoint { tensors })
+}
+
+fn squared_distance(a: &Flat, b: &Flat) -> f64 {
+    a.iter()
+        .map(|(name, values)| {
+            values
+                .iter()
+                .zip(&b[name])
+                .map(|(x, y)| {
+                    let d = (*x - *y) as f64;
+                    d * d
+                })
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+pub(crate) fn weighted_mean(flats: &[&Flat], weights: &[f64]) -> Flat {
+    let total: f64 = weights.iter().sum();
+    let mut mean: Flat = flats[0]
+        .iter()
+        .map(|(name, values)| (name.clone(), vec![0.0; values.len()]))
+        .collect();
+    for (flat, weight) in flats.iter().zip(weights) {
+        let share = (weight / total) as f32;
+        for (name, acc) in mean.iter_mut() {
+            for (a, v) in acc.iter_mut().zip(&flat[name]) {
+                *a += v * share;
+            }
+        }
+    }
+    mean
+}
+
+/// Applies `reduce` to the sorted values of every coordinate across all updates.
+pub(crate) fn coordinate_wise(flats: &[Flat], reduce: impl Fn(&[f32]) -> f32) -> Flat {
+    let mut column = Vec::with_capacity(flats.len());
+    flats[0]
+        .iter()
+        .map(|(name, values)| {
+            let reduced = (0..values.len())
+                .map(|i| {
+                    column.clear();
+                    column.extend(flats.iter().map(|flat| flat[name][i]));
+                    column.sort_by(|a, b| a.total_cmp(b));
+                    reduce(&column)
+                })
+                .collect();
+            (name.clone(), reduced)
+        })
+        .collect()
+}
+
+pub(crate) fn median(sorted: &[f32]) -> f32 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Krum scores: the summed squared distance of every update to its n - f - 2 nearest neighbours.
+fn krum_scores(flats: &[Flat], byzantine: u64) -> Result<Vec<f64>, String> {
+    let n = flats.len();
+    if n < 2 * byzantine as usize + 3 {
+        return Err(format!(
+            "Krum needs at least {} updates to tolerate {} byzantine clients, got {}.",
+            2 * byzantine + 3,
+            byzantine,
+            n
+        ));
+    }
+    let neighbours = n - byzantine as usize - 2;
+    let mut distances = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in (i + 1)..n {
+            let d = squared_distance(&flats[i], &flats[j]);
+            distances[i][j] = d;
+            distances[j][i] = d;
+        }
+    }
+    Ok(distances
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut row)| {
+            row.remove(i);
+            row.sort_by(|a, b| a.total_cmp(b));
+            row[..neighbours].iter().sum()
+        })
+        .collect())
+}
+
+/// Combines the compatible `updates` of a round. `global` is the model the round started
+/// from, it is the reference point of norm bounding and the template of the result.
+pub fn aggregate(aggregator: &Aggregator, global: &Checkpoint, updates: &[WeightedUpdate]) -> Result<Aggregate, String> {
+    if updates.is_empty() {
+        return Err("Nothing to aggregate.".to_string());
+    }
+    aggregator.validate()?;
+    let flats: Vec<Flat> = updates.iter().map(|u| flatten(&u.checkpoint)).collect::<Result<_, _>>()?;
+    let weights: Vec<f64> = updates.iter().map(|u| u.weight).collect();
+    let template = &updates[0].checkpoint;
+    let mut excluded = Vec::new();
+    let mut clipped = Vec::new();
+
+    let result = match aggregator {
+        Aggregator::FedAvg => weighted_mean(&flats.iter().collect::<Vec<_>>(), &weights),
+        Aggregator::Median => coordinate_wise(&flats, median),
+        Aggregator::TrimmedMean { trim_fraction } => {
+            let trim = (flats.len() as f64 * trim_fraction).floor() as usize;
+            coordinate_wise(&flats, |sorted| {
+                let kept = &sorted[trim..sorted.len() - trim];
+                kept.iter().sum::<f32>() / kept.len() as f32
+            })
+        }
+        Aggregator::Krum { byzantine } => {
+            let scores = krum_scores(&flats, *byzantine)?;
+            let best = (0..flats.len())
+                .min_by(|a, b| scores[*a].total_cmp(&scores[*b]))
+                .unwrap_or_default();
+            excluded = (0..flats.len()).filter(|i| *i != best).collect();
+            flats[best].clone()
+        }
+        Aggregator::MultiKrum { byzantine, select } => {
+            let scores = krum_scores(&flats, *byzantine)?;
+            let mut ranked: Vec<usize> = (0..flats.len()).collect();
+            ranked.sort_by(|a, b| scores[*a].total_cmp(&scores[*b]));
+            let keep = (*select as usize).min(flats.len());
+            excluded = ranked.split_off(keep);
+            excluded.sort();
+            let kept: Vec<&Flat> = ranked.iter().map(|i| &flats[*i]).collect();
+            let kept_weights: Vec<f64> = ranked.iter().map(|i| weights[*i]).collect();
+            weighted_mean(&kept, &kept_weights)
+        }
+        Aggregator::NormBounded { max_norm } => {
+            let base = flatten(global)?;
+            let bounded: Vec<Flat> = flats
+                .iter()
+                .enumerate()
+                .map(|(i, flat)| {
+                    let norm = squared_distance(flat, &base).sqrt();
+                    if norm <= *max_norm {
+                        return flat.clone();
+                    }
+                    clipped.push(i);
+                    let scale = (*max_norm / norm) as f32;
+                    flat.iter()
+                        .map(|(name, values)| {
+                            let clipped = values
+                                .iter()
+                                .zip(&base[name])
+                                .map(|(v, b)| b + (v - b) * scale)
+                                .collect();
+                            (name.clone(), clipped)
+                        })
+                        .collect()
+                })
+                .collect();
+            weighted_mean(&bounded.iter().collect::<Vec<_>>(), &weights)
+        }
+    };
+    Ok(Aggregate { checkpoint: rebuild(template, result)?, excluded, clipped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint as checkpoint;
+
+    fn updates(values: &[(&[f32], f64)]) -> Vec<WeightedUpdate> {
+        values
+            .iter()
+            .map(|(values, weight)| WeightedUpdate { checkpoint: checkpoint(values), weight: *weight })
+            .collect()
+    }
+
+    fn run(aggregator: Aggregator, global: &[f32], updates: &[WeightedUpdate]) -> (Vec<f32>, Vec<usize>, Vec<usize>) {
+        let result = aggregate(&aggregator, &checkpoint(global), updates).unwrap();
+        let values = flatten(&result.checkpoint).unwrap().remove("w").unwrap();
+        (values, result.excluded, result.clipped)
+    }
+
+    fn assert_close(values: &[f32], expected: &[f32]) {
+        assert_eq!(values.len(), expected.len());
+        for (value, expected) in values.iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-5, "{:?} != {:?}", values, expected);
+        }
+    }
+
+    #[test]
+    fn median_and_trimmed_mean_ignore_outliers_and_weights() {
+        let outlier = updates(&[(&[1.0, 5.0], 1.0), (&[2.0, 4.0], 1.0), (&[10.0, -3.0], 100.0)]);
+        let (values, excluded, clipped) = run(Aggregator::Median, &[0.0, 0.0], &outlier);
+        assert_close(&values, &[2.0, 4.0]);
+        assert!(excluded.is_empty() && clipped.is_empty());
+
+        let four = updates(&[(&[1.0], 1.0), (&[2.0], 1.0), (&[3.0], 1.0), (&[100.0], 1.0)]);
+        let (values, _, _) = run(Aggregator::Median, &[0.0], &four);
+        assert_close(&values, &[2.5]);
+        // A quarter of four updates drops one value from either end.
+        let (values, excluded, _) = run(Aggregator::TrimmedMean { trim_fraction: 0.25 }, &[0.0], &four);
+        assert_close(&values, &[2.5]);
+        assert!(excluded.is_empty());
+        let (values, _, _) = run(Aggregator::TrimmedMean { trim_fraction: 0.0 }, &[0.0], &four);
+        assert_close(&values, &[26.5]);
+    }
+
+    #[test]
+    fn krum_keeps_the_updates_closest_to_their_neighbours() {
+        // Scores over the two nearest neighbours: 0.05, 0.06, 0.09 and far more for the attacker.
+        let round = updates(&[(&[0.0, 0.0], 1.0), (&[0.1, 0.0], 3.0), (&[0.0, 0.2], 1.0), (&[10.0, 10.0], 1.0)]);
+        let (values, excluded, _) = run(Aggregator::Krum { byzantine: 0 }, &[0.0, 0.0], &round);
+        assert_close(&values, &[0.0, 0.0]);
+        assert_eq!(excluded, vec![1, 2, 3]);
+
+        let (values, excluded, _) = run(Aggregator::MultiKrum { byzantine: 0, select: 2 }, &[0.0, 0.0], &round);
+        assert_close(&values, &[0.075, 0.0]);
+        assert_eq!(excluded, vec![2, 3]);
+
+        let err = aggregate(&Aggregator::Krum { byzantine: 1 }, &checkpoint(&[0.0, 0.0]), &round).err().unwrap();
+        assert!(err.contains("at least 5 updates"), "{}", err);
+    }
+
+    #[test]
+    fn norm_bounding_clips_updates_towards_the_global_model() {
+        let round = updates(&[(&[4.0, 5.0], 1.0), (&[1.0, 1.5], 1.0)]);
+        // The first update is 5 away from the global model and is scaled down to 1.
+        let (values, excluded, clipped) = run(Aggregator::NormBounded { max_norm: 1.0 }, &[1.0, 1.0], &round);
+        assert_close(&values, &[1.3, 1.65]);
+        assert!(excluded.is_empty());
+        assert_eq!(clipped, vec![0]);
+
+        assert!(Aggregator::NormBounded { max_norm: 0.0 }.validate().is_err());
+        assert!(Aggregator::TrimmedMean { trim_fraction: 0.5 }.validate().is_err());
+        assert!(Aggregator::MultiKrum { byzantine: 0, select: 0 }.validate().is_err());
+    }
+}
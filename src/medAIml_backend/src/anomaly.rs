@@ -0,0 +1,174 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::aggregation::{coordinate_wise, median, Flat};
+
+//Screening of the submissions of a round before aggregation. Every update is scored against
+//the cohort by the L2 norm of its change to the global model, the cosine similarity of that
+//change to the coordinate-wise median change, and its reported loss against the loss history
+//of the client. Updates failing any check are quarantined: left out of the aggregate and
+//counted against the node in the clinic registry.
+
+// Scale of the median absolute deviation to a standard deviation for normal data.
+const MAD_SCALE: f64 = 1.4826;
+// The cohort checks need a median that a single update cannot move.
+const MIN_COHORT: usize = 3;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AnomalyConfig {
+    // Robust z-score of the update norm above which an update is an outlier.
+    pub max_norm_zscore: f64,
+    // Smallest cosine similarity to the median update.
+    pub min_cosine: f64,
+    // Reported loss may differ from the client's history by at most this factor, either way.
+    pub max_loss_ratio: f64,
+    // Quarantines after which a node is flagged in the registry.
+    pub flag_after: u64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            max_norm_zscore: 3.5,
+            min_cosine: 0.0,
+            max_loss_ratio: 3.0,
+            flag_after: 3,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_norm_zscore <= 0.0 || self.max_loss_ratio < 1.0 {
+            return Err("The norm z-score must be positive and the loss ratio at least 1.".to_string());
+        }
+        if !(-1.0..=1.0).contains(&self.min_cosine) {
+            return Err("The minimum cosine similarity must lie in [-1, 1].".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AnomalyScore {
+    pub principal: Principal,
+    pub norm: f64,
+    // Cohort scores are `None` when the round had too few submissions.
+    pub norm_zscore: Option<f64>,
+    pub cosine_to_median: Option<f64>,
+    // `None` without loss history for the client.
+    pub loss_ratio: Option<f64>,
+    // Why the update was quarantined, empty when it passed.
+    pub reasons: Vec<String>,
+}
+
+/// What is known about one submission.
+pub struct Submission<'a> {
+    pub principal: Principal,
+    pub flat: &'a Flat,
+    pub loss: f64,
+    pub historical_loss: Option<f64>,
+}
+
+fn delta(flat: &Flat, base: &Flat) -> Vec<f64> {
+    base.iter()
+        .flat_map(|(name, b)| flat[name].iter().zip(b).map(|(v, b)| (*v - *b) as f64))
+        .collect()
+}
+
+fn norm(v: &[f64]) -> f64 {
+    v.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+fn median_f64(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Scores every submission against the others and `base`, the global model of the round.
+pub fn screen(config: &AnomalyConfig, base: &Flat, submissions: &[Submission]) -> Vec<AnomalyScore> {
+    let deltas: Vec<Vec<f64>> = submissions.iter().map(|s| delta(s.flat, base)).collect();
+    let norms: Vec<f64> = deltas.iter().map(|d| norm(d)).collect();
+    let cohort = submissions.len() >= MIN_COHORT;
+
+    let (norm_median, norm_spread) = if cohort {
+        let m = median_f64(&norms);
+        let deviations: Vec<f64> = norms.iter().map(|n| (n - m).abs()).collect();
+        (m, MAD_SCALE * median_f64(&deviations))
+    } else {
+        (0.0, 0.0)
+    };
+    let median_delta = cohort.then(|| {
+        let flats: Vec<Flat> = submissions.iter().map(|s| s.flat.clone()).collect();
+        delta(&coordinate_wise(&flats, median), base)
+    });
+
+    submissions
+        .iter()
+        .zip(deltas.iter().zip(&norms))
+        .map(|(submission, (delta, norm_value))| {
+            let mut reasons = Vec::new();
+            // Identical norms across the cohort leave nothing to compare against.
+            let norm_zscore = (cohort && norm_spread > 0.0).then(|| (norm_value - norm_median) / norm_spread);
+            if let Some(z) = norm_zscore.filter(|z| *z > config.max_norm_zscore) {
+                reasons.push(format!("Update norm {:.4} has robust z-score {:.2}.", norm_value, z));
+            }
+            let cosine_to_median = median_delta.as_ref().and_then(|m| {
+                let denominator = norm(m) * norm_value;
+                (denominator > 0.0).then(|| delta.iter().zip(m).map(|(a, b)| a * b).sum::<f64>() / denominator)
+            });
+            if let Some(c) = cosine_to_median.filter(|c| *c < config.min_cosine) {
+                reasons.push(format!("Cosine similarity to the median update is {:.3}.", c));
+            }
+            let loss_ratio = submission.historical_loss.filter(|h| *h > 0.0).map(|h| submission.loss / h);
+            if let Some(r) = loss_ratio.filter(|r| *r > config.max_loss_ratio || *r < 1.0 / config.max_loss_ratio) {
+                reasons.push(format!("Reported loss is {:.2} times the client's history.", r));
+            }
+            AnomalyScore {
+                principal: submission.principal,
+                norm: *norm_value,
+                norm_zscore,
+                cosine_to_median,
+                loss_ratio,
+                reasons,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(values: &[f32]) -> Flat {
+        Flat::from([("w".to_string(), values.to_vec())])
+    }
+
+    #[test]
+    fn outliers_are_quarantined_with_reasons() {
+        let base = flat(&[0.0, 0.0]);
+        let flats = [flat(&[1.0, 1.0]), flat(&[1.1, 0.9]), flat(&[0.9, 1.1]), flat(&[-40.0, -40.0]), flat(&[1.0, 1.05])];
+        let submissions: Vec<Submission> = flats
+            .iter()
+            .enumerate()
+            .map(|(i, flat)| Submission {
+                principal: Principal::from_slice(&[i as u8]),
+                flat,
+                loss: if i == 4 { 5.0 } else { 0.5 },
+                historical_loss: Some(0.5),
+            })
+            .collect();
+        let scores = screen(&AnomalyConfig::default(), &base, &submissions);
+        let flagged: Vec<usize> = (0..scores.len()).filter(|i| !scores[*i].reasons.is_empty()).collect();
+        assert_eq!(flagged, vec![3, 4]);
+        // The flipped update fails on both its norm and its direction.
+        assert_eq!(scores[3].reasons.len(), 2);
+        assert!(scores[4].reasons[0].contains("loss"));
+    }
+}
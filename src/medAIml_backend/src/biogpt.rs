@@ -3,6 +3,7 @@ use ic_stable_structures::{
     DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
 use candle_nn::{optim, Conv2d, linear, Optimizer, loss, VarMap, VarBuilder, Activation};
+use crate::access::is_controller;
 use crate::storage;
 use candle_transformers::models::bert::{HiddenAct as OtherHiddenAct};
 use std::cell::RefCell;
@@ -19,6 +20,7 @@ use candle_core::{Module, Result as CandleResult, DType, Device, Tensor, IndexOp
 use candle_nn::{Linear, Embedding, LayerNorm};
 // use candle_core::safetensors;
 use std::path::Path;
+use std::time::Duration;
 
 
 const DEVICE: Device = Device::Cpu;
@@ -41,6 +43,8 @@ thread_local! {
 }
 
 // A global OnceCell to hold the initialized canister state, ensuring it's loaded only once.
+// Queries cannot fill it, their heap changes are discarded, so it is loaded by `load_biogpt`
+// after an upload and by `warm_up` after an upgrade.
 static CANISTER_STATE: OnceCell<CanisterState> = OnceCell::new();
 
 #[ic_cdk::update]
@@ -261,10 +265,8 @@ pub struct CanisterState {
     pub config: BioGptConfig,
 }
 
-/// The canister initialization function.
-/// This runs once when the canister is deployed and loads the model from stable memory.
-// #[init]
-fn init() {
+/// Loads the model and config from stable memory.
+fn load_canister_state() -> CanisterState {
     // Load config from stable memory.
     let config_bytes = storage::bytes(BIOGPT_CONFIG.to_string());
     let config: BioGptConfig = serde_json::from_slice(&config_bytes)
@@ -285,11 +287,25 @@ fn init() {
     let model = BioGptModel::load(vb, &config)
         .expect("Failed to load BioGPT model");
 
-    // Store the model and config in the global `OnceCell`.
-    CANISTER_STATE.set(CanisterState { model, config })
-        .expect("Failed to set canister state, it's already initialized");
-
     ic_cdk::println!("BioGPT model and config loaded successfully!");
+
+    CanisterState { model, config }
+}
+
+/// Loads the uploaded model so `generate_response` can serve it.
+#[ic_cdk::update(guard = "is_controller")]
+fn load_biogpt() {
+    CANISTER_STATE.get_or_init(load_canister_state);
+}
+
+/// Reloads the model in a timer right after an upgrade, when one was uploaded.
+pub fn warm_up() {
+    if storage::len(BIOGPT_RECCOMMENDATION) == 0 || storage::len(BIOGPT_CONFIG) == 0 {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        CANISTER_STATE.get_or_init(load_canister_state);
+    });
 }
 
 // --- Generation Logic and Query Endpoint ---
@@ -306,7 +322,7 @@ fn simple_decoder(ids: &[u32]) -> String {
 
 #[ic_cdk::query]
 pub fn generate_response(prompt: String, max_new_tokens: usize) -> String {
-    let canister_state = CANISTER_STATE.get().expect("Canister state not initialized");
+    let canister_state = CANISTER_STATE.get().expect("BioGPT is not loaded, call load_biogpt after uploading it");
     let model = &canister_state.model;
     let config = &canister_state.config;
 
@@ -0,0 +1,71 @@
+use candle_core::{DType, Device, Tensor};
+use std::collections::BTreeMap;
+
+const DEVICE: Device = Device::Cpu;
+
+/// A complete model checkpoint: every named tensor of a safetensors file.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub tensors: BTreeMap<String, Tensor>,
+}
+
+pub(crate) fn is_float(dtype: DType) -> bool {
+    matches!(dtype, DType::F16 | DType::BF16 | DType::F32 | DType::F64)
+}
+
+impl Checkpoint {
+    pub fn from_safetensors(bytes: &[u8]) -> Result<Self, String> {
+        let tensors = candle_core::safetensors::load_buffer(bytes, &DEVICE)
+            .map_err(|e| format!("Failed to load weights: {:?}", e))?;
+        Ok(Checkpoint { tensors: tensors.into_iter().collect() })
+    }
+
+    pub fn to_safetensors(&self) -> Result<Vec<u8>, String> {
+        safetensors::tensor::serialize(&self.tensors, &None)
+            .map_err(|e| format!("Failed to serialize weights: {:?}", e))
+    }
+
+    /// Decodes the safetensors of a client update, which must match `global` tensor by tensor.
+    pub fn from_update(weights: &[u8], global: &Checkpoint) -> Result<Self, String> {
+        let checkpoint = Checkpoint::from_safetensors(weights)?;
+        checkpoint.check_compatible(global)?;
+        Ok(checkpoint)
+    }
+
+    /// Fails unless both checkpoints hold the same tensor names with the same dtypes and shapes.
+    pub fn check_compatible(&self, reference: &Checkpoint) -> Result<(), String> {
+        if let Some(name) = reference.tensors.keys().find(|name| !self.tensors.contains_key(*name)) {
+            return Err(format!("Tensor {} is missing from the update.", name));
+        }
+        if let Some(name) = self.tensors.keys().find(|name| !reference.tensors.contains_key(*name)) {
+            return Err(format!("Tensor {} is not part of the global model.", name));
+        }
+        for (name, tensor) in &self.tensors {
+            let expected = &reference.tensors[name];
+            if tensor.dtype() != expected.dtype() {
+                return Err(format!(
+                    "Tensor {} has dtype {:?}, expected {:?}.",
+                    name,
+                    tensor.dtype(),
+                    expected.dtype()
+                ));
+            }
+            if tensor.dims() != expected.dims() {
+                return Err(format!(
+                    "Tensor {} has shape {:?}, expected {:?}.",
+                    name,
+                    tensor.dims(),
+                    expected.dims()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A checkpoint holding the single float tensor `w`, for tests.
+#[cfg(test)]
+pub(crate) fn test_checkpoint(values: &[f32]) -> Checkpoint {
+    let tensor = Tensor::from_slice(values, values.len(), &DEVICE).unwrap();
+    Checkpoint { tensors: BTreeMap::from([("w".to_string(), tensor)]) }
+}
@@ -18,7 +18,7 @@ use ic_cdk_macros::{self, query, update};
 use serde::{Serialize, Deserialize};
 use serde_json::{self, Value};
 use ic_stable_structures::{
-    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    memory_manager::{MemoryManager, VirtualMemory},
     DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
 };
 use candle_transformers::models::resnet::resnet50;
@@ -26,6 +26,10 @@ use ic_stable_structures::storable::Blob;
 use std::path::Path;
 use candle_core::safetensors;
 use crate::storage;
+use crate::checkpoint::Checkpoint;
+use crate::delta::UpdateEncoding;
+use crate::registry::{self, ResolvedModel};
+use crate::personalization;
 use candle_nn::ops::{sigmoid, softmax};
 use candle_nn::ops::leaky_relu;
 // use image::GenericImageView;
@@ -36,12 +40,12 @@ const FEATURE_DIMENSION: usize = 7;
 const NUM_CLASSES: usize = 2;
 const LABELS: usize = 2;
 
-//Define a heap memory to store the model weights and uploaded file. 
-const WASI_MEMORY_ID: MemoryId = MemoryId::new(10);
-
 // Files in the WASI filesystem (in the stable memory) that store the models.
-const MALARIA_MODEL: &str = "malaria_mobilenetSmall.safetensors";
-const MODEL_CONFIG: &str = "config.json";
+pub(crate) const MALARIA_MODEL: &str = "malaria_mobilenetSmall.safetensors";
+pub(crate) const MODEL_CONFIG: &str = "config.json";
+
+// Registry task served by `load_and_predict`.
+pub const MALARIA_TASK: &str = "malaria";
 
 type Memory1 = VirtualMemory<DefaultMemoryImpl>;
 thread_local! {
@@ -77,21 +81,62 @@ pub struct Model {
 }
 
 
+//Describes a locally trained update. Its weights, or its delta to `model_version`, are
+//uploaded beforehand with `append_update_bytes`.
 #[derive(Serialize, Deserialize, Clone, CandidType)]
-pub struct ModelWeights{
-    pub model_weights: Vec<String>,
-    pub num_samples: usize,
+pub struct ClientUpdate {
+    pub task: String,
+    pub num_samples: u64,
     pub loss: f64,
-    pub model_version: usize,
+    // Global version the update was trained from.
+    pub model_version: u64,
+    // FedProx mu the client actually trained with.
+    pub proximal_mu: f64,
+    // How the uploaded bytes encode the update.
+    pub encoding: UpdateEncoding,
+}
+
+impl ClientUpdate {
+    /// Checks what the update reports about itself against a round on `base_version` and
+    /// returns how many versions it lags behind. The simulator submits through it as well.
+    pub fn check(&self, base_version: u64, max_staleness: u64) -> Result<u64, String> {
+        if self.num_samples == 0 {
+            return Err("An update must be trained on at least one sample.".to_string());
+        }
+        if !self.loss.is_finite() {
+            return Err("The reported loss must be a finite number.".to_string());
+        }
+        if !(self.proximal_mu >= 0.0 && self.proximal_mu.is_finite()) {
+            return Err("The reported proximal mu must be a non-negative number.".to_string());
+        }
+        if self.model_version > base_version {
+            return Err(format!(
+                "Update was trained from version {} but the round runs on version {}.",
+                self.model_version, base_version
+            ));
+        }
+        let staleness = base_version - self.model_version;
+        if staleness > max_staleness {
+            return Err(format!(
+                "Update was trained from version {} but the global model is at version {}.",
+                self.model_version, base_version
+            ));
+        }
+        Ok(staleness)
+    }
 }
 
-//Define the defaulttraining configurations for the model.
-#[derive(Debug, Clone)]
+//Define the defaulttraining configurations for the model. Rounds hand it to the clients
+//they invite.
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq)]
 pub struct TrainingConfig {
-    pub num_epochs: usize,
-    pub batch_size: usize,
-    pub num_workers: usize,
+    pub num_epochs: u64,
+    pub batch_size: u64,
+    pub num_workers: u64,
     pub seed: u64,
+    pub learning_rate: f64,
+    // FedProx proximal coefficient, 0 trains plain FedAvg.
+    pub proximal_mu: f64,
 }
 
 impl Default for TrainingConfig {
@@ -101,10 +146,42 @@ impl Default for TrainingConfig {
             batch_size: 64,      
             num_workers: 4,      
             seed: 42,            
+            learning_rate: 1e-3,
+            proximal_mu: 0.0,
         }
     }
 }
 
+impl TrainingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.num_epochs == 0 || self.batch_size == 0 {
+            return Err("Epochs and batch size must be at least 1.".to_string());
+        }
+        if !(self.learning_rate > 0.0 && self.learning_rate.is_finite()) {
+            return Err("The learning rate must be a positive number.".to_string());
+        }
+        if !(self.proximal_mu >= 0.0 && self.proximal_mu.is_finite()) {
+            return Err("The proximal mu must be a non-negative number.".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// FedProx proximal term mu/2 * ||w - w_global||^2 over the trainable variables that are
+/// part of the global model. Local trainers add it to their loss.
+pub fn proximal_term(varmap: &VarMap, global: &Checkpoint, mu: f64) -> CandleResult<Tensor> {
+    let mut term = Tensor::zeros((), DType::F32, &DEVICE);
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        let Some(reference) = global.tensors.get(name) else {
+            continue;
+        };
+        let reference = reference.to_dtype(var.dtype())?;
+        let distance = var.as_tensor().sub(&reference)?.sqr()?.sum_all()?.to_dtype(DType::F32)?;
+        term = term?.add(&distance);
+    }
+    term?.affine(mu / 2.0, 0.0)
+}
+
 
 #[derive(Debug, serde::Deserialize, CandidType)]
 pub struct Dataset {
@@ -270,28 +347,18 @@ impl MalariaModelV3 {
 }
 
 
-#[ic_cdk::init]
-fn init() {
-    let wasi_memory = MEMORY_MANAGER.with(|m| m.borrow().get(WASI_MEMORY_ID));
-    ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
-}
-
-fn load_model_bytes_from_storage() -> Vec<u8> {
-    crate::storage::bytes("malaria_mobilenetSmall.safetensors".to_string()) 
-}
-
-fn load_config_from_storage() -> Vec<u8> {
-    crate::storage::bytes("config.json".to_string()) 
+//Production version from the registry, or the legacy upload keys before the first promotion.
+fn load_model_from_registry() -> ResolvedModel {
+    registry::resolve(MALARIA_TASK, MALARIA_MODEL, MODEL_CONFIG)
 }
 
 #[ic_cdk::update]
-pub fn load_and_predict(image_bytes: Vec<u8>) -> Result<(u32, String, f32), String> {
+pub fn load_and_predict(image_bytes: Vec<u8>) -> Result<(u32, String, f32, u64), String> {
     let device = Device::Cpu;
-    let mut varmap = VarMap::new();
-    let vb = VarBuilder::from_varmap(&mut varmap, DType::F32, &device);
 
     //Load model weights
-    let model_weights = load_model_bytes_from_storage();
+    let resolved = load_model_from_registry();
+    let model_weights = resolved.weights;
     if model_weights.is_empty() {
         return Err("Model weights not found in stable storage.".to_string());
     }
@@ -303,8 +370,12 @@ pub fn load_and_predict(image_bytes: Vec<u8>) -> Result<(u32, String, f32), Stri
         ic_cdk::println!("Available tensor key: {}", name);
     }
 
+    //The caller's personalised head replaces the global one when it has one.
+    let safetensors = personalization::personalize(MALARIA_TASK, &ic_cdk::caller(), safetensors);
+    let vb = VarBuilder::from_tensors(safetensors, DType::F32, &device);
+
     //Load model config
-    let config_bytes = load_config_from_storage();
+    let config_bytes = resolved.config;
     if config_bytes.is_empty() {
         return Err("Model config not found in stable storage.".to_string());
     }
@@ -359,7 +430,7 @@ pub fn load_and_predict(image_bytes: Vec<u8>) -> Result<(u32, String, f32), Stri
         .unwrap_or(&"Unknown")
         .to_string();
 
-    Ok((class_idx, label, class_prob))
+    Ok((class_idx, label, class_prob, resolved.version))
 
 }
 
@@ -379,3 +450,25 @@ pub fn get_model_weights() -> candle_core::Result<Vec<u8>> {
         }
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proximal_term_penalises_distance_to_global_model() {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &DEVICE);
+        let weight = vb.get_with_hints(3, "dense.weight", candle_nn::Init::Const(1.0)).unwrap();
+        assert_eq!(weight.dims(), &[3]);
+
+        let global = Tensor::new(&[1.0f32, 0.0, 3.0], &DEVICE).unwrap();
+        let checkpoint = Checkpoint { tensors: [("dense.weight".to_string(), global)].into_iter().collect() };
+
+        // ||(1, 1, 1) - (1, 0, 3)||^2 = 5, times mu / 2.
+        let term = proximal_term(&varmap, &checkpoint, 0.4).unwrap();
+        assert!((term.to_scalar::<f32>().unwrap() - 1.0).abs() < 1e-6);
+        let none = proximal_term(&varmap, &checkpoint, 0.0).unwrap();
+        assert_eq!(none.to_scalar::<f32>().unwrap(), 0.0);
+    }
+}
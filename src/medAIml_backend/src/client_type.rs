@@ -11,7 +11,7 @@ use std::ops::{Sub, Div};
 use candid::types::Serializer;
 use anyhow::{Context, Result};
 use candle_core::{DType, Device, Tensor, Module, Result as CandleResult};
-use candle_nn::{optim, Conv2d, linear, Optimizer, loss, VarMap, VarBuilder, Activation};
+use candle_nn::{optim, Conv2d, linear, Optimizer, loss, VarBuilder, Activation};
 use candle_nn::Sequential;
 // use candle_nn::Linear;
 use candle_nn::linear::Linear;
@@ -28,6 +28,8 @@ use ic_stable_structures::storable::Blob;
 use std::path::Path;
 use candle_core::safetensors;
 use crate::storage;
+use crate::registry::{self, ResolvedModel};
+use crate::personalization;
 use candle_nn::ops::{sigmoid, softmax};
 use candle_nn::ops::leaky_relu;
 // use image::GenericImageView;
@@ -42,8 +44,11 @@ const LABELS: usize = 4;
 const WASI_MEMORY_ID: MemoryId = MemoryId::new(7);
 
 // Files in the WASI filesystem (in the stable memory) that store the models.
-const MALARIA_MODEL_MAL: &str = "malaria_types_small.safetensors";
-const MODEL_CONFIG_MAL: &str = "malaria_multiclass.json";
+pub(crate) const MALARIA_MODEL_MAL: &str = "malaria_types_small.safetensors";
+pub(crate) const MODEL_CONFIG_MAL: &str = "malaria_multiclass.json";
+
+// Registry task served by `load_and_predict_malaria_stage`.
+pub const MALARIA_STAGE_TASK: &str = "malaria_stage";
 
 type Memory2 = VirtualMemory<DefaultMemoryImpl>;
 thread_local! {
@@ -176,21 +181,17 @@ impl MalariaModelV3Types {
 //     ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
 // }
 
-fn load_model_stage_from_storage() -> Vec<u8> {
-    crate::storage::bytes("malaria_types_small.safetensors".to_string()) 
-}
-
-fn load_config_stage_from_storage() -> Vec<u8> {
-    crate::storage::bytes("malaria_multiclass.json".to_string()) 
+//Production version from the registry, or the legacy upload keys before the first promotion.
+fn load_model_stage_from_registry() -> ResolvedModel {
+    registry::resolve(MALARIA_STAGE_TASK, MALARIA_MODEL_MAL, MODEL_CONFIG_MAL)
 }
 
 #[ic_cdk::update]
-pub fn load_and_predict_malaria_stage(image_bytes: Vec<u8>) -> Result<(String, f32), String> {
+pub fn load_and_predict_malaria_stage(image_bytes: Vec<u8>) -> Result<(String, f32, u64), String> {
     let device = Device::Cpu;
-    let mut varmap = VarMap::new();
-    let vb = VarBuilder::from_varmap(&mut varmap, DType::F32, &device);
     
-    let model_weights_stage = load_model_stage_from_storage();
+    let resolved = load_model_stage_from_registry();
+    let model_weights_stage = resolved.weights;
     if model_weights_stage.is_empty() {
         return Err("Model weights not found in stable storage.".to_string());
     }
@@ -202,8 +203,12 @@ pub fn load_and_predict_malaria_stage(image_bytes: Vec<u8>) -> Result<(String, f
         ic_cdk::println!("Available tensor key: {}", name);
     }
 
+    //The caller's personalised head replaces the global one when it has one.
+    let safetensors = personalization::personalize(MALARIA_STAGE_TASK, &ic_cdk::caller(), safetensors);
+    let vb = VarBuilder::from_tensors(safetensors, DType::F32, &device);
+
     //Load model config
-    let config_bytes = load_config_stage_from_storage();
+    let config_bytes = resolved.config;
     if config_bytes.is_empty() {
         return Err("Model config not found in stable storage.".to_string());
     }
@@ -254,7 +259,7 @@ pub fn load_and_predict_malaria_stage(image_bytes: Vec<u8>) -> Result<(String, f
         .unwrap_or(&"Unknown")
         .to_string();
 
-    Ok((label, class_prob))
+    Ok((label, class_prob, resolved.version))
 
 }
 
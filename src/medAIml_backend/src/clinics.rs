@@ -0,0 +1,356 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::access::{is_controller, is_participant};
+use crate::incentives;
+use crate::rng::Rng;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Registry of the clinic nodes taking part in federated learning, and the selection of the
+//nodes invited to each round. Only registered, active nodes are ever invited, and none that
+//were flagged for repeatedly submitting anomalous updates.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceClass {
+    Mobile,
+    Workstation,
+    Server,
+}
+
+/// What a node reports about itself when registering.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NodeProfile {
+    pub clinic_name: String,
+    pub region: String,
+    pub device_class: DeviceClass,
+    // Training samples held locally.
+    pub dataset_size: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ClinicNode {
+    pub principal: Principal,
+    pub profile: NodeProfile,
+    pub registered_at: u64,
+    // Inactive nodes are kept for their history but never invited.
+    pub active: bool,
+    pub rounds_invited: u64,
+    pub rounds_participated: u64,
+    pub samples_contributed: u64,
+    pub last_participation: Option<u64>,
+    // Smoothed share of invitations that led to a submission.
+    pub reliability: f64,
+    // Moving average of the loss reported with accepted updates.
+    #[serde(default)]
+    pub mean_loss: Option<f64>,
+    // Updates that were quarantined as anomalous.
+    #[serde(default)]
+    pub quarantined: u64,
+    // Set once a node was quarantined repeatedly, cleared by a controller. Flagged nodes are
+    // not invited and cannot join buffered rounds.
+    #[serde(default)]
+    pub flagged: bool,
+}
+
+impl_storable_json!(ClinicNode);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub enum SelectionStrategy {
+    // Every active node is invited.
+    #[default]
+    All,
+    Random { count: u64 },
+    // Up to `per_region` random nodes from every region.
+    StratifiedByRegion { per_region: u64 },
+    // `count` nodes drawn with probability proportional to their dataset size.
+    WeightedByDataVolume { count: u64 },
+    // `count` nodes drawn with probability proportional to the credit they earned on the task.
+    WeightedByContribution { count: u64 },
+}
+
+impl SelectionStrategy {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            SelectionStrategy::Random { count: 0 }
+            | SelectionStrategy::WeightedByDataVolume { count: 0 }
+            | SelectionStrategy::WeightedByContribution { count: 0 }
+            | SelectionStrategy::StratifiedByRegion { per_region: 0 } => {
+                Err("The selection must invite at least one node.".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Credit every node starts from, so that newcomers can still be picked.
+const BASE_CREDIT: f64 = 0.05;
+
+/// Efraimidis-Spirakis: keeps the `count` nodes with the largest u^(1/w).
+fn weighted_draw(rng: &mut Rng, weighted: Vec<(f64, Principal)>, count: u64) -> Vec<Principal> {
+    let mut keyed: Vec<(f64, Principal)> = weighted
+        .into_iter()
+        .map(|(weight, principal)| (rng.next_f64().powf(1.0 / weight), principal))
+        .collect();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().take(count as usize).map(|(_, principal)| principal).collect()
+}
+
+thread_local! {
+    static NODES: RefCell<StableBTreeMap<Principal, ClinicNode, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::NODES_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_nodes() -> Vec<(Principal, ClinicNode)> {
+    NODES.with(storage::entries)
+}
+
+/// Replaces every registered node, used when a snapshot is restored.
+pub(crate) fn replace_nodes(nodes: Vec<(Principal, ClinicNode)>) {
+    NODES.with(|m| storage::replace_entries(m, nodes))
+}
+
+pub fn node(principal: &Principal) -> Option<ClinicNode> {
+    NODES.with(|n| n.borrow().get(principal))
+}
+
+pub fn nodes() -> Vec<ClinicNode> {
+    NODES.with(|n| n.borrow().iter().map(|(_, node)| node).collect())
+}
+
+/// Registers `principal`, or updates its profile while keeping its history.
+pub fn register(principal: Principal, profile: NodeProfile, now: u64) -> Result<ClinicNode, String> {
+    if profile.clinic_name.trim().is_empty() {
+        return Err("The clinic name must not be empty.".to_string());
+    }
+    if profile.region.trim().is_empty() {
+        return Err("The region must not be empty.".to_string());
+    }
+    let node = match node(&principal) {
+        Some(existing) => ClinicNode { profile, ..existing },
+        None => ClinicNode {
+            principal,
+            profile,
+            registered_at: now,
+            active: true,
+            rounds_invited: 0,
+            rounds_participated: 0,
+            samples_contributed: 0,
+            last_participation: None,
+            reliability: 0.5,
+            mean_loss: None,
+            quarantined: 0,
+            flagged: false,
+        },
+    };
+    NODES.with(|n| n.borrow_mut().insert(principal, node.clone()));
+    Ok(node)
+}
+
+/// Whether `node` may be invited to rounds or join buffered ones.
+pub fn eligible(node: &ClinicNode) -> bool {
+    node.active && !node.flagged
+}
+
+/// Picks the nodes invited to round `round` of `task`. The draw is seeded with the task,
+/// the round and the time, so it is reproducible from the round record.
+pub fn select(strategy: &SelectionStrategy, task: &str, round: u64, now: u64) -> Vec<Principal> {
+    let mut candidates: Vec<ClinicNode> = nodes().into_iter().filter(eligible).collect();
+    let mut rng = Rng::from_seed(format!("{}/{}/{}", task, round, now).as_bytes());
+
+    let mut selected: Vec<Principal> = match strategy {
+        SelectionStrategy::All => candidates.iter().map(|node| node.principal).collect(),
+        SelectionStrategy::Random { count } => {
+            rng.shuffle(&mut candidates);
+            candidates.iter().take(*count as usize).map(|node| node.principal).collect()
+        }
+        SelectionStrategy::StratifiedByRegion { per_region } => {
+            let mut regions: BTreeMap<String, Vec<Principal>> = BTreeMap::new();
+            for node in &candidates {
+                regions.entry(node.profile.region.clone()).or_default().push(node.principal);
+            }
+            regions
+                .into_values()
+                .flat_map(|mut members| {
+                    rng.shuffle(&mut members);
+                    members.truncate(*per_region as usize);
+                    members
+                })
+                .collect()
+        }
+        SelectionStrategy::WeightedByDataVolume { count } => {
+            // Nodes without data still get a small chance of being picked.
+            let weighted = candidates
+                .iter()
+                .map(|node| (node.profile.dataset_size.max(1) as f64, node.principal))
+                .collect();
+            weighted_draw(&mut rng, weighted, *count)
+        }
+        SelectionStrategy::WeightedByContribution { count } => {
+            let totals = incentives::totals(task);
+            let weighted = candidates
+                .iter()
+                .map(|node| {
+                    let credit = totals.get(&node.principal).map_or(0.0, |total| total.credit.max(0.0));
+                    (BASE_CREDIT + credit, node.principal)
+                })
+                .collect();
+            weighted_draw(&mut rng, weighted, *count)
+        }
+    };
+    selected.sort();
+    selected
+}
+
+/// Updates the participation history of the invited nodes once a round closed.
+/// `participants` holds the principal, sample count and loss of every accepted submission.
+pub fn record_round(invited: &[Principal], participants: &[(Principal, u64, f64)], now: u64) {
+    NODES.with(|n| {
+        let mut n = n.borrow_mut();
+        for principal in invited {
+            let Some(mut node) = n.get(principal) else {
+                continue;
+            };
+            node.rounds_invited += 1;
+            if let Some((_, samples, loss)) = participants.iter().find(|(p, _, _)| p == principal) {
+                node.rounds_participated += 1;
+                node.samples_contributed += samples;
+                node.last_participation = Some(now);
+                node.mean_loss = Some(node.mean_loss.map_or(*loss, |mean| 0.8 * mean + 0.2 * loss));
+            }
+            node.reliability = (node.rounds_participated + 1) as f64 / (node.rounds_invited + 2) as f64;
+            n.insert(*principal, node);
+        }
+    });
+}
+
+/// Counts a quarantined update against each of `principals`, flagging repeat offenders.
+pub fn record_quarantine(principals: &[Principal], flag_after: u64) {
+    NODES.with(|n| {
+        let mut n = n.borrow_mut();
+        for principal in principals {
+            let Some(mut node) = n.get(principal) else {
+                continue;
+            };
+            node.quarantined += 1;
+            if node.quarantined >= flag_after.max(1) {
+                node.flagged = true;
+            }
+            n.insert(*principal, node);
+        }
+    });
+}
+
+#[ic_cdk::update(guard = "is_participant")]
+pub fn register_node(profile: NodeProfile) -> Result<ClinicNode, String> {
+    register(ic_cdk::caller(), profile, ic_cdk::api::time())
+}
+
+#[ic_cdk::query]
+pub fn my_node() -> Option<ClinicNode> {
+    node(&ic_cdk::caller())
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn list_nodes() -> Vec<ClinicNode> {
+    nodes()
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn set_node_active(principal: Principal, active: bool) -> Result<ClinicNode, String> {
+    let mut node = node(&principal).ok_or_else(|| format!("{} is not a registered node.", principal))?;
+    node.active = active;
+    NODES.with(|n| n.borrow_mut().insert(principal, node.clone()));
+    Ok(node)
+}
+
+/// Clears the flag of a node after its device was checked. Its quarantine count starts over.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn clear_node_flag(principal: Principal) -> Result<ClinicNode, String> {
+    let mut node = node(&principal).ok_or_else(|| format!("{} is not a registered node.", principal))?;
+    node.flagged = false;
+    node.quarantined = 0;
+    NODES.with(|n| n.borrow_mut().insert(principal, node.clone()));
+    Ok(node)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn remove_node(principal: Principal) {
+    NODES.with(|n| n.borrow_mut().remove(&principal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(id: u8, region: &str, dataset_size: u64) -> Principal {
+        let principal = Principal::from_slice(&[id]);
+        let profile = NodeProfile {
+            clinic_name: format!("Clinic {}", id),
+            region: region.to_string(),
+            device_class: DeviceClass::Mobile,
+            dataset_size,
+        };
+        register(principal, profile, 0).unwrap();
+        principal
+    }
+
+    #[test]
+    fn selection_only_invites_active_nodes() {
+        let nodes: Vec<Principal> = (1..=5).map(|id| add(id, "North", 100)).collect();
+        set_node_active(nodes[4], false).unwrap();
+
+        assert_eq!(select(&SelectionStrategy::All, "malaria", 1, 0), nodes[..4].to_vec());
+        let random = select(&SelectionStrategy::Random { count: 2 }, "malaria", 1, 0);
+        assert_eq!(random.len(), 2);
+        assert!(random.iter().all(|p| nodes[..4].contains(p)));
+        // The draw is reproducible from the task, round and time.
+        assert_eq!(select(&SelectionStrategy::Random { count: 2 }, "malaria", 1, 0), random);
+        assert_eq!(select(&SelectionStrategy::Random { count: 9 }, "malaria", 1, 0).len(), 4);
+        assert!(SelectionStrategy::Random { count: 0 }.validate().is_err());
+    }
+
+    #[test]
+    fn flagged_nodes_are_not_invited_until_cleared() {
+        let nodes: Vec<Principal> = (1..=3).map(|id| add(id, "North", 100)).collect();
+        record_quarantine(&nodes[..1], 2);
+        assert_eq!(select(&SelectionStrategy::All, "malaria", 1, 0), nodes);
+        record_quarantine(&nodes[..1], 2);
+        assert_eq!(select(&SelectionStrategy::All, "malaria", 2, 0), nodes[1..].to_vec());
+        clear_node_flag(nodes[0]).unwrap();
+        assert_eq!(select(&SelectionStrategy::All, "malaria", 3, 0), nodes);
+    }
+
+    #[test]
+    fn stratified_selection_draws_from_every_region() {
+        let north: Vec<Principal> = (1..=3).map(|id| add(id, "North", 100)).collect();
+        let south = add(4, "South", 100);
+        for round in 1..=10 {
+            let selected = select(&SelectionStrategy::StratifiedByRegion { per_region: 2 }, "malaria", round, 0);
+            assert_eq!(selected.len(), 3);
+            assert!(selected.contains(&south));
+            assert_eq!(selected.iter().filter(|p| north.contains(p)).count(), 2);
+        }
+    }
+
+    #[test]
+    fn weighted_draws_favour_heavy_nodes() {
+        let heavy = add(1, "North", 1_000_000);
+        let light: Vec<Principal> = (2..=4).map(|id| add(id, "North", 1)).collect();
+        for round in 1..=20 {
+            assert_eq!(select(&SelectionStrategy::WeightedByDataVolume { count: 1 }, "malaria", round, 0), vec![heavy]);
+        }
+        let all = select(&SelectionStrategy::WeightedByDataVolume { count: 4 }, "malaria", 1, 0);
+        assert!(light.iter().all(|p| all.contains(p)));
+
+        // Keys u^(1/w) of equal weights keep the order of the uniform draws.
+        let mut rng = Rng::from_seed(b"draw");
+        let draws: Vec<f64> = (0..3).map(|_| rng.next_f64()).collect();
+        let best = (0..3).max_by(|a, b| draws[*a].total_cmp(&draws[*b])).unwrap();
+        let weighted = light.iter().map(|p| (1.0, *p)).collect();
+        assert_eq!(weighted_draw(&mut Rng::from_seed(b"draw"), weighted, 1), vec![light[best]]);
+    }
+}
@@ -0,0 +1,50 @@
+use ic_cdk_timers::TimerId;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+//Deadline timers of federated and evaluation rounds. A task has at most one open round of
+//each kind, closed by a timer at its deadline unless it closes earlier. Timers do not survive
+//upgrades, so the modules owning the rounds re-arm them from `post_upgrade`.
+
+thread_local! {
+    static TIMERS: RefCell<BTreeMap<(&'static str, String), TimerId>> = RefCell::default();
+}
+
+/// Calls `close` for the open `kind` round of `task` at `deadline`, replacing its earlier timer.
+#[cfg(target_arch = "wasm32")]
+pub fn schedule<T: 'static>(
+    kind: &'static str,
+    task: &str,
+    deadline: u64,
+    now: u64,
+    close: fn(&str, u64) -> Result<T, String>,
+) {
+    cancel(kind, task);
+    let delay = std::time::Duration::from_nanos(deadline.saturating_sub(now));
+    let timer = ic_cdk_timers::set_timer(delay, {
+        let task = task.to_string();
+        move || {
+            if let Err(e) = close(&task, ic_cdk::api::time()) {
+                ic_cdk::println!("Failed to close {} of {}: {}", kind, task, e);
+            }
+        }
+    });
+    TIMERS.with(|t| t.borrow_mut().insert((kind, task.to_string()), timer));
+}
+
+/// Outside of a canister there are no timers, rounds only close when asked to.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn schedule<T: 'static>(
+    _kind: &'static str,
+    _task: &str,
+    _deadline: u64,
+    _now: u64,
+    _close: fn(&str, u64) -> Result<T, String>,
+) {
+}
+
+pub fn cancel(kind: &'static str, task: &str) {
+    if let Some(timer) = TIMERS.with(|t| t.borrow_mut().remove(&(kind, task.to_string()))) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
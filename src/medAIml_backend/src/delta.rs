@@ -0,0 +1,133 @@
+use candid::CandidType;
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregation::{flatten, rebuild};
+use crate::checkpoint::Checkpoint;
+
+//Compact client updates. Instead of a full safetensors checkpoint a client may upload the
+//Candid encoding of an `UpdateDelta`: the difference to the base version it trained from,
+//optionally restricted to its top-k coordinates and quantised.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum UpdateEncoding {
+    // Safetensors of the whole trained checkpoint.
+    #[default]
+    Full,
+    // Candid-encoded `UpdateDelta` against `model_version`.
+    Delta,
+    // Secure aggregation input: one little-endian u64 per float parameter of the global
+    // model, in `flatten` order, fixed-point encoded, weighted and masked.
+    Masked,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum DeltaValues {
+    F32(Vec<f32>),
+    // IEEE half precision bit patterns.
+    F16(Vec<u16>),
+    // The delta is `value * scale`.
+    Int8 { scale: f32, values: Vec<i8> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TensorDelta {
+    pub name: String,
+    // Flat positions of `values` for top-k sparsified tensors, `None` when dense.
+    pub indices: Option<Vec<u32>>,
+    pub values: DeltaValues,
+}
+
+/// Tensors missing from the delta did not change.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UpdateDelta {
+    pub tensors: Vec<TensorDelta>,
+}
+
+impl DeltaValues {
+    fn decode(&self) -> Vec<f32> {
+        match self {
+            DeltaValues::F32(values) => values.clone(),
+            DeltaValues::F16(bits) => bits.iter().map(|b| f16::from_bits(*b).to_f32()).collect(),
+            DeltaValues::Int8 { scale, values } => values.iter().map(|v| *v as f32 * scale).collect(),
+        }
+    }
+}
+
+/// Decodes a Candid `UpdateDelta` and adds it to `base`, returning the client's checkpoint.
+pub fn reconstruct(base: &Checkpoint, bytes: &[u8]) -> Result<Checkpoint, String> {
+    let delta: UpdateDelta = candid::decode_one(bytes).map_err(|e| format!("Failed to decode delta: {:?}", e))?;
+    let mut values = flatten(base)?;
+    for tensor in delta.tensors {
+        let target = values
+            .get_mut(&tensor.name)
+            .ok_or_else(|| format!("Tensor {} is not a float tensor of the base model.", tensor.name))?;
+        let decoded = tensor.values.decode();
+        if decoded.iter().any(|v| !v.is_finite()) {
+            return Err(format!("Delta of {} holds non-finite values.", tensor.name));
+        }
+        match tensor.indices {
+            None => {
+                if decoded.len() != target.len() {
+                    return Err(format!(
+                        "Dense delta of {} has {} values, expected {}.",
+                        tensor.name,
+                        decoded.len(),
+                        target.len()
+                    ));
+                }
+                target.iter_mut().zip(&decoded).for_each(|(t, d)| *t += d);
+            }
+            Some(indices) => {
+                if indices.len() != decoded.len() {
+                    return Err(format!("Sparse delta of {} has mismatched indices and values.", tensor.name));
+                }
+                for (index, d) in indices.iter().zip(&decoded) {
+                    let slot = target
+                        .get_mut(*index as usize)
+                        .ok_or_else(|| format!("Index {} is out of range for {}.", index, tensor.name))?;
+                    *slot += d;
+                }
+            }
+        }
+    }
+    rebuild(base, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint as checkpoint;
+
+    fn apply(indices: Option<Vec<u32>>, values: DeltaValues) -> Result<Vec<f32>, String> {
+        let delta = UpdateDelta { tensors: vec![TensorDelta { name: "w".to_string(), indices, values }] };
+        let bytes = candid::encode_one(delta).unwrap();
+        let next = reconstruct(&checkpoint(&[1.0, 2.0, 3.0]), &bytes)?;
+        Ok(flatten(&next)?.remove("w").unwrap())
+    }
+
+    #[test]
+    fn quantised_and_sparse_deltas_are_added_to_the_base() {
+        let half = [0.5f32, -1.0, 0.25].map(|v| f16::from_f32(v).to_bits()).to_vec();
+        assert_eq!(apply(None, DeltaValues::F16(half)).unwrap(), vec![1.5, 1.0, 3.25]);
+        let int8 = DeltaValues::Int8 { scale: 0.5, values: vec![2, -4, 0] };
+        assert_eq!(apply(None, int8).unwrap(), vec![2.0, 0.0, 3.0]);
+        let sparse = DeltaValues::Int8 { scale: 0.25, values: vec![4, -4] };
+        assert_eq!(apply(Some(vec![2, 0]), sparse).unwrap(), vec![0.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn malformed_deltas_are_rejected() {
+        let err = apply(Some(vec![0, 3]), DeltaValues::F32(vec![1.0, 1.0])).unwrap_err();
+        assert!(err.contains("Index 3 is out of range"), "{}", err);
+        assert!(apply(Some(vec![0]), DeltaValues::F32(vec![1.0, 1.0])).is_err());
+        assert!(apply(None, DeltaValues::F32(vec![1.0, 1.0])).is_err());
+        assert!(apply(None, DeltaValues::F16(vec![f16::INFINITY.to_bits(); 3])).is_err());
+
+        let unknown = UpdateDelta {
+            tensors: vec![TensorDelta { name: "b".to_string(), indices: None, values: DeltaValues::F32(vec![1.0]) }],
+        };
+        assert!(reconstruct(&checkpoint(&[1.0]), &candid::encode_one(unknown).unwrap()).is_err());
+        assert!(reconstruct(&checkpoint(&[1.0]), b"not candid").is_err());
+    }
+}
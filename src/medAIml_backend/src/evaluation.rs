@@ -0,0 +1,395 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::access::{is_controller, is_participant};
+use crate::agent::{self, GlobalModelChunk};
+use crate::clinics::{self, SelectionStrategy};
+use crate::deadlines;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Federated evaluation. An evaluation round asks the selected clinics to run a given version of
+//a task on their own labelled data and report the confusion matrix and nothing else. Once every
+//invited clinic reported, or the deadline passed, the matrices are summed into global and
+//per-region metrics: accuracy estimates from the field, next to the held-out validation set.
+
+// Kind of the deadline timers of evaluation rounds.
+const DEADLINE: &str = "evaluation round";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum EvaluationState {
+    Open,
+    Closed,
+    // The deadline passed without any report.
+    Abandoned,
+}
+
+/// What a clinic found evaluating the model on its local data.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EvaluationReport {
+    pub principal: Principal,
+    pub region: String,
+    // Rows are the true class, columns the predicted class.
+    pub confusion: Vec<Vec<u64>>,
+    pub num_samples: u64,
+    pub submitted_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EvaluationMetrics {
+    pub clinics: u64,
+    pub num_samples: u64,
+    pub accuracy: f64,
+    // By class index, `None` where the class never occurred or was never predicted.
+    pub recall: Vec<Option<f64>>,
+    pub precision: Vec<Option<f64>>,
+    // Sum of the reported matrices.
+    pub confusion: Vec<Vec<u64>>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RegionMetrics {
+    pub region: String,
+    pub metrics: EvaluationMetrics,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EvaluationRound {
+    pub task: String,
+    pub round: u64,
+    pub state: EvaluationState,
+    // Version under evaluation.
+    pub version: u64,
+    pub classes: u64,
+    pub opened_at: u64,
+    pub deadline: u64,
+    pub closed_at: Option<u64>,
+    pub invited: Vec<Principal>,
+    pub reports: Vec<EvaluationReport>,
+    pub global: Option<EvaluationMetrics>,
+    pub regions: Vec<RegionMetrics>,
+}
+
+impl_storable_json!(EvaluationRound);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EvaluationInvitation {
+    pub task: String,
+    pub round: u64,
+    pub invited: bool,
+    pub version: u64,
+    pub classes: u64,
+    pub deadline: u64,
+}
+
+// The only part of the model config evaluation needs.
+#[derive(Deserialize)]
+struct OutputConfig {
+    num_classes: usize,
+}
+
+thread_local! {
+    static EVALUATIONS: RefCell<StableBTreeMap<String, EvaluationRound, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::EVALUATIONS_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_evaluations() -> Vec<(String, EvaluationRound)> {
+    EVALUATIONS.with(storage::entries)
+}
+
+/// Replaces every evaluation round, used when a snapshot is restored.
+pub(crate) fn replace_evaluations(evaluations: Vec<(String, EvaluationRound)>) {
+    EVALUATIONS.with(|m| storage::replace_entries(m, evaluations))
+}
+
+fn evaluation_key(task: &str, round: u64) -> String {
+    format!("{}/{:020}", task, round)
+}
+
+/// Evaluation rounds of `task`, oldest first.
+pub fn evaluations(task: &str) -> Vec<EvaluationRound> {
+    let prefix = format!("{}/", task);
+    EVALUATIONS.with(|e| {
+        e.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, round)| round)
+            .collect()
+    })
+}
+
+fn save(round: &EvaluationRound) {
+    EVALUATIONS.with(|e| e.borrow_mut().insert(evaluation_key(&round.task, round.round), round.clone()));
+}
+
+pub(crate) fn open_evaluation(task: &str) -> Option<EvaluationRound> {
+    evaluations(task).pop().filter(|round| round.state == EvaluationState::Open)
+}
+
+/// Classes the model behind `config` tells apart. A single output unit is a sigmoid over two.
+fn classes(config: &[u8]) -> Result<u64, String> {
+    let output: OutputConfig =
+        serde_json::from_slice(config).map_err(|e| format!("Failed to deserialize model config: {:?}", e))?;
+    Ok(output.num_classes.max(2) as u64)
+}
+
+/// Sums `reports` into one matrix of `classes` and derives the metrics from it.
+pub fn metrics(reports: &[&EvaluationReport], classes: usize) -> EvaluationMetrics {
+    let mut confusion = vec![vec![0u64; classes]; classes];
+    for report in reports {
+        for (row, counts) in confusion.iter_mut().zip(&report.confusion) {
+            for (cell, count) in row.iter_mut().zip(counts) {
+                *cell += count;
+            }
+        }
+    }
+    let num_samples: u64 = confusion.iter().flatten().sum();
+    let correct: u64 = (0..classes).map(|class| confusion[class][class]).sum();
+    let ratio = |hits: u64, total: u64| (total > 0).then(|| hits as f64 / total as f64);
+    EvaluationMetrics {
+        clinics: reports.len() as u64,
+        num_samples,
+        accuracy: ratio(correct, num_samples).unwrap_or(0.0),
+        recall: (0..classes).map(|class| ratio(confusion[class][class], confusion[class].iter().sum())).collect(),
+        precision: (0..classes)
+            .map(|class| ratio(confusion[class][class], confusion.iter().map(|row| row[class]).sum()))
+            .collect(),
+        confusion,
+    }
+}
+
+/// Metrics over all reports of `round`, and over the reports of every region.
+fn summarise(round: &mut EvaluationRound) {
+    let classes = round.classes as usize;
+    round.global = Some(metrics(&round.reports.iter().collect::<Vec<_>>(), classes));
+    let mut regions: BTreeMap<&str, Vec<&EvaluationReport>> = BTreeMap::new();
+    for report in &round.reports {
+        regions.entry(&report.region).or_default().push(report);
+    }
+    round.regions = regions
+        .into_iter()
+        .map(|(region, reports)| RegionMetrics { region: region.to_string(), metrics: metrics(&reports, classes) })
+        .collect();
+}
+
+/// Opens an evaluation round of `version` of `task`, the production version by default.
+pub fn start(
+    task: &str,
+    version: Option<u64>,
+    selection: &SelectionStrategy,
+    duration_seconds: u64,
+    now: u64,
+) -> Result<EvaluationRound, String> {
+    if let Some(round) = open_evaluation(task) {
+        return Err(format!("Evaluation round {} of {} is still open.", round.round, task));
+    }
+    if duration_seconds == 0 {
+        return Err("An evaluation round needs a positive duration.".to_string());
+    }
+    selection.validate()?;
+    let version = match version {
+        Some(version) => version,
+        None => agent::global_model(task)?.version,
+    };
+    agent::version_weights(task, version)?;
+    let classes = classes(&agent::version_config(task, version)?)?;
+    let number = evaluations(task).last().map_or(1, |round| round.round + 1);
+    let invited = clinics::select(selection, task, number, now);
+    if invited.is_empty() {
+        return Err("No active clinic was selected for the evaluation round.".to_string());
+    }
+    let round = EvaluationRound {
+        task: task.to_string(),
+        round: number,
+        state: EvaluationState::Open,
+        version,
+        classes,
+        opened_at: now,
+        deadline: now.saturating_add(duration_seconds.saturating_mul(storage::NANOS_PER_SECOND)),
+        closed_at: None,
+        invited,
+        reports: Vec::new(),
+        global: None,
+        regions: Vec::new(),
+    };
+    save(&round);
+    deadlines::schedule(DEADLINE, &round.task, round.deadline, now, close);
+    Ok(round)
+}
+
+/// Records the confusion matrix `principal` reported for the open evaluation round of `task`,
+/// closing the round once every invited clinic reported.
+pub fn report(task: &str, round: u64, principal: Principal, confusion: Vec<Vec<u64>>, now: u64) -> Result<EvaluationRound, String> {
+    let mut open = open_evaluation(task).ok_or_else(|| format!("No evaluation round of {} is open.", task))?;
+    if open.round != round {
+        return Err(format!("Evaluation round {} of {} is not open.", round, task));
+    }
+    if !open.invited.contains(&principal) {
+        return Err(format!("{} is not invited to evaluation round {}.", principal, round));
+    }
+    if open.reports.iter().any(|report| report.principal == principal) {
+        return Err(format!("{} already reported for evaluation round {}.", principal, round));
+    }
+    let classes = open.classes as usize;
+    if confusion.len() != classes || confusion.iter().any(|row| row.len() != classes) {
+        return Err(format!("The confusion matrix has to be {} by {}.", classes, classes));
+    }
+    let num_samples: u64 = confusion.iter().flatten().sum();
+    if num_samples == 0 {
+        return Err("The confusion matrix counts no samples.".to_string());
+    }
+    let region = clinics::node(&principal).map_or_else(|| "unknown".to_string(), |node| node.profile.region);
+    open.reports.push(EvaluationReport { principal, region, confusion, num_samples, submitted_at: now });
+    save(&open);
+    if open.reports.len() == open.invited.len() {
+        return close(task, now);
+    }
+    Ok(open)
+}
+
+/// Closes the open evaluation round of `task` and aggregates its reports.
+pub fn close(task: &str, now: u64) -> Result<EvaluationRound, String> {
+    let mut round = open_evaluation(task).ok_or_else(|| format!("No evaluation round of {} is open.", task))?;
+    deadlines::cancel(DEADLINE, task);
+    if round.reports.is_empty() {
+        round.state = EvaluationState::Abandoned;
+    } else {
+        summarise(&mut round);
+        round.state = EvaluationState::Closed;
+    }
+    round.closed_at = Some(now);
+    save(&round);
+    Ok(round)
+}
+
+/// Re-arms the deadlines of open evaluation rounds after an upgrade.
+pub fn schedule_deadlines() {
+    let now = ic_cdk::api::time();
+    let open: Vec<EvaluationRound> = EVALUATIONS.with(|e| {
+        e.borrow()
+            .iter()
+            .map(|(_, round)| round)
+            .filter(|round| round.state == EvaluationState::Open)
+            .collect()
+    });
+    for round in open {
+        deadlines::schedule(DEADLINE, &round.task, round.deadline, now, close);
+    }
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn start_evaluation_round(
+    task: String,
+    version: Option<u64>,
+    selection: SelectionStrategy,
+    duration_seconds: u64,
+) -> Result<EvaluationRound, String> {
+    start(&task, version, &selection, duration_seconds, ic_cdk::api::time())
+}
+
+/// Tells the calling node whether it is invited to the open evaluation round of `task`.
+#[ic_cdk::query]
+pub fn get_evaluation_invitation(task: String) -> Result<EvaluationInvitation, String> {
+    let round = open_evaluation(&task).ok_or_else(|| format!("No evaluation round of {} is open.", task))?;
+    Ok(EvaluationInvitation {
+        invited: round.invited.contains(&ic_cdk::caller()),
+        task,
+        round: round.round,
+        version: round.version,
+        classes: round.classes,
+        deadline: round.deadline,
+    })
+}
+
+/// Serves the version under evaluation in the open round of `task` to the invited nodes.
+#[ic_cdk::query(guard = "is_participant")]
+pub fn get_evaluation_model(task: String, chunk: u64) -> Result<GlobalModelChunk, String> {
+    let round = open_evaluation(&task).ok_or_else(|| format!("No evaluation round of {} is open.", task))?;
+    if !round.invited.contains(&ic_cdk::caller()) {
+        return Err(format!("The caller is not invited to evaluation round {}.", round.round));
+    }
+    let weights = agent::version_weights(&task, round.version)?;
+    let data = weights
+        .chunks(storage::CHUNK_SIZE)
+        .nth(chunk as usize)
+        .map(|data| data.to_vec())
+        .ok_or_else(|| format!("Chunk {} of version {} of {} does not exist.", chunk, round.version, task))?;
+    Ok(GlobalModelChunk {
+        version: round.version,
+        total_size: weights.len() as u64,
+        chunk_count: weights.len().div_ceil(storage::CHUNK_SIZE) as u64,
+        data,
+        config: agent::version_config(&task, round.version)?,
+    })
+}
+
+/// Reports the confusion matrix of the caller for evaluation round `round` of `task`.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn submit_evaluation(task: String, round: u64, confusion: Vec<Vec<u64>>) -> Result<EvaluationRound, String> {
+    report(&task, round, ic_cdk::caller(), confusion, ic_cdk::api::time())
+}
+
+/// Closes the open evaluation round of `task` before every invited clinic reported.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn finalize_evaluation_round(task: String) -> Result<EvaluationRound, String> {
+    close(&task, ic_cdk::api::time())
+}
+
+/// Evaluation rounds of `task` with the reports of every clinic, oldest first.
+#[ic_cdk::query(guard = "is_controller")]
+pub fn list_evaluation_rounds(task: String) -> Vec<EvaluationRound> {
+    evaluations(&task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clinic_report(region: &str, confusion: Vec<Vec<u64>>) -> EvaluationReport {
+        EvaluationReport {
+            principal: Principal::anonymous(),
+            region: region.to_string(),
+            num_samples: confusion.iter().flatten().sum(),
+            confusion,
+            submitted_at: 0,
+        }
+    }
+
+    #[test]
+    fn reports_are_summed_globally_and_per_region() {
+        let mut round = EvaluationRound {
+            task: "malaria".to_string(),
+            round: 1,
+            state: EvaluationState::Open,
+            version: 3,
+            classes: 2,
+            opened_at: 0,
+            deadline: 0,
+            closed_at: None,
+            invited: Vec::new(),
+            reports: vec![
+                clinic_report("north", vec![vec![8, 2], vec![0, 10]]),
+                clinic_report("south", vec![vec![5, 0], vec![5, 0]]),
+                clinic_report("north", vec![vec![0, 0], vec![0, 10]]),
+            ],
+            global: None,
+            regions: Vec::new(),
+        };
+        summarise(&mut round);
+        let global = round.global.unwrap();
+        assert_eq!(global.confusion, vec![vec![13, 2], vec![5, 20]]);
+        assert_eq!(global.num_samples, 40);
+        assert!((global.accuracy - 33.0 / 40.0).abs() < 1e-9);
+        assert_eq!(round.regions.len(), 2);
+        let north = &round.regions[0].metrics;
+        assert_eq!((round.regions[0].region.as_str(), north.clinics), ("north", 2));
+        assert!((north.accuracy - 28.0 / 30.0).abs() < 1e-9);
+        // The south clinic never predicted class 1.
+        let south = &round.regions[1].metrics;
+        assert_eq!(south.recall, vec![Some(1.0), Some(0.0)]);
+        assert_eq!(south.precision, vec![Some(0.5), None]);
+    }
+}
@@ -0,0 +1,100 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregation::{flatten, rebuild};
+use crate::checkpoint::Checkpoint;
+
+//Asynchronous buffered aggregation (Nguyen et al., "Federated Learning with Buffered
+//Asynchronous Aggregation"). Clients train whenever they are online, against whatever global
+//version they last fetched. Their changes to that version are buffered and every K arrivals
+//the global model moves by the staleness-weighted sum of the buffered changes over K.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum StalenessFunction {
+    // Every update counts fully.
+    Constant,
+    // (1 + staleness)^-exponent, FedBuff uses an exponent of 0.5.
+    Polynomial { exponent: f64 },
+    // 1 up to `b` versions behind, 1 / (a * (staleness - b) + 1) beyond.
+    Hinge { a: f64, b: u64 },
+}
+
+impl Default for StalenessFunction {
+    fn default() -> Self {
+        StalenessFunction::Polynomial { exponent: 0.5 }
+    }
+}
+
+impl StalenessFunction {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            StalenessFunction::Polynomial { exponent } if *exponent < 0.0 => {
+                Err("The staleness exponent must not be negative.".to_string())
+            }
+            StalenessFunction::Hinge { a, .. } if *a < 0.0 => Err("The hinge slope must not be negative.".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Weight of an update trained `staleness` versions behind the global model.
+    pub fn weight(&self, staleness: u64) -> f64 {
+        match self {
+            StalenessFunction::Constant => 1.0,
+            StalenessFunction::Polynomial { exponent } => (1.0 + staleness as f64).powf(-exponent),
+            StalenessFunction::Hinge { a, b } if staleness > *b => 1.0 / (a * (staleness - b) as f64 + 1.0),
+            StalenessFunction::Hinge { .. } => 1.0,
+        }
+    }
+}
+
+/// A buffered client checkpoint, the version it was trained from and its staleness weight.
+pub struct BufferedUpdate {
+    pub checkpoint: Checkpoint,
+    pub base: Checkpoint,
+    pub weight: f64,
+}
+
+/// Moves `global` by `server_lr` times the weighted sum of the buffered changes over their count.
+pub fn apply(global: &Checkpoint, updates: &[BufferedUpdate], server_lr: f64) -> Result<Checkpoint, String> {
+    if updates.is_empty() {
+        return Err("The buffer is empty.".to_string());
+    }
+    let mut next = flatten(global)?;
+    let scale = server_lr / updates.len() as f64;
+    for update in updates {
+        let trained = flatten(&update.checkpoint)?;
+        let base = flatten(&update.base)?;
+        let factor = (scale * update.weight) as f32;
+        for (name, values) in next.iter_mut() {
+            let (trained, base) = match (trained.get(name), base.get(name)) {
+                (Some(trained), Some(base)) if trained.len() == values.len() && base.len() == values.len() => (trained, base),
+                _ => return Err(format!("Tensor {} of a buffered update does not match the global model.", name)),
+            };
+            for ((value, t), b) in values.iter_mut().zip(trained).zip(base) {
+                *value += factor * (t - b);
+            }
+        }
+    }
+    rebuild(global, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint as checkpoint;
+
+    #[test]
+    fn stale_changes_are_applied_against_their_own_base() {
+        let staleness = StalenessFunction::default();
+        let updates = [
+            // Trained from the current version.
+            BufferedUpdate { checkpoint: checkpoint(&[2.0, 2.0]), base: checkpoint(&[1.0, 1.0]), weight: staleness.weight(0) },
+            // Trained from a version three behind.
+            BufferedUpdate { checkpoint: checkpoint(&[0.0, 4.0]), base: checkpoint(&[0.0, 0.0]), weight: staleness.weight(3) },
+        ];
+        let next = apply(&checkpoint(&[1.0, 1.0]), &updates, 1.0).unwrap();
+        let values = flatten(&next).unwrap().remove("w").unwrap();
+        assert!((values[0] - 1.5).abs() < 1e-6);
+        assert!((values[1] - 2.5).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,637 @@
+use candid::{CandidType, Principal};
+use candle_core::{DType, Device, Module, Tensor, Var};
+use candle_nn::ops::{leaky_relu, sigmoid, softmax};
+use candle_nn::{loss, AdamW, Linear, Optimizer, ParamsAdamW, VarMap, SGD};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::access::is_uploader;
+use crate::agent;
+use crate::checkpoint::Checkpoint;
+use crate::client::{proximal_term, TrainingConfig};
+use crate::personalization::is_head_tensor;
+use crate::quota;
+use crate::lineage::VersionOrigin;
+use crate::registry;
+use crate::rng::Rng;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//On-canister fine-tuning of the classifier head. Labelled cell images are uploaded one by one
+//or in batches, then a job trains `classifier.dense_1` and `classifier.output` on top of the
+//frozen backbone of the production model. The backbone runs once per image to embed it, after
+//which epochs only go through the head. A job advances in timer calls that stop before the
+//instruction limit, and publishes the fine-tuned model as a committed candidate version that
+//a controller may promote.
+
+const DEVICE: Device = Device::Cpu;
+// Side length the predict endpoints resize images to.
+const IMAGE_SIZE: u32 = 224;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LabelledImage {
+    pub image: Vec<u8>,
+    pub label: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SampleInfo {
+    pub label: u32,
+    pub size: u64,
+    pub added_by: Principal,
+    pub added_at: u64,
+}
+
+impl_storable_json!(SampleInfo);
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum HeadOptimizer {
+    #[default]
+    Sgd,
+    AdamW { weight_decay: f64 },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FineTuneConfig {
+    // Epochs, batch size, seed and learning rate. A positive proximal mu keeps the head close
+    // to the production head.
+    pub training: TrainingConfig,
+    pub optimizer: HeadOptimizer,
+}
+
+impl FineTuneConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        self.training.validate()?;
+        match self.optimizer {
+            HeadOptimizer::AdamW { weight_decay } if !(weight_decay >= 0.0 && weight_decay.is_finite()) => {
+                Err("The weight decay must be a non-negative number.".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum JobState {
+    // Running the backbone over the uploaded images.
+    Embedding,
+    Training,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FineTuneJob {
+    pub task: String,
+    // Production version whose backbone is frozen and whose head is the starting point.
+    pub base_version: u64,
+    pub config: FineTuneConfig,
+    pub samples: u64,
+    pub embedded: u64,
+    pub epochs_done: u64,
+    // Mean training loss of every finished epoch.
+    pub losses: Vec<f64>,
+    pub state: JobState,
+    pub started_at: u64,
+    pub updated_at: u64,
+    // Candidate version published when the job completed.
+    pub result_version: Option<u64>,
+}
+
+impl_storable_json!(FineTuneJob);
+
+// The fields of the model configs the fine-tuning needs, shared by the three image models.
+#[derive(Deserialize, Clone, Debug)]
+struct Architecture {
+    activation: String,
+    hidden_units: Vec<usize>,
+    num_classes: usize,
+    classifier_head: HeadArchitecture,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct HeadArchitecture {
+    dense_1: LayerArchitecture,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct LayerArchitecture {
+    activation: String,
+}
+
+impl Architecture {
+    fn parse(config: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(config).map_err(|e| format!("Failed to deserialize model config: {:?}", e))
+    }
+
+    // A single output unit is a sigmoid over two classes.
+    fn classes(&self) -> u32 {
+        self.num_classes.max(2) as u32
+    }
+
+    fn binary(&self) -> bool {
+        self.num_classes == 1
+    }
+}
+
+enum HeadOptimizerState {
+    Sgd(SGD),
+    AdamW(AdamW),
+}
+
+/// Trains the head of a model on embeddings produced by its backbone.
+struct HeadTrainer {
+    architecture: Architecture,
+    varmap: VarMap,
+    // Head of the base version, the anchor of the proximal term.
+    base_head: Checkpoint,
+    proximal_mu: f64,
+    optimizer: HeadOptimizerState,
+}
+
+impl HeadTrainer {
+    fn new(architecture: Architecture, base_head: Checkpoint, start: &Checkpoint, config: &FineTuneConfig) -> Result<Self, String> {
+        let varmap = VarMap::new();
+        {
+            let mut data = varmap.data().lock().unwrap();
+            for (name, tensor) in &start.tensors {
+                let tensor = tensor.to_dtype(DType::F32).map_err(|e| format!("{:?}", e))?;
+                data.insert(name.clone(), Var::from_tensor(&tensor).map_err(|e| format!("{:?}", e))?);
+            }
+        }
+        let vars = varmap.all_vars();
+        let learning_rate = config.training.learning_rate;
+        let optimizer = match config.optimizer {
+            HeadOptimizer::Sgd => SGD::new(vars, learning_rate).map(HeadOptimizerState::Sgd),
+            HeadOptimizer::AdamW { weight_decay } => {
+                AdamW::new(vars, ParamsAdamW { lr: learning_rate, weight_decay, ..Default::default() })
+                    .map(HeadOptimizerState::AdamW)
+            }
+        }
+        .map_err(|e| format!("Failed to create the optimizer: {:?}", e))?;
+        Ok(HeadTrainer { architecture, varmap, base_head, proximal_mu: config.training.proximal_mu, optimizer })
+    }
+
+    fn layer(&self, prefix: &str) -> Result<Linear, String> {
+        let data = self.varmap.data().lock().unwrap();
+        let get = |name: &str| {
+            data.get(&format!("{}.{}", prefix, name))
+                .map(|var| var.as_tensor().clone())
+                .ok_or_else(|| format!("The model has no {}.{} tensor.", prefix, name))
+        };
+        Ok(Linear::new(get("weight")?, Some(get("bias")?)))
+    }
+
+    fn logits(&self, embeddings: &Tensor) -> Result<Tensor, String> {
+        let dense_1 = self.layer("classifier.dense_1")?;
+        let output = self.layer("classifier.output")?;
+        dense_1
+            .forward(embeddings)
+            .and_then(|x| activate(&self.architecture.classifier_head.dense_1.activation, &x))
+            .and_then(|x| output.forward(&x))
+            .map_err(|e| format!("Head forward pass failed: {:?}", e))
+    }
+
+    /// One optimiser step on a batch, returning its loss before the step.
+    fn train_batch(&mut self, embeddings: &Tensor, labels: &[u32]) -> Result<f64, String> {
+        let logits = self.logits(embeddings)?;
+        let targets = Tensor::new(labels, &DEVICE).map_err(|e| format!("{:?}", e))?;
+        let objective = if self.architecture.binary() {
+            targets
+                .to_dtype(DType::F32)
+                .and_then(|t| t.unsqueeze(1))
+                .and_then(|t| loss::binary_cross_entropy_with_logit(&logits, &t))
+        } else {
+            loss::cross_entropy(&logits, &targets)
+        }
+        .map_err(|e| format!("Loss computation failed: {:?}", e))?;
+        let value = objective.to_scalar::<f32>().map_err(|e| format!("{:?}", e))? as f64;
+        let total = proximal_term(&self.varmap, &self.base_head, self.proximal_mu)
+            .and_then(|term| objective.add(&term))
+            .map_err(|e| format!("Proximal term failed: {:?}", e))?;
+        match &mut self.optimizer {
+            HeadOptimizerState::Sgd(optimizer) => optimizer.backward_step(&total),
+            HeadOptimizerState::AdamW(optimizer) => optimizer.backward_step(&total),
+        }
+        .map_err(|e| format!("Optimizer step failed: {:?}", e))?;
+        Ok(value)
+    }
+
+    fn head(&self) -> Checkpoint {
+        let data = self.varmap.data().lock().unwrap();
+        Checkpoint { tensors: data.iter().map(|(name, var)| (name.clone(), var.as_tensor().clone())).collect() }
+    }
+}
+
+// Mirrors the activations of the model constructors, which fall back to ReLU.
+fn activate(name: &str, xs: &Tensor) -> candle_core::Result<Tensor> {
+    match name {
+        "leaky_relu" => leaky_relu(xs, 0.01),
+        "sigmoid" => sigmoid(xs),
+        "softmax" => softmax(xs, 1),
+        _ => xs.relu(),
+    }
+}
+
+/// Output of the frozen hidden layers of `weights` for a batch of inputs.
+fn embed(architecture: &Architecture, weights: &Checkpoint, inputs: &Tensor) -> Result<Tensor, String> {
+    let mut xs = inputs.clone();
+    for i in 0..architecture.hidden_units.len() {
+        let get = |name: &str| {
+            let key = format!("hidden_{}.{}", i, name);
+            weights
+                .tensors
+                .get(&key)
+                .ok_or_else(|| format!("The model has no {} tensor.", key))
+                .and_then(|t| t.to_dtype(DType::F32).map_err(|e| format!("{:?}", e)))
+        };
+        xs = Linear::new(get("weight")?, Some(get("bias")?))
+            .forward(&xs)
+            .and_then(|x| activate(&architecture.activation, &x))
+            .map_err(|e| format!("Backbone forward pass failed: {:?}", e))?;
+    }
+    Ok(xs)
+}
+
+/// Decodes an image into the input row the predict endpoints build.
+fn pixels(image: &[u8]) -> Result<Tensor, String> {
+    let img = image::load_from_memory(image)
+        .map_err(|e| format!("Image decode error: {}", e))?
+        .resize_exact(IMAGE_SIZE, IMAGE_SIZE, image::imageops::FilterType::Triangle)
+        .to_rgb8();
+    let data: Vec<f32> = img.pixels().flat_map(|p| p.0).map(|v| v as f32 / 255.0).collect();
+    let len = data.len();
+    Tensor::from_vec(data, &[1, len], &DEVICE).map_err(|e| format!("Tensor creation error: {:?}", e))
+}
+
+/// State of a running job that only lives on the heap. It is rebuilt from the job record and
+/// the head of its last finished epoch after an upgrade.
+struct Trainer {
+    architecture: Architecture,
+    config: Vec<u8>,
+    backbone: Checkpoint,
+    head: HeadTrainer,
+    embeddings: Vec<Tensor>,
+    labels: Vec<u32>,
+    // Sample order of the current epoch, how far it got and the loss summed so far.
+    order: Vec<usize>,
+    cursor: usize,
+    loss_sum: f64,
+}
+
+thread_local! {
+    static SAMPLES: RefCell<StableBTreeMap<String, SampleInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::TRAINING_SAMPLES_MEMORY_ID))
+    );
+
+    static JOBS: RefCell<StableBTreeMap<String, FineTuneJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::FINE_TUNE_JOBS_MEMORY_ID))
+    );
+
+    static TRAINERS: RefCell<BTreeMap<String, Trainer>> = RefCell::default();
+}
+
+pub(crate) fn export_samples() -> Vec<(String, SampleInfo)> {
+    SAMPLES.with(storage::entries)
+}
+
+/// Replaces the index of labelled images, used when a snapshot is restored.
+pub(crate) fn replace_samples(samples: Vec<(String, SampleInfo)>) {
+    SAMPLES.with(|m| storage::replace_entries(m, samples))
+}
+
+pub(crate) fn export_jobs() -> Vec<(String, FineTuneJob)> {
+    JOBS.with(storage::entries)
+}
+
+/// Replaces every fine-tuning job, used when a snapshot is restored.
+pub(crate) fn replace_jobs(jobs: Vec<(String, FineTuneJob)>) {
+    JOBS.with(|m| storage::replace_entries(m, jobs))
+}
+
+fn sample_key(task: &str, id: u64) -> String {
+    format!("{}/{:020}", task, id)
+}
+
+fn image_key(task: &str, id: u64) -> String {
+    format!("{}{}/images/{:020}", storage::TRAINING_PREFIX, task, id)
+}
+
+// Head after the last finished epoch of the running job.
+fn head_key(task: &str) -> String {
+    format!("{}{}/head", storage::TRAINING_PREFIX, task)
+}
+
+/// Ids and labels of the uploaded samples of `task`, in upload order.
+fn samples(task: &str) -> Vec<(u64, SampleInfo)> {
+    let prefix = format!("{}/", task);
+    SAMPLES.with(|s| {
+        s.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, info)| key[prefix.len()..].parse().ok().map(|id| (id, info)))
+            .collect()
+    })
+}
+
+pub fn job(task: &str) -> Option<FineTuneJob> {
+    JOBS.with(|j| j.borrow().get(&task.to_string()))
+}
+
+fn save_job(job: &FineTuneJob) {
+    JOBS.with(|j| j.borrow_mut().insert(job.task.clone(), job.clone()));
+}
+
+pub(crate) fn running(job: &FineTuneJob) -> bool {
+    matches!(job.state, JobState::Embedding | JobState::Training)
+}
+
+fn head_of(checkpoint: &Checkpoint) -> Checkpoint {
+    Checkpoint {
+        tensors: checkpoint
+            .tensors
+            .iter()
+            .filter(|(name, _)| is_head_tensor(name))
+            .map(|(name, tensor)| (name.clone(), tensor.clone()))
+            .collect(),
+    }
+}
+
+/// Stores labelled images for fine-tuning `task`, returning the number of samples it has.
+fn add_images(task: &str, images: Vec<LabelledImage>, caller: Principal, now: u64) -> Result<u64, String> {
+    if job(task).is_some_and(|job| running(&job)) {
+        return Err(format!("A fine-tuning job of {} is running.", task));
+    }
+    let architecture = Architecture::parse(&agent::global_model(task)?.config)?;
+    quota::check(&caller, images.iter().map(|i| i.image.len() as u64).sum())?;
+    for image in &images {
+        if image.label >= architecture.classes() {
+            return Err(format!("Label {} is out of range for {} classes.", image.label, architecture.classes()));
+        }
+        image::load_from_memory(&image.image).map_err(|e| format!("Image decode error: {}", e))?;
+    }
+    let first = samples(task).last().map_or(0, |(id, _)| id + 1);
+    for (id, image) in (first..).zip(images) {
+        let info = SampleInfo { label: image.label, size: image.image.len() as u64, added_by: caller, added_at: now };
+        storage::insert_raw(image_key(task, id), image.image);
+        SAMPLES.with(|s| s.borrow_mut().insert(sample_key(task, id), info));
+    }
+    Ok(samples(task).len() as u64)
+}
+
+/// Opens a job fine-tuning the head of the production model of `task` on its samples.
+fn start(task: &str, config: FineTuneConfig, now: u64) -> Result<FineTuneJob, String> {
+    config.validate()?;
+    if job(task).is_some_and(|job| running(&job)) {
+        return Err(format!("A fine-tuning job of {} is already running.", task));
+    }
+    let global = agent::global_model(task)?;
+    Architecture::parse(&global.config)?;
+    let count = samples(task).len() as u64;
+    if count == 0 {
+        return Err(format!("No labelled images were uploaded for {}.", task));
+    }
+    storage::remove_raw(&head_key(task));
+    let job = FineTuneJob {
+        task: task.to_string(),
+        base_version: global.version,
+        config,
+        samples: count,
+        embedded: 0,
+        epochs_done: 0,
+        losses: Vec::new(),
+        state: JobState::Embedding,
+        started_at: now,
+        updated_at: now,
+        result_version: None,
+    };
+    save_job(&job);
+    schedule(task.to_string());
+    Ok(job)
+}
+
+/// Builds the heap state of `job`, starting from the head of its last finished epoch.
+/// Embedding starts over, since the embeddings were on the heap.
+fn build_trainer(job: &mut FineTuneJob) -> Result<Trainer, String> {
+    let config = agent::version_config(&job.task, job.base_version)?;
+    let architecture = Architecture::parse(&config)?;
+    let backbone = Checkpoint::from_safetensors(&agent::version_weights(&job.task, job.base_version)?)?;
+    let base_head = head_of(&backbone);
+    let saved = storage::bytes(head_key(&job.task));
+    let start = match saved.is_empty() {
+        true => base_head.clone(),
+        false => Checkpoint::from_safetensors(&saved)?,
+    };
+    let head = HeadTrainer::new(architecture.clone(), base_head, &start, &job.config)?;
+    job.embedded = 0;
+    job.state = JobState::Embedding;
+    Ok(Trainer {
+        architecture,
+        config,
+        backbone,
+        head,
+        embeddings: Vec::new(),
+        labels: Vec::new(),
+        order: Vec::new(),
+        cursor: 0,
+        loss_sum: 0.0,
+    })
+}
+
+/// Advances `job` by one image or one batch. Returns whether the job is done.
+fn step(job: &mut FineTuneJob, trainer: &mut Trainer, ids: &[(u64, SampleInfo)], now: u64) -> Result<bool, String> {
+    if job.state == JobState::Embedding {
+        let (id, info) = ids
+            .get(job.embedded as usize)
+            .ok_or_else(|| "Labelled images were removed while the job was running.".to_string())?;
+        let inputs = pixels(&storage::bytes(image_key(&job.task, *id)))?;
+        trainer.embeddings.push(embed(&trainer.architecture, &trainer.backbone, &inputs)?);
+        trainer.labels.push(info.label);
+        job.embedded += 1;
+        if job.embedded == job.samples {
+            job.state = JobState::Training;
+        }
+        return Ok(false);
+    }
+    let count = trainer.labels.len();
+    if trainer.order.is_empty() {
+        let mut rng = Rng::from_seed(format!("{}/{}/{}", job.task, job.config.training.seed, job.epochs_done).as_bytes());
+        trainer.order = (0..count).collect();
+        rng.shuffle(&mut trainer.order);
+    }
+    let end = (trainer.cursor + job.config.training.batch_size as usize).min(count);
+    let batch = &trainer.order[trainer.cursor..end];
+    let rows: Vec<Tensor> = batch.iter().map(|i| trainer.embeddings[*i].clone()).collect();
+    let embeddings = Tensor::cat(&rows, 0).map_err(|e| format!("{:?}", e))?;
+    let labels: Vec<u32> = batch.iter().map(|i| trainer.labels[*i]).collect();
+    trainer.loss_sum += trainer.head.train_batch(&embeddings, &labels)? * batch.len() as f64;
+    trainer.cursor = end;
+    if trainer.cursor < count {
+        return Ok(false);
+    }
+    job.losses.push(trainer.loss_sum / count as f64);
+    job.epochs_done += 1;
+    trainer.order.clear();
+    trainer.cursor = 0;
+    trainer.loss_sum = 0.0;
+    let head = trainer.head.head();
+    if job.epochs_done < job.config.training.num_epochs {
+        storage::insert_raw(head_key(&job.task), head.to_safetensors()?);
+        return Ok(false);
+    }
+    let mut model = trainer.backbone.clone();
+    model.tensors.extend(head.tensors);
+    let weights = model.to_safetensors()?;
+    let origin = VersionOrigin::FineTuning { started_at: job.started_at, config: job.config.clone(), samples: job.samples };
+    let version =
+        registry::publish(&job.task, weights, trainer.config.clone(), storage::canister_id(), Some(job.base_version), origin, now)?;
+    job.result_version = Some(version);
+    job.state = JobState::Completed;
+    Ok(true)
+}
+
+/// Runs `job` until it is done or the call used its instruction budget. Returns the heap
+/// state to keep for the next call when it is not done.
+fn run(job: &mut FineTuneJob, now: u64) -> Result<Option<Trainer>, String> {
+    let ids = samples(&job.task);
+    let mut trainer = match TRAINERS.with(|t| t.borrow_mut().remove(&job.task)) {
+        Some(trainer) => trainer,
+        None => build_trainer(job)?,
+    };
+    loop {
+        if step(job, &mut trainer, &ids, now)? {
+            return Ok(None);
+        }
+        if storage::instructions() > storage::INSTRUCTION_BUDGET {
+            return Ok(Some(trainer));
+        }
+    }
+}
+
+fn advance(task: &str, now: u64) {
+    let Some(mut job) = job(task).filter(running) else {
+        return;
+    };
+    let result = run(&mut job, now);
+    job.updated_at = now;
+    match result {
+        Ok(Some(trainer)) => {
+            TRAINERS.with(|t| t.borrow_mut().insert(task.to_string(), trainer));
+            schedule(task.to_string());
+        }
+        Ok(None) => storage::remove_raw(&head_key(task)),
+        Err(e) => {
+            job.state = JobState::Failed(e);
+            storage::remove_raw(&head_key(task));
+        }
+    }
+    save_job(&job);
+}
+
+fn schedule(task: String) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || advance(&task, ic_cdk::api::time()));
+}
+
+/// Continues the running jobs after an upgrade, which dropped their timers and heap state, or
+/// after a snapshot restore, which replaced the jobs the heap state was built for.
+pub fn resume_jobs() {
+    TRAINERS.with(|t| t.borrow_mut().clear());
+    let tasks: Vec<String> = JOBS.with(|j| j.borrow().iter().filter(|(_, job)| running(job)).map(|(task, _)| task).collect());
+    for task in tasks {
+        schedule(task);
+    }
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn add_labelled_image(task: String, image: Vec<u8>, label: u32) -> Result<u64, String> {
+    add_images(&task, vec![LabelledImage { image, label }], ic_cdk::caller(), ic_cdk::api::time())
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn add_labelled_images(task: String, images: Vec<LabelledImage>) -> Result<u64, String> {
+    add_images(&task, images, ic_cdk::caller(), ic_cdk::api::time())
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn clear_labelled_images(task: String) -> Result<(), String> {
+    if job(&task).is_some_and(|job| running(&job)) {
+        return Err(format!("A fine-tuning job of {} is running.", task));
+    }
+    for (id, _) in samples(&task) {
+        storage::remove_raw(&image_key(&task, id));
+        SAMPLES.with(|s| s.borrow_mut().remove(&sample_key(&task, id)));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+pub fn get_labelled_image_count(task: String) -> u64 {
+    samples(&task).len() as u64
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn start_fine_tuning(task: String, config: FineTuneConfig) -> Result<FineTuneJob, String> {
+    start(&task, config, ic_cdk::api::time())
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn cancel_fine_tuning(task: String) -> Result<FineTuneJob, String> {
+    let mut job = job(&task)
+        .filter(running)
+        .ok_or_else(|| format!("No fine-tuning job of {} is running.", task))?;
+    TRAINERS.with(|t| t.borrow_mut().remove(&task));
+    storage::remove_raw(&head_key(&task));
+    job.state = JobState::Cancelled;
+    job.updated_at = ic_cdk::api::time();
+    save_job(&job);
+    Ok(job)
+}
+
+/// The running or last fine-tuning job of `task`, with its loss curve.
+#[ic_cdk::query]
+pub fn get_fine_tuning_job(task: String) -> Option<FineTuneJob> {
+    job(&task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensor(values: &[f32], shape: (usize, usize)) -> Tensor {
+        Tensor::from_slice(values, shape, &DEVICE).unwrap()
+    }
+
+    #[test]
+    fn head_training_lowers_the_loss() {
+        let architecture = Architecture::parse(
+            br#"{"activation": "relu", "hidden_units": [4], "num_classes": 1,
+                 "classifier_head": {"dense_1": {"units": 3, "activation": "relu"}, "output": {"units": 1, "activation": "sigmoid"}}}"#,
+        )
+        .unwrap();
+        let head = Checkpoint {
+            tensors: BTreeMap::from([
+                ("classifier.dense_1.weight".to_string(), tensor(&[0.5; 12], (3, 4))),
+                ("classifier.dense_1.bias".to_string(), Tensor::zeros(3, DType::F32, &DEVICE).unwrap()),
+                ("classifier.output.weight".to_string(), tensor(&[0.1, -0.1, 0.1], (1, 3))),
+                ("classifier.output.bias".to_string(), Tensor::zeros(1, DType::F32, &DEVICE).unwrap()),
+            ]),
+        };
+        let config = FineTuneConfig {
+            training: TrainingConfig { learning_rate: 0.5, proximal_mu: 0.01, ..TrainingConfig::default() },
+            optimizer: HeadOptimizer::Sgd,
+        };
+        let mut trainer = HeadTrainer::new(architecture, head.clone(), &head, &config).unwrap();
+        let embeddings = tensor(&[1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0], (4, 4));
+        let labels = [1, 1, 0, 0];
+        let first = trainer.train_batch(&embeddings, &labels).unwrap();
+        let mut last = first;
+        for _ in 0..50 {
+            last = trainer.train_batch(&embeddings, &labels).unwrap();
+        }
+        assert!(last < first / 2.0, "loss went from {} to {}", first, last);
+        assert_eq!(trainer.head().tensors.len(), 4);
+    }
+}
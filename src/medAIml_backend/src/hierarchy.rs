@@ -0,0 +1,172 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::access::is_controller;
+use crate::agent::{self, RoundStatus};
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Two-tier federation. A district aggregator, usually the district hospital, combines the
+//updates of its clinics off-canister and submits one update weighted by their combined sample
+//count. The canister keeps the clinics of every district, checks district submissions against
+//them and records which clinics contributed through which district.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct District {
+    pub aggregator: Principal,
+    pub name: String,
+    pub clinics: Vec<Principal>,
+    pub registered_at: u64,
+}
+
+impl_storable_json!(District);
+
+/// Samples a clinic contributed to a district update.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClinicContribution {
+    pub clinic: Principal,
+    pub num_samples: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ProvenanceEntry {
+    pub clinic: Principal,
+    // District the clinic contributed through, `None` for direct submissions.
+    pub district: Option<Principal>,
+    pub num_samples: u64,
+}
+
+/// Which clinics a published version was trained on.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Provenance {
+    pub task: String,
+    pub version: u64,
+    pub round: u64,
+    pub contributors: Vec<ProvenanceEntry>,
+}
+
+thread_local! {
+    static DISTRICTS: RefCell<StableBTreeMap<Principal, District, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::DISTRICTS_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_districts() -> Vec<(Principal, District)> {
+    DISTRICTS.with(storage::entries)
+}
+
+/// Replaces every district, used when a snapshot is restored.
+pub(crate) fn replace_districts(districts: Vec<(Principal, District)>) {
+    DISTRICTS.with(|m| storage::replace_entries(m, districts))
+}
+
+pub fn district(aggregator: &Principal) -> Option<District> {
+    DISTRICTS.with(|d| d.borrow().get(aggregator))
+}
+
+pub fn districts() -> Vec<District> {
+    DISTRICTS.with(|d| d.borrow().iter().map(|(_, district)| district).collect())
+}
+
+/// Checks the contributions of a district update: every clinic belongs to the district and
+/// appears once, and the sample counts add up to `num_samples`.
+pub fn check_contributions(district: &District, contributions: &[ClinicContribution], num_samples: u64) -> Result<(), String> {
+    if contributions.is_empty() {
+        return Err("A district update needs at least one contributing clinic.".to_string());
+    }
+    let mut seen = BTreeSet::new();
+    for contribution in contributions {
+        if !district.clinics.contains(&contribution.clinic) {
+            return Err(format!("{} is not a clinic of district {}.", contribution.clinic, district.name));
+        }
+        if !seen.insert(contribution.clinic) {
+            return Err(format!("{} is listed more than once.", contribution.clinic));
+        }
+    }
+    let total: u64 = contributions.iter().map(|c| c.num_samples).sum();
+    if total != num_samples {
+        return Err(format!("The clinics contributed {} samples, the update reports {}.", total, num_samples));
+    }
+    Ok(())
+}
+
+fn save(district: District) -> Result<District, String> {
+    if district.name.trim().is_empty() {
+        return Err("The district name must not be empty.".to_string());
+    }
+    // A clinic contributes through one district at most.
+    let claimed = districts().into_iter().find(|other| {
+        other.aggregator != district.aggregator && other.clinics.iter().any(|c| district.clinics.contains(c))
+    });
+    if let Some(other) = claimed {
+        return Err(format!("Some of the clinics already belong to district {}.", other.name));
+    }
+    if district.clinics.contains(&district.aggregator) {
+        return Err("A district aggregator cannot be one of its own clinics.".to_string());
+    }
+    DISTRICTS.with(|d| d.borrow_mut().insert(district.aggregator, district.clone()));
+    Ok(district)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn register_district(aggregator: Principal, name: String, clinics: Vec<Principal>) -> Result<District, String> {
+    let registered_at = district(&aggregator).map_or(ic_cdk::api::time(), |d| d.registered_at);
+    save(District { aggregator, name, clinics, registered_at })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn remove_district(aggregator: Principal) {
+    DISTRICTS.with(|d| d.borrow_mut().remove(&aggregator));
+}
+
+#[ic_cdk::query]
+pub fn list_districts() -> Vec<District> {
+    districts()
+}
+
+/// The clinics whose updates went into the aggregate of `round`.
+pub fn contributors(round: &RoundStatus) -> Vec<ProvenanceEntry> {
+    round
+        .participants
+        .iter()
+        // Quarantined and excluded updates did not make it into the version.
+        .filter(|p| !round.quarantined.contains(&p.principal) && !round.excluded.contains(&p.principal))
+        .flat_map(|p| {
+            if p.contributors.is_empty() {
+                return vec![ProvenanceEntry { clinic: p.principal, district: None, num_samples: p.num_samples }];
+            }
+            p.contributors
+                .iter()
+                .map(|c| ProvenanceEntry { clinic: c.clinic, district: Some(p.principal), num_samples: c.num_samples })
+                .collect()
+        })
+        .collect()
+}
+
+/// The clinics behind `version` of `task`, from the round that published it.
+#[ic_cdk::query]
+pub fn get_version_provenance(task: String, version: u64) -> Result<Provenance, String> {
+    let round = agent::rounds(&task)
+        .into_iter()
+        .find(|round| round.result_version == Some(version))
+        .ok_or_else(|| format!("Version {} of {} was not published by a round.", version, task))?;
+    Ok(Provenance { contributors: contributors(&round), task, version, round: round.round })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn district_updates_must_match_their_clinics() {
+        let clinic = |i: u8| Principal::from_slice(&[i]);
+        let district = District { aggregator: clinic(0), name: "North".to_string(), clinics: vec![clinic(1), clinic(2)], registered_at: 0 };
+        let contribution = |i: u8, num_samples: u64| ClinicContribution { clinic: clinic(i), num_samples };
+        assert!(check_contributions(&district, &[contribution(1, 10), contribution(2, 5)], 15).is_ok());
+        assert!(check_contributions(&district, &[contribution(1, 10), contribution(2, 5)], 20).is_err());
+        assert!(check_contributions(&district, &[contribution(1, 10), contribution(3, 5)], 15).is_err());
+        assert!(check_contributions(&district, &[contribution(1, 10), contribution(1, 5)], 15).is_err());
+    }
+}
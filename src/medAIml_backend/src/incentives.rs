@@ -0,0 +1,271 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::rng::Rng;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Contribution accounting. When a round publishes, every aggregated update is scored by how
+//much it helped: its share of the samples, the validation accuracy lost when it is left out,
+//or its Monte Carlo Shapley value over the validation accuracy of partial aggregates. Each
+//round then hands out one unit of credit in proportion to the positive scores, and district
+//credit is passed on to the clinics by their samples. The ledger of credit per task can be
+//used to reward clinics or to prefer them in client selection.
+//Measured scores need a validation pass per subset of updates, so they are computed by a
+//scoring job in timer calls after the round closed, and recorded when the job is done.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum ContributionMethod {
+    #[default]
+    SampleShare,
+    // Validation accuracy of the aggregate minus that of the aggregate without the update.
+    LeaveOneOut,
+    // Mean marginal accuracy gain over `permutations` random orders of the updates.
+    Shapley { permutations: u64 },
+}
+
+impl ContributionMethod {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ContributionMethod::Shapley { permutations } if !(1..=MAX_PERMUTATIONS).contains(permutations) => {
+                Err(format!("Shapley values need between 1 and {} permutations.", MAX_PERMUTATIONS))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether scores are measured on the validation set.
+    pub fn needs_validation(&self) -> bool {
+        *self != ContributionMethod::SampleShare
+    }
+}
+
+// Every permutation evaluates up to one model per update on the validation set.
+const MAX_PERMUTATIONS: u64 = 50;
+
+/// Score of one clinic in a round.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ContributionScore {
+    pub principal: Principal,
+    // District the clinic contributed through, which scores are split from by samples.
+    pub district: Option<Principal>,
+    pub num_samples: u64,
+    pub score: f64,
+    // Share of the round's unit of credit.
+    pub credit: f64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoundContributions {
+    pub task: String,
+    pub round: u64,
+    pub version: u64,
+    // Method the scores were computed with, sample shares when the configured one failed.
+    pub method: ContributionMethod,
+    pub fallback: Option<String>,
+    pub scores: Vec<ContributionScore>,
+    pub recorded_at: u64,
+}
+
+impl_storable_json!(RoundContributions);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ContributionTotal {
+    pub principal: Principal,
+    pub rounds: u64,
+    pub credit: f64,
+}
+
+/// Measured scoring of a published round, in progress.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ScoringJob {
+    pub task: String,
+    pub round: u64,
+    // Version the round published, and the version its counterfactual models start from.
+    pub version: u64,
+    pub base_version: u64,
+    pub method: ContributionMethod,
+    // Validation accuracy of the subsets of updates evaluated so far.
+    pub utilities: Vec<(Vec<usize>, f64)>,
+    pub started_at: u64,
+}
+
+impl_storable_json!(ScoringJob);
+
+thread_local! {
+    static CONTRIBUTIONS: RefCell<StableBTreeMap<String, RoundContributions, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::CONTRIBUTIONS_MEMORY_ID))
+    );
+
+    static SCORING_JOBS: RefCell<StableBTreeMap<String, ScoringJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::SCORING_JOBS_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_contributions() -> Vec<(String, RoundContributions)> {
+    CONTRIBUTIONS.with(storage::entries)
+}
+
+/// Replaces the contribution records, used when a snapshot is restored.
+pub(crate) fn replace_contributions(contributions: Vec<(String, RoundContributions)>) {
+    CONTRIBUTIONS.with(|m| storage::replace_entries(m, contributions))
+}
+
+pub(crate) fn export_scoring_jobs() -> Vec<(String, ScoringJob)> {
+    SCORING_JOBS.with(storage::entries)
+}
+
+/// Replaces the scoring jobs, used when a snapshot is restored.
+pub(crate) fn replace_scoring_jobs(scoring_jobs: Vec<(String, ScoringJob)>) {
+    SCORING_JOBS.with(|m| storage::replace_entries(m, scoring_jobs))
+}
+
+fn round_key(task: &str, round: u64) -> String {
+    format!("{}/{:020}", task, round)
+}
+
+/// Share of the total weight of every update.
+pub fn sample_shares(weights: &[f64]) -> Vec<f64> {
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| if total > 0.0 { w / total } else { 0.0 }).collect()
+}
+
+/// Utility of all `n` updates minus the utility without each of them. `utility` evaluates the
+/// aggregate of a subset of update indexes.
+pub fn leave_one_out<E>(n: usize, mut utility: impl FnMut(&[usize]) -> Result<f64, E>) -> Result<Vec<f64>, E> {
+    let all: Vec<usize> = (0..n).collect();
+    let full = utility(&all)?;
+    (0..n)
+        .map(|i| {
+            let rest: Vec<usize> = all.iter().copied().filter(|j| *j != i).collect();
+            Ok(full - utility(&rest)?)
+        })
+        .collect()
+}
+
+/// Monte Carlo Shapley values of `n` updates. The empty subset is the model of the round.
+pub fn shapley<E>(
+    n: usize,
+    permutations: u64,
+    rng: &mut Rng,
+    mut utility: impl FnMut(&[usize]) -> Result<f64, E>,
+) -> Result<Vec<f64>, E> {
+    // Subsets reached by several permutations are evaluated once.
+    let mut cache: BTreeMap<Vec<usize>, f64> = BTreeMap::new();
+    let mut evaluate = |subset: &[usize]| -> Result<f64, E> {
+        let mut key = subset.to_vec();
+        key.sort();
+        if let Some(value) = cache.get(&key) {
+            return Ok(*value);
+        }
+        let value = utility(&key)?;
+        cache.insert(key, value);
+        Ok(value)
+    };
+    let mut values = vec![0.0; n];
+    let mut order: Vec<usize> = (0..n).collect();
+    for _ in 0..permutations {
+        rng.shuffle(&mut order);
+        let mut previous = evaluate(&[])?;
+        for k in 0..n {
+            let current = evaluate(&order[..=k])?;
+            values[order[k]] += current - previous;
+            previous = current;
+        }
+    }
+    Ok(values.into_iter().map(|v| v / permutations.max(1) as f64).collect())
+}
+
+/// Splits one unit of credit over the positive `scores`, evenly when none is positive.
+pub fn credits(scores: &[f64]) -> Vec<f64> {
+    let positive: f64 = scores.iter().map(|s| s.max(0.0)).sum();
+    if positive > 0.0 {
+        return scores.iter().map(|s| s.max(0.0) / positive).collect();
+    }
+    vec![1.0 / scores.len().max(1) as f64; scores.len()]
+}
+
+pub fn record(contributions: RoundContributions) {
+    let key = round_key(&contributions.task, contributions.round);
+    CONTRIBUTIONS.with(|c| c.borrow_mut().insert(key, contributions));
+}
+
+pub fn scoring_job(task: &str, round: u64) -> Option<ScoringJob> {
+    SCORING_JOBS.with(|j| j.borrow().get(&round_key(task, round)))
+}
+
+pub fn save_scoring_job(job: &ScoringJob) {
+    SCORING_JOBS.with(|j| j.borrow_mut().insert(round_key(&job.task, job.round), job.clone()));
+}
+
+pub fn remove_scoring_job(task: &str, round: u64) {
+    SCORING_JOBS.with(|j| j.borrow_mut().remove(&round_key(task, round)));
+}
+
+pub fn scoring_jobs() -> Vec<ScoringJob> {
+    SCORING_JOBS.with(|j| j.borrow().iter().map(|(_, job)| job).collect())
+}
+
+/// Contribution records of `task`, oldest round first.
+pub fn contributions(task: &str) -> Vec<RoundContributions> {
+    let prefix = format!("{}/", task);
+    CONTRIBUTIONS.with(|c| {
+        c.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, round)| round)
+            .collect()
+    })
+}
+
+/// Credit earned per principal over all rounds of `task`.
+pub fn totals(task: &str) -> BTreeMap<Principal, ContributionTotal> {
+    let mut totals: BTreeMap<Principal, ContributionTotal> = BTreeMap::new();
+    for round in contributions(task) {
+        for score in round.scores {
+            let total = totals.entry(score.principal).or_insert(ContributionTotal {
+                principal: score.principal,
+                rounds: 0,
+                credit: 0.0,
+            });
+            total.rounds += 1;
+            total.credit += score.credit;
+        }
+    }
+    totals
+}
+
+#[ic_cdk::query]
+pub fn get_round_contributions(task: String, round: u64) -> Option<RoundContributions> {
+    CONTRIBUTIONS.with(|c| c.borrow().get(&round_key(&task, round)))
+}
+
+/// Credit per principal for `task`, largest first.
+#[ic_cdk::query]
+pub fn get_contribution_ledger(task: String) -> Vec<ContributionTotal> {
+    let mut totals: Vec<ContributionTotal> = totals(&task).into_values().collect();
+    totals.sort_by(|a, b| b.credit.total_cmp(&a.credit));
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn additive_utilities_are_attributed_exactly() {
+        // Every update adds its own value to the accuracy of the base model.
+        let gains = [0.1, 0.0, 0.3];
+        let utility = |subset: &[usize]| Ok::<_, String>(0.5 + subset.iter().map(|i| gains[*i]).sum::<f64>());
+        let loo = leave_one_out(3, utility).unwrap();
+        let values = shapley(3, 5, &mut Rng::from_seed(b"test"), utility).unwrap();
+        for (i, gain) in gains.iter().enumerate() {
+            assert!((loo[i] - gain).abs() < 1e-9);
+            assert!((values[i] - gain).abs() < 1e-9);
+        }
+        let credit = credits(&[0.1, -0.2, 0.3]);
+        assert!((credit[0] - 0.25).abs() < 1e-9 && credit[1] == 0.0 && (credit[2] - 0.75).abs() < 1e-9);
+    }
+}
@@ -1,9 +1,9 @@
-mod client; 
-mod client_type;
+pub mod client; 
+pub mod client_type;
 mod agent;
 mod server; 
 mod biogpt;
-mod malaria_types;
+pub mod malaria_types;
 use serde::Deserialize;
 use candle_core::{DType, Device, Tensor};
 use candle_nn::{linear, Linear, Module, Optimizer, VarBuilder, VarMap};
@@ -13,8 +13,51 @@ use crate::client::{FILE_STORAGE, upload_file, read_image_data, Dataset, Dataset
 use crate::client_type::{load_and_predict_malaria_stage};
 use crate::malaria_types::load_and_predict_malaria_type;
 use crate::biogpt::generate_response;
+use crate::registry::{ArtifactKind, ModelVersion, TaskState};
+use crate::access::Role;
+use crate::quota::{PrincipalUsage, QuotaConfig, StorageUsage};
+use crate::retention::{GcReport, RetentionPolicy};
+use crate::storage::CompressionConfig;
+use crate::snapshot::{SnapshotInfo, SnapshotManifest};
+use crate::agent::{GlobalModelChunk, Invitation, RoundConfig, RoundStatus};
+use crate::clinics::{ClinicNode, NodeProfile};
+use crate::client::ClientUpdate;
+use crate::privacy::PrivacyBudget;
+use crate::personalization::HeadInfo;
+use crate::hierarchy::{ClinicContribution, District, Provenance};
+use crate::incentives::{ContributionTotal, RoundContributions};
+use crate::finetune::{FineTuneConfig, FineTuneJob, LabelledImage};
+use crate::evaluation::{EvaluationInvitation, EvaluationRound};
+use crate::lineage::Lineage;
+use crate::clinics::SelectionStrategy;
+use crate::secure_aggregation::{EncryptedShare, MaskKeys, RevealedShare, SecAggView};
+use candid::Principal;
 use candid::CandidType;
 mod storage;
+mod access;
+mod registry;
+mod upgrade;
+mod quota;
+mod retention;
+mod snapshot;
+pub mod checkpoint;
+pub mod aggregation;
+mod optimizer;
+pub mod delta;
+mod rng;
+mod clinics;
+mod secure_aggregation;
+mod privacy;
+mod validation;
+mod anomaly;
+mod fedbuff;
+mod personalization;
+mod hierarchy;
+mod incentives;
+mod finetune;
+mod evaluation;
+mod lineage;
+mod deadlines;
 
 const DEVICE: Device = Device::Cpu;
 use getrandom::Error;
@@ -0,0 +1,175 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::agent::{self, TrainingMode};
+use crate::aggregation::Aggregator;
+use crate::client::TrainingConfig;
+use crate::finetune::FineTuneConfig;
+use crate::hierarchy::ProvenanceEntry;
+use crate::optimizer::ServerOptimizer;
+use crate::privacy::DifferentialPrivacy;
+use crate::registry::{self, ArtifactKind};
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Model lineage for audits. Every committed version records where it came from: the round that
+//aggregated it, the fine-tuning job that trained it or the principal that uploaded it, its
+//parent version and the hashes of its artifacts. Records are never pruned with the versions
+//themselves, so the history of a production model can be walked back to its origins.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum VersionOrigin {
+    Upload {
+        uploader: Principal,
+    },
+    FederatedRound {
+        round: u64,
+        aggregator: Aggregator,
+        server_optimizer: ServerOptimizer,
+        training: TrainingConfig,
+        mode: TrainingMode,
+        secure: bool,
+        differential_privacy: Option<DifferentialPrivacy>,
+        // Clinics whose updates made it into the aggregate.
+        contributors: Vec<ProvenanceEntry>,
+    },
+    FineTuning {
+        // When the job started, which identifies it among the jobs of the task.
+        started_at: u64,
+        config: FineTuneConfig,
+        samples: u64,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LineageRecord {
+    pub task: String,
+    pub version: u64,
+    // Version this one was derived from, `None` for uploads and the legacy model.
+    pub parent: Option<u64>,
+    pub origin: VersionOrigin,
+    // Hex SHA-256 of the artifacts as uploaded, before compression.
+    pub model_sha256: String,
+    pub config_sha256: String,
+    pub recorded_at: u64,
+}
+
+impl_storable_json!(LineageRecord);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Lineage {
+    pub task: String,
+    pub version: u64,
+    // The version itself first, then its ancestors.
+    pub records: Vec<LineageRecord>,
+    // First ancestor without a record, committed before lineage was recorded.
+    pub unrecorded: Option<u64>,
+}
+
+thread_local! {
+    static RECORDS: RefCell<StableBTreeMap<String, LineageRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::LINEAGE_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_records() -> Vec<(String, LineageRecord)> {
+    RECORDS.with(storage::entries)
+}
+
+/// Replaces every lineage record, used when a snapshot is restored.
+pub(crate) fn replace_records(records: Vec<(String, LineageRecord)>) {
+    RECORDS.with(|r| storage::replace_entries(r, records))
+}
+
+fn record_key(task: &str, version: u64) -> String {
+    format!("{}/{:020}", task, version)
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn get(task: &str, version: u64) -> Option<LineageRecord> {
+    RECORDS.with(|r| r.borrow().get(&record_key(task, version)))
+}
+
+/// Records the origin of the committed `version` of `task`, hashing its artifacts.
+pub fn record(task: &str, version: u64, parent: Option<u64>, origin: VersionOrigin, now: u64) {
+    let hash = |kind| sha256(&storage::bytes(registry::artifact_key(task, version, kind)));
+    let record = LineageRecord {
+        task: task.to_string(),
+        version,
+        // The legacy upload is not a registry version.
+        parent: parent.filter(|parent| *parent > 0),
+        origin,
+        model_sha256: hash(ArtifactKind::Model),
+        config_sha256: hash(ArtifactKind::Config),
+        recorded_at: now,
+    };
+    RECORDS.with(|r| r.borrow_mut().insert(record_key(task, version), record));
+}
+
+/// Walks from `version` of `task` back through its parents.
+pub fn lineage(task: &str, version: u64) -> Lineage {
+    let mut records = Vec::new();
+    let mut next = Some(version);
+    let mut unrecorded = None;
+    while let Some(current) = next {
+        let Some(record) = get(task, current) else {
+            unrecorded = Some(current);
+            break;
+        };
+        next = record.parent;
+        records.push(record);
+    }
+    Lineage { task: task.to_string(), version, records, unrecorded }
+}
+
+/// The lineage of `version` of `task`, of its production version by default.
+#[ic_cdk::query]
+pub fn get_model_lineage(task: String, version: Option<u64>) -> Result<Lineage, String> {
+    let version = match version {
+        Some(version) => version,
+        None => agent::global_model(&task)?.version,
+    };
+    if version == 0 {
+        return Err(format!("{} is served by the legacy upload, which has no lineage.", task));
+    }
+    Ok(lineage(&task, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lineage_walks_back_to_the_upload() {
+        let uploader = Principal::from_slice(&[1]);
+        record("lineage-test", 1, None, VersionOrigin::Upload { uploader }, 0);
+        let round = VersionOrigin::FederatedRound {
+            round: 1,
+            aggregator: Aggregator::FedAvg,
+            server_optimizer: ServerOptimizer::default(),
+            training: TrainingConfig::default(),
+            mode: TrainingMode::Rounds,
+            secure: false,
+            differential_privacy: None,
+            contributors: Vec::new(),
+        };
+        record("lineage-test", 2, Some(1), round, 1);
+        let tuning = VersionOrigin::FineTuning { started_at: 2, config: FineTuneConfig::default(), samples: 10 };
+        record("lineage-test", 3, Some(2), tuning, 3);
+
+        let walked = lineage("lineage-test", 3);
+        assert_eq!(walked.records.iter().map(|r| r.version).collect::<Vec<_>>(), vec![3, 2, 1]);
+        assert!(matches!(walked.records[2].origin, VersionOrigin::Upload { uploader: u } if u == uploader));
+        assert_eq!(walked.unrecorded, None);
+        // Artifacts that were never uploaded hash like empty ones.
+        assert_eq!(walked.records[0].model_sha256, sha256(&[]));
+
+        record("lineage-test", 5, Some(4), VersionOrigin::Upload { uploader }, 4);
+        assert_eq!(lineage("lineage-test", 5).unrecorded, Some(4));
+    }
+}
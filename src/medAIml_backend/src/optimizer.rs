@@ -0,0 +1,206 @@
+use candid::CandidType;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::access::is_controller;
+use crate::aggregation::{flatten, rebuild, Flat};
+use crate::checkpoint::Checkpoint;
+use crate::storage::{self, Memory};
+
+//Server-side adaptive optimisers (Reddi et al., "Adaptive Federated Optimization"). The
+//aggregated client model minus the global model is used as a pseudo-gradient, and the
+//first and second moments of it are kept per task across rounds.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AdaptiveParams {
+    pub server_lr: f64,
+    pub beta1: f64,
+    pub beta2: f64,
+    // Adaptivity, also the initial square root of the second moment.
+    pub tau: f64,
+}
+
+impl Default for AdaptiveParams {
+    fn default() -> Self {
+        AdaptiveParams {
+            server_lr: 0.01,
+            beta1: 0.9,
+            beta2: 0.99,
+            tau: 1e-3,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum ServerOptimizer {
+    // The aggregate becomes the new global model as is.
+    #[default]
+    None,
+    FedAdagrad(AdaptiveParams),
+    FedAdam(AdaptiveParams),
+    FedYogi(AdaptiveParams),
+}
+
+impl ServerOptimizer {
+    pub fn validate(&self) -> Result<(), String> {
+        let params = match self {
+            ServerOptimizer::None => return Ok(()),
+            ServerOptimizer::FedAdagrad(p) | ServerOptimizer::FedAdam(p) | ServerOptimizer::FedYogi(p) => p,
+        };
+        if params.server_lr <= 0.0 || params.tau <= 0.0 {
+            return Err("The server learning rate and tau must be positive.".to_string());
+        }
+        if !(0.0..1.0).contains(&params.beta1) || !(0.0..1.0).contains(&params.beta2) {
+            return Err("The betas must lie in [0, 1).".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Moments of one task, as safetensors. `optimizer` is the one they were accumulated with.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct StoredMoments {
+    optimizer: ServerOptimizer,
+    #[serde(with = "serde_bytes")]
+    first: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    second: Vec<u8>,
+}
+
+impl Storable for StoredMoments {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(postcard::to_allocvec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        postcard::from_bytes(&bytes).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static MOMENTS: RefCell<StableBTreeMap<String, StoredMoments, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::OPTIMIZER_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_moments() -> Vec<(String, StoredMoments)> {
+    MOMENTS.with(storage::entries)
+}
+
+/// Replaces the moments of every task, used when a snapshot is restored.
+pub(crate) fn replace_moments(moments: Vec<(String, StoredMoments)>) {
+    MOMENTS.with(|m| storage::replace_entries(m, moments))
+}
+
+fn load_moments(task: &str, optimizer: &ServerOptimizer, global: &Flat) -> Option<(Flat, Flat)> {
+    let stored = MOMENTS.with(|m| m.borrow().get(&task.to_string()))?;
+    if stored.optimizer != *optimizer {
+        return None;
+    }
+    let first = flatten(&Checkpoint::from_safetensors(&stored.first).ok()?).ok()?;
+    let second = flatten(&Checkpoint::from_safetensors(&stored.second).ok()?).ok()?;
+    // The model architecture changed, start over.
+    let same_shape = |moment: &Flat| {
+        moment.len() == global.len()
+            && global.iter().all(|(name, values)| moment.get(name).is_some_and(|m| m.len() == values.len()))
+    };
+    (same_shape(&first) && same_shape(&second)).then_some((first, second))
+}
+
+/// Applies the server optimiser of the round to `aggregate` and returns the next global model.
+pub fn step(task: &str, optimizer: &ServerOptimizer, global: &Checkpoint, aggregate: Checkpoint) -> Result<Checkpoint, String> {
+    let params = match optimizer {
+        ServerOptimizer::None => return Ok(aggregate),
+        ServerOptimizer::FedAdagrad(p) | ServerOptimizer::FedAdam(p) | ServerOptimizer::FedYogi(p) => p.clone(),
+    };
+    let x = flatten(global)?;
+    let averaged = flatten(&aggregate)?;
+    let tau_sq = (params.tau * params.tau) as f32;
+    let (mut first, mut second) = load_moments(task, optimizer, &x).unwrap_or_else(|| {
+        let zeros = x.iter().map(|(name, v)| (name.clone(), vec![0.0; v.len()])).collect();
+        let initial = x.iter().map(|(name, v)| (name.clone(), vec![tau_sq; v.len()])).collect();
+        (zeros, initial)
+    });
+
+    let (b1, b2) = (params.beta1 as f32, params.beta2 as f32);
+    let (lr, tau) = (params.server_lr as f32, params.tau as f32);
+    let mut next = Flat::new();
+    for (name, values) in &x {
+        let m = first.get_mut(name).expect("moment shapes were checked");
+        let v = second.get_mut(name).expect("moment shapes were checked");
+        let updated = values
+            .iter()
+            .zip(&averaged[name])
+            .enumerate()
+            .map(|(i, (x, a))| {
+                let delta = a - x;
+                let delta_sq = delta * delta;
+                m[i] = b1 * m[i] + (1.0 - b1) * delta;
+                v[i] = match optimizer {
+                    ServerOptimizer::FedAdagrad(_) => v[i] + delta_sq,
+                    ServerOptimizer::FedAdam(_) => b2 * v[i] + (1.0 - b2) * delta_sq,
+                    _ => v[i] - (1.0 - b2) * delta_sq * (v[i] - delta_sq).signum(),
+                };
+                x + lr * m[i] / (v[i].sqrt() + tau)
+            })
+            .collect();
+        next.insert(name.clone(), updated);
+    }
+
+    let stored = StoredMoments {
+        optimizer: optimizer.clone(),
+        first: rebuild(global, first)?.to_safetensors()?,
+        second: rebuild(global, second)?.to_safetensors()?,
+    };
+    MOMENTS.with(|m| m.borrow_mut().insert(task.to_string(), stored));
+    rebuild(&aggregate, next)
+}
+
+/// Forgets the moments of `task`, e.g. after a rollback to an unrelated version.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn reset_optimizer_state(task: String) {
+    MOMENTS.with(|m| m.borrow_mut().remove(&task));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint as checkpoint;
+
+    const PARAMS: AdaptiveParams = AdaptiveParams { server_lr: 0.1, beta1: 0.9, beta2: 0.99, tau: 0.1 };
+
+    fn stepped(task: &str, optimizer: &ServerOptimizer, global: f32, aggregate: f32) -> f32 {
+        let next = step(task, optimizer, &checkpoint(&[global]), checkpoint(&[aggregate])).unwrap();
+        flatten(&next).unwrap()["w"][0]
+    }
+
+    #[test]
+    fn adaptive_optimizers_match_hand_computed_steps() {
+        // A pseudo-gradient of 1 from fresh moments m = 0 and v = tau^2 = 0.01.
+        let adam = ServerOptimizer::FedAdam(PARAMS);
+        assert!((stepped("optimizer-adam", &adam, 1.0, 2.0) - 1.04148).abs() < 1e-5);
+        let adagrad = ServerOptimizer::FedAdagrad(PARAMS);
+        assert!((stepped("optimizer-adagrad", &adagrad, 1.0, 2.0) - 1.00905).abs() < 1e-5);
+        let yogi = ServerOptimizer::FedYogi(PARAMS);
+        assert!((stepped("optimizer-yogi", &yogi, 1.0, 2.0) - 1.04142).abs() < 1e-5);
+        assert_eq!(stepped("optimizer-none", &ServerOptimizer::None, 1.0, 2.0), 2.0);
+    }
+
+    #[test]
+    fn moments_carry_over_until_the_optimizer_changes() {
+        let adam = ServerOptimizer::FedAdam(PARAMS);
+        stepped("optimizer-moments", &adam, 1.0, 2.0);
+        // No pseudo-gradient, but the decayed moments m = 0.09 and v = 0.019701 still move the model.
+        assert!((stepped("optimizer-moments", &adam, 1.0, 1.0) - 1.03744).abs() < 1e-5);
+        // Moments accumulated with another optimiser are discarded.
+        let yogi = ServerOptimizer::FedYogi(PARAMS);
+        assert_eq!(stepped("optimizer-moments", &yogi, 1.0, 1.0), 1.0);
+        reset_optimizer_state("optimizer-moments".to_string());
+        assert_eq!(stepped("optimizer-moments", &adam, 1.0, 1.0), 1.0);
+    }
+}
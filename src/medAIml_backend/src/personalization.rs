@@ -0,0 +1,207 @@
+use candid::{CandidType, Principal};
+use candle_core::Tensor;
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::access::{is_controller, is_participant};
+use crate::agent;
+use crate::checkpoint::Checkpoint;
+use crate::quota;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Personalised classifier heads. The backbone of a task is shared through federated training,
+//while every clinic may keep its own `classifier.dense_1` and `classifier.output` layers.
+//Predictions made by a clinic use its head on top of the production backbone, and fall back
+//to the global head when the clinic has none or its head no longer fits the model.
+
+const HEAD_LAYERS: [&str; 2] = ["classifier.dense_1.", "classifier.output."];
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HeadInfo {
+    pub task: String,
+    pub principal: Principal,
+    // Global version the head was trained on top of.
+    pub base_version: u64,
+    pub tensors: Vec<String>,
+    pub size: u64,
+    pub updated_at: u64,
+}
+
+impl_storable_json!(HeadInfo);
+
+thread_local! {
+    static HEADS: RefCell<StableBTreeMap<String, HeadInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::HEADS_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_heads() -> Vec<(String, HeadInfo)> {
+    HEADS.with(storage::entries)
+}
+
+/// Replaces the index of personalised heads, used when a snapshot is restored.
+pub(crate) fn replace_heads(heads: Vec<(String, HeadInfo)>) {
+    HEADS.with(|m| storage::replace_entries(m, heads))
+}
+
+pub fn is_head_tensor(name: &str) -> bool {
+    HEAD_LAYERS.iter().any(|layer| name.starts_with(layer))
+}
+
+fn info_key(task: &str, principal: &Principal) -> String {
+    format!("{}/{}", task, principal.to_text())
+}
+
+fn head_key(task: &str, principal: &Principal) -> String {
+    format!("{}{}/{}", storage::HEADS_PREFIX, task, principal.to_text())
+}
+
+fn staged_key(task: &str, principal: &Principal) -> String {
+    format!("{}{}/staged/{}", storage::HEADS_PREFIX, task, principal.to_text())
+}
+
+pub fn head_info(task: &str, principal: &Principal) -> Option<HeadInfo> {
+    HEADS.with(|h| h.borrow().get(&info_key(task, principal)))
+}
+
+/// The head of `principal` for `task`, if it has one.
+pub fn head(task: &str, principal: &Principal) -> Option<Checkpoint> {
+    head_info(task, principal)?;
+    Checkpoint::from_safetensors(&storage::bytes(head_key(task, principal))).ok()
+}
+
+/// Fails unless `head` holds only head layers, all of them present in `global` with the same
+/// dtype and shape.
+pub fn check_head(head: &Checkpoint, global: &Checkpoint) -> Result<(), String> {
+    if head.tensors.is_empty() {
+        return Err("A head needs at least one tensor.".to_string());
+    }
+    for (name, tensor) in &head.tensors {
+        if !is_head_tensor(name) {
+            return Err(format!("Tensor {} is not part of the classifier head.", name));
+        }
+        let expected = global
+            .tensors
+            .get(name)
+            .ok_or_else(|| format!("Tensor {} is not part of the global model.", name))?;
+        if tensor.dtype() != expected.dtype() || tensor.dims() != expected.dims() {
+            return Err(format!(
+                "Tensor {} has {:?} {:?}, the global model {:?} {:?}.",
+                name,
+                tensor.dtype(),
+                tensor.dims(),
+                expected.dtype(),
+                expected.dims()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Stores `head` as the head of `principal` for `task`.
+pub fn save_head(task: &str, principal: Principal, head: &Checkpoint, base_version: u64, now: u64) -> Result<HeadInfo, String> {
+    let bytes = head.to_safetensors()?;
+    let info = HeadInfo {
+        task: task.to_string(),
+        principal,
+        base_version,
+        tensors: head.tensors.keys().cloned().collect(),
+        size: bytes.len() as u64,
+        updated_at: now,
+    };
+    storage::insert_raw(head_key(task, &principal), bytes);
+    HEADS.with(|h| h.borrow_mut().insert(info_key(task, &principal), info.clone()));
+    Ok(info)
+}
+
+/// Replaces the head layers of `tensors` with the head of `principal`, when it has one that
+/// fits them.
+pub fn personalize(task: &str, principal: &Principal, mut tensors: HashMap<String, Tensor>) -> HashMap<String, Tensor> {
+    let Some(head) = head(task, principal) else {
+        return tensors;
+    };
+    let fits = head.tensors.iter().all(|(name, tensor)| {
+        tensors
+            .get(name)
+            .is_some_and(|global| global.dtype() == tensor.dtype() && global.dims() == tensor.dims())
+    });
+    if fits {
+        tensors.extend(head.tensors);
+    }
+    tensors
+}
+
+/// Uploads the safetensors of the caller's head in chunks, before calling `commit_head`.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn append_head_bytes(task: String, bytes: Vec<u8>) -> Result<(), String> {
+    agent::global_model(&task)?;
+    let caller = ic_cdk::caller();
+    quota::check(&caller, bytes.len() as u64)?;
+    storage::append_raw(staged_key(&task, &caller), bytes);
+    Ok(())
+}
+
+/// Makes the uploaded head the caller's head for `task`.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn commit_head(task: String, base_version: u64) -> Result<HeadInfo, String> {
+    let caller = ic_cdk::caller();
+    let key = staged_key(&task, &caller);
+    let bytes = storage::bytes(key.clone());
+    storage::remove_raw(&key);
+    if bytes.is_empty() {
+        return Err("No head uploaded, call append_head_bytes first.".to_string());
+    }
+    let head = Checkpoint::from_safetensors(&bytes)?;
+    check_head(&head, &Checkpoint::from_safetensors(&agent::global_model(&task)?.weights)?)?;
+    save_head(&task, caller, &head, base_version, ic_cdk::api::time())
+}
+
+#[ic_cdk::query(guard = "is_participant")]
+pub fn get_head_info(task: String) -> Option<HeadInfo> {
+    head_info(&task, &ic_cdk::caller())
+}
+
+/// Drops the caller's head, its predictions use the global head again.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn delete_head(task: String) {
+    let caller = ic_cdk::caller();
+    storage::remove_raw(&head_key(&task, &caller));
+    HEADS.with(|h| h.borrow_mut().remove(&info_key(&task, &caller)));
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn list_heads(task: String) -> Vec<HeadInfo> {
+    let prefix = format!("{}/", task);
+    HEADS.with(|h| {
+        h.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, info)| info)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+    use std::collections::BTreeMap;
+
+    fn checkpoint(tensors: &[(&str, usize)]) -> Checkpoint {
+        let tensors = tensors
+            .iter()
+            .map(|(name, len)| (name.to_string(), Tensor::zeros(*len, DType::F32, &Device::Cpu).unwrap()))
+            .collect::<BTreeMap<_, _>>();
+        Checkpoint { tensors }
+    }
+
+    #[test]
+    fn heads_only_replace_classifier_layers() {
+        let global = checkpoint(&[("hidden_0.weight", 8), ("classifier.dense_1.weight", 4), ("classifier.output.weight", 2)]);
+        assert!(check_head(&checkpoint(&[("classifier.output.weight", 2)]), &global).is_ok());
+        assert!(check_head(&checkpoint(&[("hidden_0.weight", 8)]), &global).is_err());
+        assert!(check_head(&checkpoint(&[("classifier.output.weight", 3)]), &global).is_err());
+    }
+}
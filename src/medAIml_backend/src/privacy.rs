@@ -0,0 +1,295 @@
+use candid::CandidType;
+use ic_stable_structures::StableBTreeMap;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::aggregation::{flatten, rebuild};
+use crate::checkpoint::Checkpoint;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Differential privacy for federated rounds after DP-FedAvg (McMahan et al., "Learning
+//Differentially Private Recurrent Language Models"). The update of every client is clipped
+//to an L2 distance of `clip_norm` from the global model, the clipped updates are averaged
+//with equal weights and Gaussian noise scaled to the clip norm is added to the average.
+//
+//The accountant tracks the Rényi DP of the Gaussian mechanism per task and converts it to
+//(epsilon, delta). Client selection is not treated as subsampling, so the reported epsilon
+//is an upper bound.
+
+// Rényi orders the accountant tracks.
+const ORDERS: [f64; 20] = [
+    1.25, 1.5, 1.75, 2.0, 2.5, 3.0, 4.0, 5.0, 6.0, 8.0, 10.0, 12.0, 16.0, 20.0, 24.0, 32.0, 48.0, 64.0, 128.0, 256.0,
+];
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DifferentialPrivacy {
+    // Largest L2 distance of an update from the global model.
+    pub clip_norm: f64,
+    // Noise standard deviation over the clip norm.
+    pub noise_multiplier: f64,
+    pub target_delta: f64,
+    // No round is started once it would push epsilon above this.
+    pub epsilon_budget: f64,
+}
+
+impl Default for DifferentialPrivacy {
+    fn default() -> Self {
+        DifferentialPrivacy {
+            clip_norm: 1.0,
+            noise_multiplier: 1.0,
+            target_delta: 1e-5,
+            epsilon_budget: 10.0,
+        }
+    }
+}
+
+impl DifferentialPrivacy {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.clip_norm > 0.0 && self.noise_multiplier > 0.0 && self.epsilon_budget > 0.0) {
+            return Err("The clip norm, noise multiplier and epsilon budget must be positive.".to_string());
+        }
+        if !(self.target_delta > 0.0 && self.target_delta < 1.0) {
+            return Err("The target delta must lie in (0, 1).".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PrivacySpend {
+    pub round: u64,
+    pub clip_norm: f64,
+    pub noise_multiplier: f64,
+    pub participants: u64,
+    // Cumulative epsilon of the task after this round, at the delta of the round.
+    pub epsilon: f64,
+    pub spent_at: u64,
+}
+
+// Cumulative Rényi DP per entry of `ORDERS`, and the rounds that spent it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct PrivacyLedger {
+    rdp: Vec<f64>,
+    rounds: Vec<PrivacySpend>,
+}
+
+impl_storable_json!(PrivacyLedger);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PrivacyBudget {
+    pub task: String,
+    pub target_delta: f64,
+    pub epsilon_spent: f64,
+    // `None` when the task is not configured for differential privacy.
+    pub epsilon_budget: Option<f64>,
+    pub rounds: Vec<PrivacySpend>,
+}
+
+thread_local! {
+    static LEDGERS: RefCell<StableBTreeMap<String, PrivacyLedger, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::PRIVACY_MEMORY_ID))
+    );
+
+    // Secret the noise is drawn from. The canister state is not public, but the seeds of
+    // `rng::Rng` are, so noise needs its own source. Fetched again after every upgrade.
+    static NOISE_SEED: RefCell<Option<[u8; 32]>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn export_ledgers() -> Vec<(String, PrivacyLedger)> {
+    LEDGERS.with(storage::entries)
+}
+
+/// Replaces the privacy ledger of every task, used when a snapshot is restored.
+pub(crate) fn replace_ledgers(ledgers: Vec<(String, PrivacyLedger)>) {
+    LEDGERS.with(|m| storage::replace_entries(m, ledgers))
+}
+
+fn ledger(task: &str) -> PrivacyLedger {
+    LEDGERS.with(|l| l.borrow().get(&task.to_string())).unwrap_or_default()
+}
+
+/// Rényi DP of one release of the Gaussian mechanism at `order`.
+fn gaussian_rdp(noise_multiplier: f64, order: f64) -> f64 {
+    order / (2.0 * noise_multiplier * noise_multiplier)
+}
+
+/// Smallest epsilon over all orders (Mironov, "Rényi Differential Privacy", Proposition 3).
+fn epsilon(rdp: &[f64], delta: f64) -> f64 {
+    if rdp.is_empty() {
+        return 0.0;
+    }
+    ORDERS
+        .iter()
+        .zip(rdp)
+        .map(|(order, rdp)| rdp + (1.0 / delta).ln() / (order - 1.0))
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn add_round(rdp: &[f64], noise_multiplier: f64) -> Vec<f64> {
+    ORDERS
+        .iter()
+        .enumerate()
+        .map(|(i, order)| rdp.get(i).copied().unwrap_or(0.0) + gaussian_rdp(noise_multiplier, *order))
+        .collect()
+}
+
+/// Fails when one more round under `dp` would exceed its budget.
+pub fn check_budget(task: &str, dp: &DifferentialPrivacy) -> Result<(), String> {
+    let ledger = ledger(task);
+    let projected = epsilon(&add_round(&ledger.rdp, dp.noise_multiplier), dp.target_delta);
+    if projected > dp.epsilon_budget {
+        return Err(format!(
+            "The privacy budget of {} is exhausted: another round would reach epsilon {:.3} of {:.3}.",
+            task, projected, dp.epsilon_budget
+        ));
+    }
+    Ok(())
+}
+
+/// Charges the noisy aggregate of a round to the ledger of `task`.
+pub fn record(task: &str, round: u64, dp: &DifferentialPrivacy, participants: u64, now: u64) {
+    let mut ledger = ledger(task);
+    ledger.rdp = add_round(&ledger.rdp, dp.noise_multiplier);
+    ledger.rounds.push(PrivacySpend {
+        round,
+        clip_norm: dp.clip_norm,
+        noise_multiplier: dp.noise_multiplier,
+        participants,
+        epsilon: epsilon(&ledger.rdp, dp.target_delta),
+        spent_at: now,
+    });
+    LEDGERS.with(|l| l.borrow_mut().insert(task.to_string(), ledger));
+}
+
+pub fn budget(task: &str, dp: Option<&DifferentialPrivacy>) -> PrivacyBudget {
+    let ledger = ledger(task);
+    let target_delta = dp.map(|dp| dp.target_delta).unwrap_or(DifferentialPrivacy::default().target_delta);
+    PrivacyBudget {
+        task: task.to_string(),
+        target_delta,
+        epsilon_spent: epsilon(&ledger.rdp, target_delta),
+        epsilon_budget: dp.map(|dp| dp.epsilon_budget),
+        rounds: ledger.rounds,
+    }
+}
+
+/// Standard normal sample (Box-Muller).
+fn gaussian(rng: &mut ChaCha20Rng) -> f64 {
+    let uniform = |rng: &mut ChaCha20Rng| ((rng.next_u64() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    let (u1, u2) = (uniform(rng), uniform(rng));
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Noise generator of round `round` of `task`.
+pub fn noise_rng(task: &str, round: u64) -> Result<ChaCha20Rng, String> {
+    let seed = NOISE_SEED
+        .with(|s| *s.borrow())
+        .ok_or_else(|| "No randomness for differential privacy noise is available yet.".to_string())?;
+    let mut hasher = Sha256::new();
+    hasher.update(seed);
+    hasher.update(format!("{}/{}", task, round).as_bytes());
+    Ok(ChaCha20Rng::from_seed(hasher.finalize().into()))
+}
+
+/// Fetches a fresh noise seed from the management canister once the current call is over.
+#[cfg(target_arch = "wasm32")]
+pub fn refresh_noise_seed() {
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            match ic_cdk::api::management_canister::main::raw_rand().await {
+                Ok((bytes,)) => match <[u8; 32]>::try_from(bytes.as_slice()) {
+                    Ok(seed) => NOISE_SEED.with(|s| *s.borrow_mut() = Some(seed)),
+                    Err(_) => ic_cdk::println!("raw_rand returned {} bytes instead of 32", bytes.len()),
+                },
+                Err(e) => ic_cdk::println!("Failed to fetch a noise seed: {:?}", e),
+            }
+        })
+    });
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn refresh_noise_seed() {
+    NOISE_SEED.with(|s| *s.borrow_mut() = Some([0; 32]));
+}
+
+/// Equal-weight average of the `updates` clipped around `global`, plus Gaussian noise.
+/// Returns the noisy model and the indexes of the updates that had to be clipped.
+pub fn privatize(
+    dp: &DifferentialPrivacy,
+    global: &Checkpoint,
+    updates: &[Checkpoint],
+    rng: &mut ChaCha20Rng,
+) -> Result<(Checkpoint, Vec<usize>), String> {
+    if updates.is_empty() {
+        return Err("Nothing to aggregate.".to_string());
+    }
+    let base = flatten(global)?;
+    let mut sum: Vec<Vec<f64>> = base.values().map(|values| vec![0.0; values.len()]).collect();
+    let mut clipped = Vec::new();
+    for (i, update) in updates.iter().enumerate() {
+        let flat = flatten(update)?;
+        let deltas: Vec<Vec<f64>> = base
+            .iter()
+            .map(|(name, b)| flat[name].iter().zip(b).map(|(v, b)| (*v - *b) as f64).collect())
+            .collect();
+        let norm = deltas.iter().flatten().map(|d| d * d).sum::<f64>().sqrt();
+        let scale = if norm > dp.clip_norm {
+            clipped.push(i);
+            dp.clip_norm / norm
+        } else {
+            1.0
+        };
+        for (acc, delta) in sum.iter_mut().zip(&deltas) {
+            acc.iter_mut().zip(delta).for_each(|(a, d)| *a += d * scale);
+        }
+    }
+
+    // One client moves the sum by at most `clip_norm`.
+    let n = updates.len() as f64;
+    let sigma = dp.noise_multiplier * dp.clip_norm;
+    let mut noisy = base.clone();
+    for (values, acc) in noisy.values_mut().zip(&sum) {
+        for (value, total) in values.iter_mut().zip(acc) {
+            *value += ((total + sigma * gaussian(rng)) / n) as f32;
+        }
+    }
+    Ok((rebuild(global, noisy)?, clipped))
+}
+
+/// Epsilon spent on `task` so far and the rounds that spent it.
+#[ic_cdk::query]
+pub fn get_privacy_budget(task: String) -> PrivacyBudget {
+    let config = crate::agent::round_config(&task);
+    budget(&task, config.differential_privacy.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint as checkpoint;
+
+    #[test]
+    fn epsilon_grows_with_rounds_and_shrinks_with_noise() {
+        let one = add_round(&[], 1.0);
+        let two = add_round(&one, 1.0);
+        assert!(epsilon(&two, 1e-5) > epsilon(&one, 1e-5));
+        assert!(epsilon(&add_round(&[], 4.0), 1e-5) < epsilon(&one, 1e-5));
+        // Best order for a single release with sigma = 1 and delta = 1e-5 is 6.
+        assert!((epsilon(&one, 1e-5) - (3.0 + 1e5f64.ln() / 5.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn updates_are_clipped_before_averaging() {
+        let dp = DifferentialPrivacy { clip_norm: 1.0, noise_multiplier: 1e-9, ..Default::default() };
+        let global = checkpoint(&[0.0, 0.0]);
+        let updates = [checkpoint(&[3.0, 4.0]), checkpoint(&[0.0, 0.5])];
+        let (result, clipped) = privatize(&dp, &global, &updates, &mut ChaCha20Rng::from_seed([0; 32])).unwrap();
+        assert_eq!(clipped, vec![0]);
+        let values = flatten(&result).unwrap().remove("w").unwrap();
+        assert!((values[0] - 0.3).abs() < 1e-5);
+        assert!((values[1] - 0.65).abs() < 1e-5);
+    }
+}
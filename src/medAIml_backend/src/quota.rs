@@ -0,0 +1,191 @@
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::access::{self, is_controller, Role};
+use crate::storage::{self, impl_storable_json, Memory};
+
+const MIB: u64 = 1024 * 1024;
+
+//Stable-memory quotas. A principal may own at most the limit of its role, unless it has
+//a limit of its own. `None` means unlimited.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct QuotaConfig {
+    pub role_limits: Vec<(Role, Option<u64>)>,
+    pub principal_limits: Vec<(Principal, Option<u64>)>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            role_limits: vec![
+                (Role::Admin, None),
+                (Role::Uploader, Some(1024 * MIB)),
+                (Role::Clinic, Some(64 * MIB)),
+                (Role::User, Some(8 * MIB)),
+            ],
+            principal_limits: Vec::new(),
+        }
+    }
+}
+
+impl_storable_json!(QuotaConfig);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArtifactUsage {
+    pub key: String,
+    pub namespace: String,
+    pub owner: Principal,
+    pub size: u64,
+    pub raw_size: u64,
+    pub compressed: bool,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NamespaceUsage {
+    pub namespace: String,
+    pub artifacts: u64,
+    pub bytes: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PrincipalUsage {
+    pub principal: Principal,
+    pub role: Role,
+    pub bytes: u64,
+    pub limit: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StorageUsage {
+    pub total_bytes: u64,
+    pub namespaces: Vec<NamespaceUsage>,
+    pub principals: Vec<PrincipalUsage>,
+    pub artifacts: Vec<ArtifactUsage>,
+}
+
+thread_local! {
+    static QUOTA_CONFIG: RefCell<StableCell<QuotaConfig, Memory>> = RefCell::new(
+        StableCell::init(storage::memory(storage::QUOTA_MEMORY_ID), QuotaConfig::default())
+            .expect("failed to init QUOTA_CONFIG cell")
+    );
+}
+
+pub fn config() -> QuotaConfig {
+    QUOTA_CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn limit_for(principal: &Principal) -> Option<u64> {
+    let config = config();
+    if let Some((_, limit)) = config.principal_limits.iter().find(|(p, _)| p == principal) {
+        return *limit;
+    }
+    let role = access::role_of(principal);
+    config
+        .role_limits
+        .iter()
+        .find(|(r, _)| *r == role)
+        .and_then(|(_, limit)| *limit)
+}
+
+pub fn usage_of(principal: &Principal) -> u64 {
+    storage::artifacts()
+        .iter()
+        .filter(|(_, meta)| meta.owner == *principal)
+        .map(|(_, meta)| meta.size)
+        .sum()
+}
+
+/// Fails when storing `additional` more bytes would take `principal` over its limit.
+pub fn check(principal: &Principal, additional: u64) -> Result<(), String> {
+    let Some(limit) = limit_for(principal) else {
+        return Ok(());
+    };
+    let used = usage_of(principal);
+    if used.saturating_add(additional) > limit {
+        return Err(format!(
+            "Storage quota exceeded: {} of {} bytes used, {} more requested.",
+            used, limit, additional
+        ));
+    }
+    Ok(())
+}
+
+pub fn usage() -> StorageUsage {
+    let mut namespaces: BTreeMap<String, NamespaceUsage> = BTreeMap::new();
+    let mut principals: BTreeMap<Principal, u64> = BTreeMap::new();
+    let mut artifacts = Vec::new();
+    let mut total_bytes = 0;
+
+    for (key, meta) in storage::artifacts() {
+        let namespace = storage::namespace(&key).to_string();
+        let entry = namespaces.entry(namespace.clone()).or_insert(NamespaceUsage {
+            namespace: namespace.clone(),
+            artifacts: 0,
+            bytes: 0,
+        });
+        entry.artifacts += 1;
+        entry.bytes += meta.size;
+        *principals.entry(meta.owner).or_default() += meta.size;
+        total_bytes += meta.size;
+        artifacts.push(ArtifactUsage {
+            key,
+            namespace,
+            owner: meta.owner,
+            size: meta.size,
+            raw_size: meta.raw_size,
+            compressed: meta.compressed,
+            updated_at: meta.updated_at,
+        });
+    }
+
+    StorageUsage {
+        total_bytes,
+        namespaces: namespaces.into_values().collect(),
+        principals: principals
+            .into_iter()
+            .map(|(principal, bytes)| PrincipalUsage {
+                principal,
+                role: access::role_of(&principal),
+                bytes,
+                limit: limit_for(&principal),
+            })
+            .collect(),
+        artifacts,
+    }
+}
+
+pub fn set_config(config: QuotaConfig) -> Result<(), String> {
+    QUOTA_CONFIG.with(|c| c.borrow_mut().set(config))
+        .map(|_| ())
+        .map_err(|e| format!("Failed to store quota config: {:?}", e))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn set_quota_config(config: QuotaConfig) -> Result<(), String> {
+    set_config(config)
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn get_quota_config() -> QuotaConfig {
+    config()
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn storage_usage() -> StorageUsage {
+    usage()
+}
+
+#[ic_cdk::query]
+pub fn my_storage_usage() -> PrincipalUsage {
+    let principal = ic_cdk::caller();
+    PrincipalUsage {
+        principal,
+        role: access::role_of(&principal),
+        bytes: usage_of(&principal),
+        limit: limit_for(&principal),
+    }
+}
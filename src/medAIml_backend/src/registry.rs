@@ -0,0 +1,422 @@
+use candid::{CandidType, Principal};
+use candle_core::Device;
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::access::{self, is_controller, is_uploader, Role};
+use crate::lineage::{self, VersionOrigin};
+use crate::quota;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Every model and config upload becomes an immutable, numbered version under a task.
+//A version starts as a draft that accepts chunks, and is sealed by `commit_model_version`.
+//The production pointer of a task is moved by `promote` and restored by `rollback`.
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionStatus {
+    Draft,
+    Committed,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Model,
+    Config,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ModelVersion {
+    pub task: String,
+    pub version: u64,
+    pub status: VersionStatus,
+    pub model_size: u64,
+    pub config_size: u64,
+    pub created_at: u64,
+    pub created_by: Principal,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TaskState {
+    pub latest_version: u64,
+    pub production: Option<u64>,
+    // Previously promoted versions, the most recent one last.
+    pub history: Vec<u64>,
+}
+
+impl_storable_json!(ModelVersion);
+impl_storable_json!(TaskState);
+
+/// Everything the registry keeps in stable memory, used by snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RegistryState {
+    pub versions: Vec<ModelVersion>,
+    pub tasks: Vec<(String, TaskState)>,
+}
+
+/// Weights and config of the version that serves a task.
+/// `version` is 0 when the task has no production version and the legacy upload keys are used.
+pub struct ResolvedModel {
+    pub version: u64,
+    pub weights: Vec<u8>,
+    pub config: Vec<u8>,
+}
+
+thread_local! {
+    static VERSIONS: RefCell<StableBTreeMap<String, ModelVersion, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::REGISTRY_VERSIONS_MEMORY_ID))
+    );
+
+    static TASKS: RefCell<StableBTreeMap<String, TaskState, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::REGISTRY_TASKS_MEMORY_ID))
+    );
+}
+
+fn version_key(task: &str, version: u64) -> String {
+    format!("{}/{:020}", task, version)
+}
+
+pub fn artifact_key(task: &str, version: u64, kind: ArtifactKind) -> String {
+    let file = match kind {
+        ArtifactKind::Model => "model.safetensors",
+        ArtifactKind::Config => "config.json",
+    };
+    format!("{}{}/v{}/{}", storage::REGISTRY_PREFIX, task, version, file)
+}
+
+fn validate_task(task: &str) -> Result<(), String> {
+    if task.is_empty() || task.contains('/') {
+        return Err(format!("Invalid task name: {:?}", task));
+    }
+    Ok(())
+}
+
+pub fn export_state() -> RegistryState {
+    RegistryState {
+        versions: VERSIONS.with(|v| v.borrow().iter().map(|(_, version)| version).collect()),
+        tasks: TASKS.with(|t| t.borrow().iter().collect()),
+    }
+}
+
+/// Replaces the whole registry. The artifacts themselves are restored by the caller.
+pub fn replace_state(state: RegistryState) {
+    VERSIONS.with(|v| {
+        let mut v = v.borrow_mut();
+        let keys: Vec<String> = v.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            v.remove(&key);
+        }
+        for version in state.versions {
+            v.insert(version_key(&version.task, version.version), version);
+        }
+    });
+    TASKS.with(|t| {
+        let mut t = t.borrow_mut();
+        let keys: Vec<String> = t.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            t.remove(&key);
+        }
+        for (task, state) in state.tasks {
+            t.insert(task, state);
+        }
+    });
+}
+
+pub fn tasks() -> Vec<String> {
+    TASKS.with(|t| t.borrow().iter().map(|(task, _)| task).collect())
+}
+
+pub fn task_state(task: &str) -> TaskState {
+    TASKS.with(|t| t.borrow().get(&task.to_string()).unwrap_or_default())
+}
+
+pub fn get_version(task: &str, version: u64) -> Option<ModelVersion> {
+    VERSIONS.with(|v| v.borrow().get(&version_key(task, version)))
+}
+
+pub fn versions(task: &str) -> Vec<ModelVersion> {
+    let prefix = format!("{}/", task);
+    VERSIONS.with(|v| {
+        v.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, version)| version)
+            .collect()
+    })
+}
+
+/// Opens a new draft version of `task` and returns its number.
+pub fn create_version(task: &str, caller: Principal, now: u64) -> Result<u64, String> {
+    validate_task(task)?;
+    let mut state = task_state(task);
+    state.latest_version += 1;
+    let version = state.latest_version;
+    VERSIONS.with(|v| {
+        v.borrow_mut().insert(
+            version_key(task, version),
+            ModelVersion {
+                task: task.to_string(),
+                version,
+                status: VersionStatus::Draft,
+                model_size: 0,
+                config_size: 0,
+                created_at: now,
+                created_by: caller,
+            },
+        )
+    });
+    TASKS.with(|t| t.borrow_mut().insert(task.to_string(), state));
+    Ok(version)
+}
+
+/// Appends a chunk of the model or config to a draft version.
+pub fn append_artifact(task: &str, version: u64, kind: ArtifactKind, bytes: Vec<u8>) -> Result<(), String> {
+    let mut entry = get_version(task, version)
+        .ok_or_else(|| format!("Version {} of {} does not exist.", version, task))?;
+    if entry.status != VersionStatus::Draft {
+        return Err(format!("Version {} of {} is committed and immutable.", version, task));
+    }
+    let added = bytes.len() as u64;
+    storage::append_raw(artifact_key(task, version, kind), bytes);
+    match kind {
+        ArtifactKind::Model => entry.model_size += added,
+        ArtifactKind::Config => entry.config_size += added,
+    }
+    VERSIONS.with(|v| v.borrow_mut().insert(version_key(task, version), entry));
+    Ok(())
+}
+
+/// Seals a draft version after checking that its weights and config can be decoded.
+pub fn commit_version(task: &str, version: u64) -> Result<ModelVersion, String> {
+    let mut entry = get_version(task, version)
+        .ok_or_else(|| format!("Version {} of {} does not exist.", version, task))?;
+    if entry.status != VersionStatus::Draft {
+        return Err(format!("Version {} of {} is already committed.", version, task));
+    }
+
+    let weights = storage::bytes(artifact_key(task, version, ArtifactKind::Model));
+    if weights.is_empty() {
+        return Err("Model weights have not been uploaded.".to_string());
+    }
+    candle_core::safetensors::load_buffer(&weights, &Device::Cpu)
+        .map_err(|e| format!("Failed to load weights: {:?}", e))?;
+
+    let config = storage::bytes(artifact_key(task, version, ArtifactKind::Config));
+    if config.is_empty() {
+        return Err("Model config has not been uploaded.".to_string());
+    }
+    serde_json::from_slice::<serde_json::Value>(&config)
+        .map_err(|e| format!("Failed to deserialize model config: {:?}", e))?;
+
+    // Committed artifacts never change again, so they can be stored compressed.
+    storage::compact(&artifact_key(task, version, ArtifactKind::Model));
+    storage::compact(&artifact_key(task, version, ArtifactKind::Config));
+
+    entry.status = VersionStatus::Committed;
+    VERSIONS.with(|v| v.borrow_mut().insert(version_key(task, version), entry.clone()));
+    Ok(entry)
+}
+
+/// Stores a complete model as a new committed version in one step, recording that it was
+/// derived from `parent` by `origin`.
+pub fn publish(
+    task: &str,
+    weights: Vec<u8>,
+    config: Vec<u8>,
+    caller: Principal,
+    parent: Option<u64>,
+    origin: VersionOrigin,
+    now: u64,
+) -> Result<u64, String> {
+    let version = create_version(task, caller, now)?;
+    append_artifact(task, version, ArtifactKind::Model, weights)?;
+    append_artifact(task, version, ArtifactKind::Config, config)?;
+    commit_version(task, version)?;
+    lineage::record(task, version, parent, origin, now);
+    Ok(version)
+}
+
+/// Deletes a version that is neither in production nor a rollback target, returning the freed bytes.
+pub fn remove_version(task: &str, version: u64) -> Result<u64, String> {
+    let state = task_state(task);
+    if state.production == Some(version) || state.history.contains(&version) {
+        return Err(format!("Version {} of {} is still referenced by production.", version, task));
+    }
+    if get_version(task, version).is_none() {
+        return Err(format!("Version {} of {} does not exist.", version, task));
+    }
+    let mut freed = 0;
+    for kind in [ArtifactKind::Model, ArtifactKind::Config] {
+        let key = artifact_key(task, version, kind);
+        freed += storage::len(&key);
+        storage::remove_raw(&key);
+    }
+    VERSIONS.with(|v| v.borrow_mut().remove(&version_key(task, version)));
+    Ok(freed)
+}
+
+/// Points production at `version`, remembering the previous one for `rollback_task`.
+pub fn promote_version(task: &str, version: u64) -> Result<(), String> {
+    let entry = get_version(task, version)
+        .ok_or_else(|| format!("Version {} of {} does not exist.", version, task))?;
+    if entry.status != VersionStatus::Committed {
+        return Err(format!("Version {} of {} is still a draft.", version, task));
+    }
+    let mut state = task_state(task);
+    if state.production == Some(version) {
+        return Ok(());
+    }
+    if let Some(previous) = state.production.replace(version) {
+        state.history.push(previous);
+    }
+    TASKS.with(|t| t.borrow_mut().insert(task.to_string(), state));
+    Ok(())
+}
+
+/// Restores the previously promoted version and returns it.
+pub fn rollback_task(task: &str) -> Result<u64, String> {
+    let mut state = task_state(task);
+    let previous = state
+        .history
+        .pop()
+        .ok_or_else(|| format!("No earlier production version of {} to roll back to.", task))?;
+    state.production = Some(previous);
+    TASKS.with(|t| t.borrow_mut().insert(task.to_string(), state));
+    Ok(previous)
+}
+
+/// Loads the production version of `task`, or the legacy upload keys when nothing was promoted yet.
+pub fn resolve(task: &str, legacy_model_key: &str, legacy_config_key: &str) -> ResolvedModel {
+    match task_state(task).production {
+        Some(version) => ResolvedModel {
+            version,
+            weights: storage::bytes(artifact_key(task, version, ArtifactKind::Model)),
+            config: storage::bytes(artifact_key(task, version, ArtifactKind::Config)),
+        },
+        None => ResolvedModel {
+            version: 0,
+            weights: storage::bytes(legacy_model_key.to_string()),
+            config: storage::bytes(legacy_config_key.to_string()),
+        },
+    }
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn create_model_version(task: String) -> Result<u64, String> {
+    create_version(&task, ic_cdk::caller(), ic_cdk::api::time())
+}
+
+/// Fails unless `caller` created the draft `version` of `task`. Controllers may finish any draft.
+fn check_creator(task: &str, version: u64, caller: &Principal) -> Result<(), String> {
+    let entry = get_version(task, version)
+        .ok_or_else(|| format!("Version {} of {} does not exist.", version, task))?;
+    if entry.created_by != *caller && access::role_of(caller) != Role::Admin {
+        return Err(format!("Version {} of {} was created by {}.", version, task, entry.created_by));
+    }
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn append_model_version_bytes(task: String, version: u64, kind: ArtifactKind, bytes: Vec<u8>) -> Result<(), String> {
+    check_creator(&task, version, &ic_cdk::caller())?;
+    quota::check(&ic_cdk::caller(), bytes.len() as u64)?;
+    append_artifact(&task, version, kind, bytes)
+}
+
+#[ic_cdk::update(guard = "is_uploader")]
+pub fn commit_model_version(task: String, version: u64) -> Result<ModelVersion, String> {
+    check_creator(&task, version, &ic_cdk::caller())?;
+    let entry = commit_version(&task, version)?;
+    let origin = VersionOrigin::Upload { uploader: entry.created_by };
+    lineage::record(&task, version, None, origin, ic_cdk::api::time());
+    Ok(entry)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn promote(task: String, version: u64) -> Result<(), String> {
+    promote_version(&task, version)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn rollback(task: String) -> Result<u64, String> {
+    rollback_task(&task)
+}
+
+#[ic_cdk::query]
+pub fn list_model_versions(task: String) -> Vec<ModelVersion> {
+    versions(&task)
+}
+
+#[ic_cdk::query]
+pub fn get_task_state(task: String) -> TaskState {
+    task_state(&task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::test_checkpoint;
+
+    fn weights(value: f32) -> Vec<u8> {
+        test_checkpoint(&[value]).to_safetensors().unwrap()
+    }
+
+    fn upload(task: &str, model: Vec<u8>, config: &[u8]) -> u64 {
+        let version = create_version(task, Principal::anonymous(), 0).unwrap();
+        append_artifact(task, version, ArtifactKind::Model, model).unwrap();
+        append_artifact(task, version, ArtifactKind::Config, config.to_vec()).unwrap();
+        version
+    }
+
+    #[test]
+    fn drafts_are_sealed_on_commit() {
+        assert!(create_version("bad/task", Principal::anonymous(), 0).is_err());
+        let empty = create_version("registry-test", Principal::anonymous(), 0).unwrap();
+        assert!(commit_version("registry-test", empty).is_err());
+
+        let broken = upload("registry-test", weights(1.0), b"not json");
+        assert!(commit_version("registry-test", broken).is_err());
+
+        let version = upload("registry-test", weights(1.0), b"{}");
+        assert!(promote_version("registry-test", version).is_err());
+        assert_eq!(commit_version("registry-test", version).unwrap().status, VersionStatus::Committed);
+        assert!(commit_version("registry-test", version).is_err());
+        assert!(append_artifact("registry-test", version, ArtifactKind::Model, vec![1]).is_err());
+        assert_eq!(task_state("registry-test").latest_version, 3);
+    }
+
+    #[test]
+    fn only_the_creator_may_finish_a_draft() {
+        let creator = Principal::from_slice(&[7]);
+        let version = create_version("registry-test", creator, 0).unwrap();
+        assert!(check_creator("registry-test", version, &creator).is_ok());
+        assert!(check_creator("registry-test", version, &Principal::from_slice(&[8])).is_err());
+        assert!(check_creator("registry-test", version + 1, &creator).is_err());
+    }
+
+    #[test]
+    fn production_falls_back_to_the_legacy_keys_and_rolls_back() {
+        storage::insert_raw("legacy-model".to_string(), weights(0.0));
+        storage::insert_raw("legacy-config".to_string(), b"{}".to_vec());
+        let legacy = resolve("registry-test", "legacy-model", "legacy-config");
+        assert_eq!((legacy.version, legacy.weights), (0, weights(0.0)));
+        assert!(rollback_task("registry-test").is_err());
+
+        let first = upload("registry-test", weights(1.0), b"{}");
+        commit_version("registry-test", first).unwrap();
+        promote_version("registry-test", first).unwrap();
+        let origin = VersionOrigin::Upload { uploader: Principal::anonymous() };
+        let second = publish("registry-test", weights(2.0), b"{}".to_vec(), Principal::anonymous(), Some(first), origin, 0).unwrap();
+        promote_version("registry-test", second).unwrap();
+        let served = resolve("registry-test", "legacy-model", "legacy-config");
+        assert_eq!((served.version, served.weights), (second, weights(2.0)));
+        assert_eq!(task_state("registry-test").history, vec![first]);
+        assert!(remove_version("registry-test", first).is_err());
+
+        assert_eq!(rollback_task("registry-test").unwrap(), first);
+        assert_eq!(resolve("registry-test", "legacy-model", "legacy-config").weights, weights(1.0));
+        assert!(rollback_task("registry-test").is_err());
+        assert!(remove_version("registry-test", second).unwrap() > 0);
+        assert!(get_version("registry-test", second).is_none());
+    }
+}
@@ -0,0 +1,203 @@
+use candid::CandidType;
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::StableCell;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::access::is_controller;
+use crate::agent;
+use crate::evaluation;
+use crate::finetune;
+use crate::incentives;
+use crate::registry::{self, VersionStatus};
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Retention policy applied by the periodic garbage collection job.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionPolicy {
+    // Superseded versions kept per task, on top of production and its rollback targets.
+    pub keep_versions: u64,
+    // Draft versions and staged updates older than this are abandoned upload sessions.
+    pub draft_ttl_seconds: u64,
+    pub interval_seconds: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            keep_versions: 5,
+            draft_ttl_seconds: 24 * 60 * 60,
+            interval_seconds: 60 * 60,
+        }
+    }
+}
+
+impl_storable_json!(RetentionPolicy);
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct GcReport {
+    pub pruned_versions: Vec<(String, u64)>,
+    pub expired_drafts: Vec<(String, u64)>,
+    // Keys of updates that were staged but never submitted.
+    #[serde(default)]
+    pub expired_uploads: Vec<String>,
+    pub freed_bytes: u64,
+}
+
+thread_local! {
+    static RETENTION_POLICY: RefCell<StableCell<RetentionPolicy, Memory>> = RefCell::new(
+        StableCell::init(storage::memory(storage::RETENTION_MEMORY_ID), RetentionPolicy::default())
+            .expect("failed to init RETENTION_POLICY cell")
+    );
+
+    static GC_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+pub fn policy() -> RetentionPolicy {
+    RETENTION_POLICY.with(|p| p.borrow().get().clone())
+}
+
+/// Versions of `task` that are still read: those delta updates to the open round may be
+/// encoded against, the bases of its buffered updates, of a running fine-tuning job and of
+/// contribution scoring, and the version under evaluation.
+fn versions_in_use(task: &str) -> Vec<u64> {
+    let mut versions = Vec::new();
+    if let Some(round) = agent::open_round(task) {
+        let oldest = round.base_version.saturating_sub(agent::max_staleness(&round));
+        versions.extend(oldest..=round.base_version);
+        versions.extend(round.participants.iter().map(|p| p.model_version));
+    }
+    versions.extend(finetune::job(task).filter(finetune::running).map(|job| job.base_version));
+    versions.extend(evaluation::open_evaluation(task).map(|round| round.version));
+    versions.extend(incentives::scoring_jobs().into_iter().filter(|job| job.task == task).map(|job| job.base_version));
+    versions
+}
+
+/// Whether `key` holds an update staged by `append_update_bytes`, `updates/<task>/<principal>`.
+/// Submissions kept until their round closes live under `updates/<task>/round/`.
+fn is_staged_update(key: &str) -> bool {
+    key.strip_prefix(storage::UPDATE_PREFIX).is_some_and(|rest| rest.split('/').count() == 2)
+}
+
+/// Applies the retention policy at time `now` (nanoseconds).
+pub fn collect_garbage(now: u64) -> GcReport {
+    let policy = policy();
+    let mut report = GcReport::default();
+    let ttl = policy.draft_ttl_seconds.saturating_mul(storage::NANOS_PER_SECOND);
+
+    for (key, meta) in storage::artifacts() {
+        if is_staged_update(&key) && now.saturating_sub(meta.updated_at) > ttl {
+            storage::remove_raw(&key);
+            report.freed_bytes += meta.size;
+            report.expired_uploads.push(key);
+        }
+    }
+
+    for task in registry::tasks() {
+        let state = registry::task_state(&task);
+        let versions = registry::versions(&task);
+        let in_use = versions_in_use(&task);
+
+        let expired: Vec<u64> = versions
+            .iter()
+            .filter(|v| v.status == VersionStatus::Draft && now.saturating_sub(v.created_at) > ttl)
+            .map(|v| v.version)
+            .collect();
+
+        // Committed versions older than production that are neither rollback targets nor in
+        // use, oldest first.
+        let superseded: Vec<u64> = versions
+            .iter()
+            .filter(|v| v.status == VersionStatus::Committed)
+            .filter(|v| state.production.is_some_and(|p| v.version < p))
+            .filter(|v| !state.history.contains(&v.version) && !in_use.contains(&v.version))
+            .map(|v| v.version)
+            .collect();
+        let prune = superseded.len().saturating_sub(policy.keep_versions as usize);
+
+        for version in expired {
+            if let Ok(freed) = registry::remove_version(&task, version) {
+                report.freed_bytes += freed;
+                report.expired_drafts.push((task.clone(), version));
+            }
+        }
+        for &version in &superseded[..prune] {
+            if let Ok(freed) = registry::remove_version(&task, version) {
+                report.freed_bytes += freed;
+                report.pruned_versions.push((task.clone(), version));
+            }
+        }
+    }
+    report
+}
+
+/// (Re)starts the periodic garbage collection job. Timers do not survive upgrades,
+/// so this runs from `init` and `post_upgrade`.
+pub fn schedule_gc() {
+    let interval = Duration::from_secs(policy().interval_seconds.max(60));
+    GC_TIMER.with(|t| {
+        if let Some(timer) = t.borrow_mut().take() {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        let timer = ic_cdk_timers::set_timer_interval(interval, || {
+            let report = collect_garbage(ic_cdk::api::time());
+            ic_cdk::println!(
+                "Garbage collection freed {} bytes ({} pruned versions, {} expired drafts, {} expired uploads)",
+                report.freed_bytes,
+                report.pruned_versions.len(),
+                report.expired_drafts.len(),
+                report.expired_uploads.len()
+            );
+        });
+        *t.borrow_mut() = Some(timer);
+    });
+}
+
+/// Stores `policy` without rescheduling the collection, which the caller has to do.
+pub fn store_policy(policy: RetentionPolicy) -> Result<(), String> {
+    RETENTION_POLICY.with(|p| p.borrow_mut().set(policy))
+        .map_err(|e| format!("Failed to store retention policy: {:?}", e))?;
+    Ok(())
+}
+
+pub fn set_policy(policy: RetentionPolicy) -> Result<(), String> {
+    store_policy(policy)?;
+    schedule_gc();
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn set_retention_policy(policy: RetentionPolicy) -> Result<(), String> {
+    set_policy(policy)
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn get_retention_policy() -> RetentionPolicy {
+    policy()
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn run_garbage_collection() -> GcReport {
+    collect_garbage(ic_cdk::api::time())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_updates_expire_but_round_submissions_stay() {
+        let staged = format!("{}malaria/aaaaa-aa", storage::UPDATE_PREFIX);
+        let submitted = format!("{}malaria/round/1/aaaaa-aa", storage::UPDATE_PREFIX);
+        storage::insert_raw(staged.clone(), vec![1; 10]);
+        storage::insert_raw(submitted.clone(), vec![1; 10]);
+
+        let ttl = policy().draft_ttl_seconds * storage::NANOS_PER_SECOND;
+        assert!(collect_garbage(ttl).expired_uploads.is_empty());
+        let report = collect_garbage(ttl + 1);
+        assert_eq!(report.expired_uploads, vec![staged.clone()]);
+        assert_eq!(storage::len(&staged), 0);
+        assert_eq!(storage::len(&submitted), 10);
+    }
+}
@@ -0,0 +1,37 @@
+use sha2::{Digest, Sha256};
+
+//Deterministic pseudo-random numbers for coordinator decisions such as client selection.
+//Seeds are derived from canister state, so the draws are reproducible but not secret.
+pub struct Rng(u64);
+
+impl Rng {
+    /// Seeds the generator with the first 8 bytes of the SHA-256 of `seed`.
+    pub fn from_seed(seed: &[u8]) -> Self {
+        let digest = Sha256::digest(seed);
+        let mut state = [0u8; 8];
+        state.copy_from_slice(&digest[..8]);
+        Rng(u64::from_le_bytes(state))
+    }
+
+    // SplitMix64.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Fisher-Yates shuffle.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next_u64() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
@@ -0,0 +1,676 @@
+use candid::{CandidType, Principal};
+use hkdf::Hkdf;
+use ic_stable_structures::StableBTreeMap;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sharks::{Share, Sharks};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::access::{is_controller, is_participant};
+use crate::agent;
+use crate::storage::{self, impl_storable_json, Memory};
+
+//Secure aggregation after Bonawitz et al., "Practical Secure Aggregation for
+//Privacy-Preserving Machine Learning". Clients upload their weighted update in fixed point,
+//masked with a self mask and with pairwise masks that cancel in the sum. Both mask secrets
+//are Shamir-shared among the other clients, so the canister can remove the self masks of
+//the survivors and the pairwise masks of clients that dropped out, but never both for the
+//same client. It only ever learns the sum of the updates.
+//
+//Phases of a secure round:
+//1. AdvertiseKeys: invited clients post two X25519 public keys, one to encrypt shares and
+//   one to agree on pairwise masks.
+//2. ShareKeys: every client shares its self-mask seed and its mask secret key among the
+//   others, each share encrypted for its recipient.
+//3. MaskedInput: clients submit their masked update with `submit_update`.
+//4. Unmasking: surviving clients reveal the shares they hold, after which the round closes.
+//A phase ends once every client expected in it acted, or when a controller advances it.
+
+// Clients upload round(value * num_samples * 2^FRACTION_BITS) modulo 2^64 per parameter.
+pub const FRACTION_BITS: u32 = 24;
+const MASK_INFO: &str = "medaiml-secagg/mask";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SecureAggregationConfig {
+    // Share of the clients holding keys needed to unmask, at least 2.
+    pub threshold_fraction: f64,
+}
+
+impl SecureAggregationConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.threshold_fraction > 0.0 && self.threshold_fraction <= 1.0) {
+            return Err("The threshold fraction must lie in (0, 1].".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for SecureAggregationConfig {
+    fn default() -> Self {
+        SecureAggregationConfig { threshold_fraction: 0.5 }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SecAggPhase {
+    AdvertiseKeys,
+    ShareKeys,
+    MaskedInput,
+    Unmasking,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaskKeys {
+    pub cipher_public_key: Vec<u8>,
+    pub mask_public_key: Vec<u8>,
+}
+
+/// A share of `owner`'s secrets, readable only by `recipient`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptedShare {
+    pub owner: Principal,
+    pub recipient: Principal,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShareKind {
+    // Revealed for clients whose masked update arrived.
+    SelfSeed,
+    // Revealed for clients that dropped out after sharing.
+    MaskKey,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevealedShare {
+    pub owner: Principal,
+    pub kind: ShareKind,
+    pub share: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SecAggSession {
+    pub task: String,
+    pub round: u64,
+    pub threshold_fraction: f64,
+    pub phase: SecAggPhase,
+    // Shares needed to recover a secret, fixed once the keys are in.
+    pub threshold: u64,
+    pub invited: Vec<Principal>,
+    pub keys: Vec<(Principal, MaskKeys)>,
+    pub shares: Vec<EncryptedShare>,
+    pub share_senders: Vec<Principal>,
+    pub submitted: Vec<Principal>,
+    // Clients whose masked update is part of the sum, fixed when unmasking starts.
+    pub survivors: Vec<Principal>,
+    pub reveals: Vec<(Principal, Vec<RevealedShare>)>,
+}
+
+impl_storable_json!(SecAggSession);
+
+/// Everything about a session except the encrypted shares.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SecAggView {
+    pub round: u64,
+    pub phase: SecAggPhase,
+    pub threshold: u64,
+    pub keys: Vec<(Principal, MaskKeys)>,
+    pub share_senders: Vec<Principal>,
+    pub submitted: Vec<Principal>,
+    pub survivors: Vec<Principal>,
+    pub revealed: Vec<Principal>,
+}
+
+thread_local! {
+    static SESSIONS: RefCell<StableBTreeMap<String, SecAggSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(storage::memory(storage::SECURE_AGGREGATION_MEMORY_ID))
+    );
+}
+
+pub(crate) fn export_sessions() -> Vec<(String, SecAggSession)> {
+    SESSIONS.with(storage::entries)
+}
+
+/// Replaces every secure aggregation session, used when a snapshot is restored.
+pub(crate) fn replace_sessions(sessions: Vec<(String, SecAggSession)>) {
+    SESSIONS.with(|m| storage::replace_entries(m, sessions))
+}
+
+fn key_bytes(bytes: &[u8]) -> Result<[u8; 32], String> {
+    bytes.try_into().map_err(|_| "X25519 keys are 32 bytes long.".to_string())
+}
+
+fn derive(secret: &StaticSecret, peer_public: &[u8; 32], info: &str, task: &str, round: u64) -> [u8; 32] {
+    let shared = secret.diffie_hellman(&PublicKey::from(*peer_public));
+    let mut out = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared.as_bytes())
+        .expand(format!("{}/{}/{}", info, task, round).as_bytes(), &mut out)
+        .expect("32 bytes is a valid HKDF output length");
+    out
+}
+
+/// Seed of the pairwise mask between the owner of `secret` and the owner of `peer_public`.
+/// Both sides derive the same seed.
+pub fn pair_seed(secret: &StaticSecret, peer_public: &[u8; 32], task: &str, round: u64) -> [u8; 32] {
+    derive(secret, peer_public, MASK_INFO, task, round)
+}
+
+/// Adds (or subtracts) the mask expanded from `seed` to `values`, modulo 2^64.
+pub fn apply_mask(values: &mut [u64], seed: &[u8; 32], add: bool) {
+    let mut rng = ChaCha20Rng::from_seed(*seed);
+    for value in values.iter_mut() {
+        let mask = rng.next_u64();
+        *value = if add { value.wrapping_add(mask) } else { value.wrapping_sub(mask) };
+    }
+}
+
+/// The client with the smaller principal adds the pairwise mask, the other subtracts it.
+pub fn adds_pair_mask(own: &Principal, peer: &Principal) -> bool {
+    own < peer
+}
+
+/// Weighted mean from the unmasked sum of `encode_fixed` values.
+pub fn decode_mean(sum: u64, total_weight: u64) -> f32 {
+    (sum as i64 as f64 / (1u64 << FRACTION_BITS) as f64 / total_weight as f64) as f32
+}
+
+fn recover(threshold: u64, shares: &[&Vec<u8>]) -> Result<[u8; 32], String> {
+    // Interpolation breaks on repeated x coordinates.
+    let mut distinct: BTreeMap<u8, Share> = BTreeMap::new();
+    for bytes in shares {
+        let share = Share::try_from(bytes.as_slice()).map_err(|e| format!("Malformed share: {}", e))?;
+        distinct.entry(bytes[0]).or_insert(share);
+    }
+    let secret = Sharks(threshold as u8)
+        .recover(distinct.values())
+        .map_err(|e| e.to_string())?;
+    key_bytes(&secret)
+}
+
+impl SecAggSession {
+    pub fn new(task: &str, round: u64, invited: Vec<Principal>, config: &SecureAggregationConfig) -> Self {
+        SecAggSession {
+            task: task.to_string(),
+            round,
+            threshold_fraction: config.threshold_fraction,
+            phase: SecAggPhase::AdvertiseKeys,
+            threshold: 0,
+            invited,
+            keys: Vec::new(),
+            shares: Vec::new(),
+            share_senders: Vec::new(),
+            submitted: Vec::new(),
+            survivors: Vec::new(),
+            reveals: Vec::new(),
+        }
+    }
+
+    pub fn view(&self) -> SecAggView {
+        SecAggView {
+            round: self.round,
+            phase: self.phase,
+            threshold: self.threshold,
+            keys: self.keys.clone(),
+            share_senders: self.share_senders.clone(),
+            submitted: self.submitted.clone(),
+            survivors: self.survivors.clone(),
+            revealed: self.reveals.iter().map(|(p, _)| *p).collect(),
+        }
+    }
+
+    fn expect_phase(&self, phase: SecAggPhase) -> Result<(), String> {
+        if self.phase != phase {
+            return Err(format!("Secure aggregation is in phase {:?}, not {:?}.", self.phase, phase));
+        }
+        Ok(())
+    }
+
+    fn mask_key(&self, principal: &Principal) -> Option<[u8; 32]> {
+        self.keys
+            .iter()
+            .find(|(p, _)| p == principal)
+            .and_then(|(_, keys)| key_bytes(&keys.mask_public_key).ok())
+    }
+
+    /// Moves on to the next phase with whoever acted in the current one.
+    pub fn advance(&mut self) -> Result<(), String> {
+        let (acted, next) = match self.phase {
+            SecAggPhase::AdvertiseKeys => {
+                let threshold = (self.threshold_fraction * self.keys.len() as f64).ceil() as u64;
+                self.threshold = threshold.clamp(2, 255);
+                (self.keys.len(), SecAggPhase::ShareKeys)
+            }
+            SecAggPhase::ShareKeys => (self.share_senders.len(), SecAggPhase::MaskedInput),
+            SecAggPhase::MaskedInput => {
+                self.survivors = self.submitted.clone();
+                (self.submitted.len(), SecAggPhase::Unmasking)
+            }
+            SecAggPhase::Unmasking => return Err("Secure aggregation is already unmasking.".to_string()),
+        };
+        if (acted as u64) < self.threshold {
+            return Err(format!(
+                "Only {} clients took part in phase {:?}, {} are needed.",
+                acted, self.phase, self.threshold
+            ));
+        }
+        self.phase = next;
+        Ok(())
+    }
+
+    pub fn advertise(&mut self, caller: Principal, keys: MaskKeys) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::AdvertiseKeys)?;
+        if !self.invited.contains(&caller) {
+            return Err(format!("{} is not invited to this round.", caller));
+        }
+        if self.keys.iter().any(|(p, _)| *p == caller) {
+            return Err(format!("{} already advertised its keys.", caller));
+        }
+        key_bytes(&keys.cipher_public_key)?;
+        key_bytes(&keys.mask_public_key)?;
+        self.keys.push((caller, keys));
+        if self.keys.len() == self.invited.len() {
+            self.advance()?;
+        }
+        Ok(())
+    }
+
+    pub fn upload_shares(&mut self, caller: Principal, shares: Vec<EncryptedShare>) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::ShareKeys)?;
+        if self.mask_key(&caller).is_none() {
+            return Err(format!("{} did not advertise keys for this round.", caller));
+        }
+        if self.share_senders.contains(&caller) {
+            return Err(format!("{} already uploaded its shares.", caller));
+        }
+        let mut recipients: Vec<Principal> = shares.iter().map(|s| s.recipient).collect();
+        recipients.sort();
+        let expected: Vec<Principal> = self.keys.iter().map(|(p, _)| *p).filter(|p| *p != caller).collect();
+        let mut expected_sorted = expected.clone();
+        expected_sorted.sort();
+        if shares.iter().any(|s| s.owner != caller) || recipients != expected_sorted {
+            return Err("Exactly one share for every other client holding keys is required.".to_string());
+        }
+        self.shares.extend(shares);
+        self.share_senders.push(caller);
+        if self.share_senders.len() == self.keys.len() {
+            self.advance()?;
+        }
+        Ok(())
+    }
+
+    /// Records that `caller` submitted its masked update.
+    pub fn record_submission(&mut self, caller: Principal) -> Result<(), String> {
+        self.expect_phase(SecAggPhase::MaskedInput)?;
+        if !self.share_senders.contains(&caller) {
+            return Err(format!("{} did not share its keys for this round.", caller));
+        }
+        if self.submitted.contains(&caller) {
+            return Err(format!("{} already submitted a masked update.", caller));
+        }
+        self.submitted.push(caller);
+        if self.submitted.len() == self.share_senders.len() {
+            self.advance()?;
+        }
+        Ok(())
+    }
+
+    /// Stores the shares `caller` reveals. Returns true once every survivor revealed.
+    pub fn reveal(&mut self, caller: Principal, reveals: Vec<RevealedShare>) -> Result<bool, String> {
+        self.expect_phase(SecAggPhase::Unmasking)?;
+        if !self.survivors.contains(&caller) {
+            return Err(format!("{} is not a survivor of this round.", caller));
+        }
+        if self.reveals.iter().any(|(p, _)| *p == caller) {
+            return Err(format!("{} already revealed its shares.", caller));
+        }
+        let owners: Vec<Principal> = self.share_senders.iter().copied().filter(|p| *p != caller).collect();
+        if reveals.len() != owners.len() {
+            return Err(format!("Expected {} revealed shares, got {}.", owners.len(), reveals.len()));
+        }
+        for owner in &owners {
+            let expected = if self.survivors.contains(owner) { ShareKind::SelfSeed } else { ShareKind::MaskKey };
+            let revealed = reveals
+                .iter()
+                .find(|r| r.owner == *owner)
+                .ok_or_else(|| format!("No share of {} was revealed.", owner))?;
+            // Revealing both secrets of one client would expose its update.
+            if revealed.kind != expected {
+                return Err(format!("The share of {} must be of kind {:?}.", owner, expected));
+            }
+        }
+        self.reveals.push((caller, reveals));
+        Ok(self.reveals.len() == self.survivors.len())
+    }
+
+    fn revealed(&self, owner: &Principal, kind: ShareKind) -> Vec<&Vec<u8>> {
+        self.reveals
+            .iter()
+            .flat_map(|(_, reveals)| reveals.iter())
+            .filter(|r| r.owner == *owner && r.kind == kind)
+            .map(|r| &r.share)
+            .collect()
+    }
+
+    /// Removes all masks from the sum of the survivors' `masked` updates.
+    pub fn unmask(&self, masked: &[(Principal, Vec<u64>)]) -> Result<Vec<u64>, String> {
+        self.expect_phase(SecAggPhase::Unmasking)?;
+        if (self.reveals.len() as u64) < self.threshold {
+            return Err(format!(
+                "Only {} of the {} survivors needed revealed their shares.",
+                self.reveals.len(),
+                self.threshold
+            ));
+        }
+        let len = masked.first().map(|(_, values)| values.len()).unwrap_or(0);
+        let mut sum = vec![0u64; len];
+        for survivor in &self.survivors {
+            let (_, values) = masked
+                .iter()
+                .find(|(p, _)| p == survivor)
+                .ok_or_else(|| format!("The masked update of {} is missing.", survivor))?;
+            if values.len() != len {
+                return Err("Masked updates differ in length.".to_string());
+            }
+            sum.iter_mut().zip(values).for_each(|(s, v)| *s = s.wrapping_add(*v));
+        }
+
+        for survivor in &self.survivors {
+            let seed = recover(self.threshold, &self.revealed(survivor, ShareKind::SelfSeed))
+                .map_err(|e| format!("Failed to recover the self mask of {}: {}", survivor, e))?;
+            apply_mask(&mut sum, &seed, false);
+        }
+        for dropped in self.share_senders.iter().filter(|p| !self.survivors.contains(p)) {
+            let secret = StaticSecret::from(
+                recover(self.threshold, &self.revealed(dropped, ShareKind::MaskKey))
+                    .map_err(|e| format!("Failed to recover the mask key of {}: {}", dropped, e))?,
+            );
+            if Some(PublicKey::from(&secret).to_bytes()) != self.mask_key(dropped) {
+                return Err(format!("The recovered mask key of {} does not match its public key.", dropped));
+            }
+            // Undo the pairwise masks the survivors applied against the dropped client.
+            for survivor in &self.survivors {
+                let peer = self.mask_key(survivor).expect("survivors advertised keys");
+                let seed = pair_seed(&secret, &peer, &self.task, self.round);
+                apply_mask(&mut sum, &seed, !adds_pair_mask(survivor, dropped));
+            }
+        }
+        Ok(sum)
+    }
+}
+
+fn session_key(task: &str, round: u64) -> String {
+    format!("{}/{:020}", task, round)
+}
+
+pub fn open_session(session: SecAggSession) {
+    SESSIONS.with(|s| s.borrow_mut().insert(session_key(&session.task, session.round), session));
+}
+
+/// Drops the session of a closed round, the shares are of no use anymore.
+pub fn close_session(task: &str, round: u64) {
+    SESSIONS.with(|s| s.borrow_mut().remove(&session_key(task, round)));
+}
+
+pub fn session(task: &str, round: u64) -> Option<SecAggSession> {
+    SESSIONS.with(|s| s.borrow().get(&session_key(task, round)))
+}
+
+/// Runs `f` on the session of the open round of `task` and stores the result.
+fn update_session<T>(task: &str, f: impl FnOnce(&mut SecAggSession) -> Result<T, String>) -> Result<(T, SecAggSession), String> {
+    let round = agent::open_round(task).ok_or_else(|| format!("No round of {} is open.", task))?;
+    let mut session = session(task, round.round)
+        .ok_or_else(|| format!("Round {} of {} does not use secure aggregation.", round.round, task))?;
+    let result = f(&mut session)?;
+    open_session(session.clone());
+    Ok((result, session))
+}
+
+pub fn record_submission(task: &str, caller: Principal) -> Result<(), String> {
+    update_session(task, |s| s.record_submission(caller)).map(|_| ())
+}
+
+#[ic_cdk::update(guard = "is_participant")]
+pub fn advertise_mask_keys(task: String, keys: MaskKeys) -> Result<SecAggView, String> {
+    update_session(&task, |s| s.advertise(ic_cdk::caller(), keys)).map(|(_, s)| s.view())
+}
+
+#[ic_cdk::update(guard = "is_participant")]
+pub fn upload_mask_shares(task: String, shares: Vec<EncryptedShare>) -> Result<SecAggView, String> {
+    update_session(&task, |s| s.upload_shares(ic_cdk::caller(), shares)).map(|(_, s)| s.view())
+}
+
+#[ic_cdk::query(guard = "is_participant")]
+pub fn get_secure_aggregation(task: String) -> Result<SecAggView, String> {
+    let round = agent::open_round(&task).ok_or_else(|| format!("No round of {} is open.", task))?;
+    session(&task, round.round)
+        .map(|s| s.view())
+        .ok_or_else(|| format!("Round {} of {} does not use secure aggregation.", round.round, task))
+}
+
+/// The shares other clients encrypted for the caller.
+#[ic_cdk::query(guard = "is_participant")]
+pub fn get_mask_shares(task: String) -> Result<Vec<EncryptedShare>, String> {
+    let round = agent::open_round(&task).ok_or_else(|| format!("No round of {} is open.", task))?;
+    let session = session(&task, round.round)
+        .ok_or_else(|| format!("Round {} of {} does not use secure aggregation.", round.round, task))?;
+    let caller = ic_cdk::caller();
+    Ok(session.shares.into_iter().filter(|s| s.recipient == caller).collect())
+}
+
+/// Closes the round once every survivor revealed its shares.
+#[ic_cdk::update(guard = "is_participant")]
+pub fn reveal_mask_shares(task: String, reveals: Vec<RevealedShare>) -> Result<SecAggView, String> {
+    let (complete, session) = update_session(&task, |s| s.reveal(ic_cdk::caller(), reveals))?;
+    if complete {
+        agent::close_round(&task, ic_cdk::api::time())?;
+    }
+    Ok(session.view())
+}
+
+/// Ends the current phase without the clients that did not act. While unmasking,
+/// closes the round with the shares revealed so far.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn advance_secure_aggregation(task: String) -> Result<SecAggView, String> {
+    let round = agent::open_round(&task).ok_or_else(|| format!("No round of {} is open.", task))?;
+    if let Some(session) = session(&task, round.round).filter(|s| s.phase == SecAggPhase::Unmasking) {
+        agent::close_round(&task, ic_cdk::api::time())?;
+        return Ok(session.view());
+    }
+    update_session(&task, |s| s.advance()).map(|(_, s)| s.view())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chacha20poly1305::aead::{Aead, KeyInit};
+    use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+    use sha2::Digest;
+
+    const TASK: &str = "malaria";
+    const ROUND: u64 = 7;
+    const SHARE_INFO: &str = "medaiml-secagg/share";
+
+    /// Key the reference client encrypts the shares for one peer with.
+    fn share_key(secret: &StaticSecret, peer_public: &[u8; 32], task: &str, round: u64) -> [u8; 32] {
+        derive(secret, peer_public, SHARE_INFO, task, round)
+    }
+
+    /// `value * weight` in two's complement fixed point, what `decode_mean` expects.
+    fn encode_fixed(value: f32, weight: u64) -> u64 {
+        (value as f64 * weight as f64 * (1u64 << FRACTION_BITS) as f64).round() as i64 as u64
+    }
+
+    /// What a client implementation has to do, in the order of the phases.
+    struct ReferenceClient {
+        principal: Principal,
+        cipher_secret: StaticSecret,
+        mask_secret: StaticSecret,
+        self_seed: [u8; 32],
+    }
+
+    fn nonce(owner: &Principal, recipient: &Principal) -> [u8; 12] {
+        let digest = Sha256::digest([owner.as_slice(), recipient.as_slice()].concat());
+        digest[..12].try_into().unwrap()
+    }
+
+    fn peer_key(view: &SecAggView, principal: &Principal, cipher: bool) -> [u8; 32] {
+        let (_, keys) = view.keys.iter().find(|(p, _)| p == principal).unwrap();
+        key_bytes(if cipher { &keys.cipher_public_key } else { &keys.mask_public_key }).unwrap()
+    }
+
+    impl ReferenceClient {
+        fn new(id: u8) -> Self {
+            let mut rng = ChaCha20Rng::from_seed([id; 32]);
+            let mut self_seed = [0u8; 32];
+            rng.fill_bytes(&mut self_seed);
+            ReferenceClient {
+                principal: Principal::from_slice(&[id]),
+                cipher_secret: StaticSecret::random_from_rng(&mut rng),
+                mask_secret: StaticSecret::random_from_rng(&mut rng),
+                self_seed,
+            }
+        }
+
+        fn keys(&self) -> MaskKeys {
+            MaskKeys {
+                cipher_public_key: PublicKey::from(&self.cipher_secret).to_bytes().to_vec(),
+                mask_public_key: PublicKey::from(&self.mask_secret).to_bytes().to_vec(),
+            }
+        }
+
+        fn shares(&self, view: &SecAggView) -> Vec<EncryptedShare> {
+            let peers: Vec<Principal> = view.keys.iter().map(|(p, _)| *p).filter(|p| *p != self.principal).collect();
+            let mut rng = ChaCha20Rng::from_seed(self.self_seed);
+            let sharks = Sharks(view.threshold as u8);
+            let seeds: Vec<Share> = sharks.dealer_rng(&self.self_seed, &mut rng).take(peers.len()).collect();
+            let keys: Vec<Share> = sharks
+                .dealer_rng(&self.mask_secret.to_bytes(), &mut rng)
+                .take(peers.len())
+                .collect();
+            peers
+                .iter()
+                .zip(seeds.iter().zip(&keys))
+                .map(|(peer, (seed, key))| {
+                    let plaintext = candid::encode_one((Vec::from(seed), Vec::from(key))).unwrap();
+                    let aead_key = share_key(&self.cipher_secret, &peer_key(view, peer, true), TASK, ROUND);
+                    let ciphertext = ChaCha20Poly1305::new(&aead_key.into())
+                        .encrypt(Nonce::from_slice(&nonce(&self.principal, peer)), plaintext.as_slice())
+                        .unwrap();
+                    EncryptedShare { owner: self.principal, recipient: *peer, ciphertext }
+                })
+                .collect()
+        }
+
+        fn masked_update(&self, update: &[f32], weight: u64, view: &SecAggView) -> Vec<u64> {
+            let mut values: Vec<u64> = update.iter().map(|v| encode_fixed(*v, weight)).collect();
+            apply_mask(&mut values, &self.self_seed, true);
+            for peer in view.share_senders.iter().filter(|p| **p != self.principal) {
+                let seed = pair_seed(&self.mask_secret, &peer_key(view, peer, false), TASK, ROUND);
+                apply_mask(&mut values, &seed, adds_pair_mask(&self.principal, peer));
+            }
+            values
+        }
+
+        fn reveal(&self, incoming: &[EncryptedShare], view: &SecAggView) -> Vec<RevealedShare> {
+            incoming
+                .iter()
+                .filter(|s| view.share_senders.contains(&s.owner))
+                .map(|s| {
+                    let aead_key = share_key(&self.cipher_secret, &peer_key(view, &s.owner, true), TASK, ROUND);
+                    let plaintext = ChaCha20Poly1305::new(&aead_key.into())
+                        .decrypt(Nonce::from_slice(&nonce(&s.owner, &self.principal)), s.ciphertext.as_slice())
+                        .unwrap();
+                    let (seed, key): (Vec<u8>, Vec<u8>) = candid::decode_one(&plaintext).unwrap();
+                    if view.survivors.contains(&s.owner) {
+                        RevealedShare { owner: s.owner, kind: ShareKind::SelfSeed, share: seed }
+                    } else {
+                        RevealedShare { owner: s.owner, kind: ShareKind::MaskKey, share: key }
+                    }
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn masks_cancel_and_dropouts_are_recovered() {
+        let clients: Vec<ReferenceClient> = (1..=4).map(ReferenceClient::new).collect();
+        let updates = [[1.0f32, -2.0, 0.5], [3.0, 0.0, -0.5], [-1.0, 4.0, 1.5], [100.0, 100.0, 100.0]];
+        let weights = [10u64, 30, 20, 5];
+        let mut session = SecAggSession::new(
+            TASK,
+            ROUND,
+            clients.iter().map(|c| c.principal).collect(),
+            &SecureAggregationConfig::default(),
+        );
+
+        for client in &clients {
+            session.advertise(client.principal, client.keys()).unwrap();
+        }
+        assert_eq!(session.phase, SecAggPhase::ShareKeys);
+        assert_eq!(session.threshold, 2);
+
+        for client in &clients {
+            let shares = client.shares(&session.view());
+            session.upload_shares(client.principal, shares).unwrap();
+        }
+        assert_eq!(session.phase, SecAggPhase::MaskedInput);
+
+        // The last client drops out after sharing its keys.
+        let mut masked = Vec::new();
+        for (i, client) in clients.iter().enumerate().take(3) {
+            let values = client.masked_update(&updates[i], weights[i], &session.view());
+            let plain: Vec<u64> = updates[i].iter().map(|v| encode_fixed(*v, weights[i])).collect();
+            assert_ne!(values, plain);
+            session.record_submission(client.principal).unwrap();
+            masked.push((client.principal, values));
+        }
+        session.advance().unwrap();
+        assert_eq!(session.phase, SecAggPhase::Unmasking);
+
+        for client in clients.iter().take(3) {
+            let incoming: Vec<EncryptedShare> =
+                session.shares.iter().filter(|s| s.recipient == client.principal).cloned().collect();
+            let reveals = client.reveal(&incoming, &session.view());
+            session.reveal(client.principal, reveals).unwrap();
+        }
+
+        let sum = session.unmask(&masked).unwrap();
+        let total: u64 = weights[..3].iter().sum();
+        for (k, value) in sum.iter().enumerate() {
+            let expected: f32 = (0..3).map(|i| updates[i][k] * weights[i] as f32).sum::<f32>() / total as f32;
+            assert!((decode_mean(*value, total) - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn a_client_cannot_have_both_secrets_revealed() {
+        let clients: Vec<ReferenceClient> = (1..=3).map(ReferenceClient::new).collect();
+        let mut session = SecAggSession::new(
+            TASK,
+            ROUND,
+            clients.iter().map(|c| c.principal).collect(),
+            &SecureAggregationConfig::default(),
+        );
+        for client in &clients {
+            session.advertise(client.principal, client.keys()).unwrap();
+        }
+        for client in &clients {
+            let shares = client.shares(&session.view());
+            session.upload_shares(client.principal, shares).unwrap();
+        }
+        for client in &clients {
+            session.record_submission(client.principal).unwrap();
+        }
+
+        // Client 2 submitted, so only its self-mask seed may be revealed.
+        let incoming: Vec<EncryptedShare> =
+            session.shares.iter().filter(|s| s.recipient == clients[0].principal).cloned().collect();
+        let mut reveals = clients[0].reveal(&incoming, &session.view());
+        reveals[0].kind = ShareKind::MaskKey;
+        assert!(session.reveal(clients[0].principal, reveals).is_err());
+    }
+}
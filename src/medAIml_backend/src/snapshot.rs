@@ -0,0 +1,354 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::access::{self, is_controller, Role};
+use crate::agent::{self, RoundConfig, RoundStatus};
+use crate::clinics::{self, ClinicNode};
+use crate::evaluation::{self, EvaluationRound};
+use crate::finetune::{self, FineTuneJob, SampleInfo};
+use crate::hierarchy::{self, District};
+use crate::incentives::{self, RoundContributions, ScoringJob};
+use crate::lineage::{self, LineageRecord};
+use crate::optimizer::{self, StoredMoments};
+use crate::personalization::{self, HeadInfo};
+use crate::privacy::{self, PrivacyLedger};
+use crate::registry::{self, RegistryState};
+use crate::retention::{self, RetentionPolicy};
+use crate::quota::{self, QuotaConfig};
+use crate::secure_aggregation::{self, SecAggSession};
+use crate::storage::{self, ArtifactMeta, CompressionConfig};
+
+//Disaster-recovery bundles. `export_snapshot` packs all artifacts, the model registry,
+//role assignments, the storage settings, the federated training state and the lineage of
+//every version into one archive that is downloaded with `export_snapshot_chunk`. An archive
+//is uploaded back with `append_snapshot_chunk` and only replaces the canister state in
+//`import_snapshot` after its manifest checked out.
+
+// Version 2 added the training state and the lineage records.
+const FORMAT_VERSION: u32 = 2;
+
+const ARTIFACT_PREFIX: &str = "artifacts/";
+const ARTIFACT_META_ENTRY: &str = "artifact_meta";
+const REGISTRY_ENTRY: &str = "registry";
+const ROLES_ENTRY: &str = "roles";
+const SETTINGS_ENTRY: &str = "settings";
+const TRAINING_ENTRY: &str = "training";
+const LINEAGE_ENTRY: &str = "lineage";
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SnapshotManifest {
+    pub format_version: u32,
+    pub created_at: u64,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SnapshotInfo {
+    pub manifest: SnapshotManifest,
+    pub total_size: u64,
+    pub chunk_count: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SnapshotEntry {
+    name: String,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SnapshotArchive {
+    manifest: SnapshotManifest,
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Settings {
+    quota: QuotaConfig,
+    retention: RetentionPolicy,
+    compression: CompressionConfig,
+}
+
+// Every stable map of federated training, fine-tuning and evaluation.
+#[derive(Serialize, Deserialize)]
+struct TrainingState {
+    rounds: Vec<(String, RoundStatus)>,
+    round_configs: Vec<(String, RoundConfig)>,
+    nodes: Vec<(Principal, ClinicNode)>,
+    moments: Vec<(String, StoredMoments)>,
+    sessions: Vec<(String, SecAggSession)>,
+    ledgers: Vec<(String, PrivacyLedger)>,
+    heads: Vec<(String, HeadInfo)>,
+    districts: Vec<(Principal, District)>,
+    contributions: Vec<(String, RoundContributions)>,
+    scoring_jobs: Vec<(String, ScoringJob)>,
+    samples: Vec<(String, SampleInfo)>,
+    jobs: Vec<(String, FineTuneJob)>,
+    evaluations: Vec<(String, EvaluationRound)>,
+}
+
+impl TrainingState {
+    fn export() -> Self {
+        TrainingState {
+            rounds: agent::export_rounds(),
+            round_configs: agent::export_round_configs(),
+            nodes: clinics::export_nodes(),
+            moments: optimizer::export_moments(),
+            sessions: secure_aggregation::export_sessions(),
+            ledgers: privacy::export_ledgers(),
+            heads: personalization::export_heads(),
+            districts: hierarchy::export_districts(),
+            contributions: incentives::export_contributions(),
+            scoring_jobs: incentives::export_scoring_jobs(),
+            samples: finetune::export_samples(),
+            jobs: finetune::export_jobs(),
+            evaluations: evaluation::export_evaluations(),
+        }
+    }
+
+    fn replace(self) {
+        agent::replace_rounds(self.rounds);
+        agent::replace_round_configs(self.round_configs);
+        clinics::replace_nodes(self.nodes);
+        optimizer::replace_moments(self.moments);
+        secure_aggregation::replace_sessions(self.sessions);
+        privacy::replace_ledgers(self.ledgers);
+        personalization::replace_heads(self.heads);
+        hierarchy::replace_districts(self.districts);
+        incentives::replace_contributions(self.contributions);
+        incentives::replace_scoring_jobs(self.scoring_jobs);
+        finetune::replace_samples(self.samples);
+        finetune::replace_jobs(self.jobs);
+        evaluation::replace_evaluations(self.evaluations);
+    }
+}
+
+thread_local! {
+    static EXPORT_BUFFER: RefCell<Vec<u8>> = RefCell::default();
+    static IMPORT_BUFFER: RefCell<Vec<u8>> = RefCell::default();
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json_entry<T: Serialize>(name: &str, value: &T) -> SnapshotEntry {
+    SnapshotEntry {
+        name: name.to_string(),
+        data: serde_json::to_vec(value).unwrap(),
+    }
+}
+
+fn build_archive(now: u64) -> SnapshotArchive {
+    let artifacts = storage::artifacts();
+    let mut entries: Vec<SnapshotEntry> = artifacts
+        .iter()
+        .map(|(key, _)| SnapshotEntry {
+            name: format!("{}{}", ARTIFACT_PREFIX, key),
+            data: storage::stored_bytes(key),
+        })
+        .collect();
+    entries.push(json_entry(ARTIFACT_META_ENTRY, &artifacts));
+    entries.push(json_entry(REGISTRY_ENTRY, &registry::export_state()));
+    entries.push(json_entry(ROLES_ENTRY, &access::roles()));
+    entries.push(json_entry(
+        SETTINGS_ENTRY,
+        &Settings {
+            quota: quota::config(),
+            retention: retention::policy(),
+            compression: storage::compression_config(),
+        },
+    ));
+    entries.push(json_entry(TRAINING_ENTRY, &TrainingState::export()));
+    entries.push(json_entry(LINEAGE_ENTRY, &lineage::export_records()));
+
+    let manifest = SnapshotManifest {
+        format_version: FORMAT_VERSION,
+        created_at: now,
+        entries: entries
+            .iter()
+            .map(|entry| ManifestEntry {
+                name: entry.name.clone(),
+                size: entry.data.len() as u64,
+                sha256: sha256_hex(&entry.data),
+            })
+            .collect(),
+    };
+    SnapshotArchive { manifest, entries }
+}
+
+/// Checks the archive against its manifest. Nothing is modified here.
+fn validate(archive: &SnapshotArchive) -> Result<(), String> {
+    let manifest = &archive.manifest;
+    if manifest.format_version != FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported snapshot format {}, expected {}.",
+            manifest.format_version, FORMAT_VERSION
+        ));
+    }
+    if manifest.entries.len() != archive.entries.len() {
+        return Err(format!(
+            "Manifest lists {} entries but the archive holds {}.",
+            manifest.entries.len(),
+            archive.entries.len()
+        ));
+    }
+    for (expected, entry) in manifest.entries.iter().zip(&archive.entries) {
+        if expected.name != entry.name {
+            return Err(format!("Expected entry {} but found {}.", expected.name, entry.name));
+        }
+        if expected.size != entry.data.len() as u64 {
+            return Err(format!("Size mismatch for {}.", entry.name));
+        }
+        if expected.sha256 != sha256_hex(&entry.data) {
+            return Err(format!("Checksum mismatch for {}.", entry.name));
+        }
+    }
+    Ok(())
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(archive: &SnapshotArchive, name: &str) -> Result<T, String> {
+    let entry = archive
+        .entries
+        .iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| format!("Snapshot has no {} entry.", name))?;
+    serde_json::from_slice(&entry.data).map_err(|e| format!("Failed to decode {}: {:?}", name, e))
+}
+
+/// Validates and decodes the whole archive first, then replaces the canister state.
+fn restore(archive: SnapshotArchive) -> Result<SnapshotManifest, String> {
+    validate(&archive)?;
+
+    let metas: Vec<(String, ArtifactMeta)> = decode_json(&archive, ARTIFACT_META_ENTRY)?;
+    let registry_state: RegistryState = decode_json(&archive, REGISTRY_ENTRY)?;
+    let roles: Vec<(Principal, Role)> = decode_json(&archive, ROLES_ENTRY)?;
+    let settings: Settings = decode_json(&archive, SETTINGS_ENTRY)?;
+    let training: TrainingState = decode_json(&archive, TRAINING_ENTRY)?;
+    let lineage_records: Vec<(String, LineageRecord)> = decode_json(&archive, LINEAGE_ENTRY)?;
+
+    let SnapshotArchive { manifest, entries } = archive;
+    let mut stored: std::collections::BTreeMap<String, Vec<u8>> = entries
+        .into_iter()
+        .filter_map(|entry| {
+            entry
+                .name
+                .strip_prefix(ARTIFACT_PREFIX)
+                .map(|key| (key.to_string(), entry.data))
+        })
+        .collect();
+    let mut artifacts = Vec::with_capacity(metas.len());
+    for (key, meta) in metas {
+        let data = stored
+            .remove(&key)
+            .ok_or_else(|| format!("Snapshot has metadata but no data for {}.", key))?;
+        artifacts.push((key, meta, data));
+    }
+    if let Some(key) = stored.keys().next() {
+        return Err(format!("Snapshot has data but no metadata for {}.", key));
+    }
+
+    storage::replace_all(artifacts);
+    registry::replace_state(registry_state);
+    access::replace_roles(roles);
+    quota::set_config(settings.quota)?;
+    storage::set_compression(settings.compression)?;
+    retention::store_policy(settings.retention)?;
+    training.replace();
+    lineage::replace_records(lineage_records);
+    Ok(manifest)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn export_snapshot() -> Result<SnapshotInfo, String> {
+    let archive = build_archive(ic_cdk::api::time());
+    let bytes = candid::encode_one(&archive).map_err(|e| format!("Failed to encode snapshot: {:?}", e))?;
+    let info = SnapshotInfo {
+        manifest: archive.manifest,
+        total_size: bytes.len() as u64,
+        chunk_count: bytes.len().div_ceil(storage::CHUNK_SIZE) as u64,
+    };
+    EXPORT_BUFFER.with(|b| *b.borrow_mut() = bytes);
+    Ok(info)
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn export_snapshot_chunk(index: u64) -> Result<Vec<u8>, String> {
+    EXPORT_BUFFER.with(|b| {
+        b.borrow()
+            .chunks(storage::CHUNK_SIZE)
+            .nth(index as usize)
+            .map(|chunk| chunk.to_vec())
+            .ok_or_else(|| format!("Snapshot chunk {} does not exist, call export_snapshot first.", index))
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn append_snapshot_chunk(bytes: Vec<u8>) {
+    IMPORT_BUFFER.with(|b| b.borrow_mut().extend(bytes));
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn discard_snapshot_import() {
+    IMPORT_BUFFER.with(|b| b.borrow_mut().clear());
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn import_snapshot() -> Result<SnapshotManifest, String> {
+    let archive: SnapshotArchive = IMPORT_BUFFER
+        .with(|b| candid::decode_one(&b.borrow()))
+        .map_err(|e| format!("Failed to decode snapshot: {:?}", e))?;
+    let manifest = restore(archive)?;
+    IMPORT_BUFFER.with(|b| b.borrow_mut().clear());
+    // The restored policy, rounds and jobs need their timers, like after an upgrade.
+    retention::schedule_gc();
+    agent::schedule_deadlines();
+    evaluation::schedule_deadlines();
+    finetune::resume_jobs();
+    agent::resume_scoring();
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clinics::{DeviceClass, NodeProfile};
+    use crate::lineage::VersionOrigin;
+    use crate::privacy::DifferentialPrivacy;
+
+    #[test]
+    fn archives_restore_the_training_state() {
+        agent::replace_round_configs(vec![("malaria".to_string(), RoundConfig { quorum: 7, ..RoundConfig::default() })]);
+        let clinic = Principal::from_slice(&[3]);
+        let profile = NodeProfile {
+            clinic_name: "Clinic".to_string(),
+            region: "North".to_string(),
+            device_class: DeviceClass::Workstation,
+            dataset_size: 100,
+        };
+        clinics::register(clinic, profile, 0).unwrap();
+        privacy::record("malaria", 1, &DifferentialPrivacy::default(), 3, 0);
+        lineage::record("malaria", 1, None, VersionOrigin::Upload { uploader: clinic }, 0);
+        let bytes = candid::encode_one(build_archive(1)).unwrap();
+
+        agent::replace_round_configs(Vec::new());
+        clinics::replace_nodes(Vec::new());
+        privacy::replace_ledgers(Vec::new());
+        lineage::replace_records(Vec::new());
+        restore(candid::decode_one(&bytes).unwrap()).unwrap();
+
+        assert_eq!(agent::round_config("malaria").quorum, 7);
+        assert!(clinics::node(&clinic).is_some());
+        assert_eq!(privacy::export_ledgers().len(), 1);
+        assert!(lineage::get("malaria", 1).is_some());
+    }
+}
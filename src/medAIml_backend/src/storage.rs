@@ -1,9 +1,112 @@
+use candid::{CandidType, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+
+use crate::access::{self, is_controller, Role};
+use crate::quota;
 // use client::MalariaModelV3;
 
-type Memory = VirtualMemory<DefaultMemoryImpl>;
+pub(crate) type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Memory ids handed out to the other modules. Keep them clear of the WASI ids
+// used in client.rs, client_type.rs, malaria_types.rs and biogpt.rs.
+pub(crate) const REGISTRY_VERSIONS_MEMORY_ID: MemoryId = MemoryId::new(20);
+pub(crate) const REGISTRY_TASKS_MEMORY_ID: MemoryId = MemoryId::new(21);
+pub(crate) const HEAP_STATE_MEMORY_ID: MemoryId = MemoryId::new(22);
+pub(crate) const ROLES_MEMORY_ID: MemoryId = MemoryId::new(23);
+pub(crate) const ARTIFACTS_MEMORY_ID: MemoryId = MemoryId::new(24);
+pub(crate) const QUOTA_MEMORY_ID: MemoryId = MemoryId::new(25);
+pub(crate) const RETENTION_MEMORY_ID: MemoryId = MemoryId::new(26);
+pub(crate) const COMPRESSION_MEMORY_ID: MemoryId = MemoryId::new(27);
+pub(crate) const ROUNDS_MEMORY_ID: MemoryId = MemoryId::new(28);
+pub(crate) const ROUND_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(29);
+pub(crate) const NODES_MEMORY_ID: MemoryId = MemoryId::new(30);
+pub(crate) const OPTIMIZER_MEMORY_ID: MemoryId = MemoryId::new(31);
+pub(crate) const SECURE_AGGREGATION_MEMORY_ID: MemoryId = MemoryId::new(32);
+pub(crate) const PRIVACY_MEMORY_ID: MemoryId = MemoryId::new(33);
+pub(crate) const HEADS_MEMORY_ID: MemoryId = MemoryId::new(34);
+pub(crate) const DISTRICTS_MEMORY_ID: MemoryId = MemoryId::new(35);
+pub(crate) const CONTRIBUTIONS_MEMORY_ID: MemoryId = MemoryId::new(36);
+pub(crate) const TRAINING_SAMPLES_MEMORY_ID: MemoryId = MemoryId::new(37);
+pub(crate) const FINE_TUNE_JOBS_MEMORY_ID: MemoryId = MemoryId::new(38);
+pub(crate) const EVALUATIONS_MEMORY_ID: MemoryId = MemoryId::new(39);
+pub(crate) const LINEAGE_MEMORY_ID: MemoryId = MemoryId::new(40);
+pub(crate) const SCORING_JOBS_MEMORY_ID: MemoryId = MemoryId::new(41);
+
+// Backing memory of the WASI filesystem, initialised in upgrade.rs.
+pub(crate) const WASI_MEMORY_ID: MemoryId = MemoryId::new(10);
+
+// Keys under this prefix belong to the model registry and are immutable once
+// committed, so the generic byte endpoints refuse to touch them.
+pub(crate) const REGISTRY_PREFIX: &str = "models/";
+// Client updates staged for federated aggregation, one key per participant and task.
+pub(crate) const UPDATE_PREFIX: &str = "updates/";
+// Held-out validation sets, one per task, only written by controllers.
+pub(crate) const VALIDATION_PREFIX: &str = "validation/";
+// Personalised classifier heads, one per task and clinic.
+pub(crate) const HEADS_PREFIX: &str = "heads/";
+// Labelled images and in-progress heads of on-canister fine-tuning.
+pub(crate) const TRAINING_PREFIX: &str = "training/";
+
+/// Implements `Storable` for a serde type by encoding it as JSON.
+macro_rules! impl_storable_json {
+    ($t:ty) => {
+        impl ic_stable_structures::Storable for $t {
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                std::borrow::Cow::Owned(serde_json::to_vec(self).unwrap())
+            }
+
+            fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                serde_json::from_slice(&bytes).unwrap()
+            }
+
+            const BOUND: ic_stable_structures::storable::Bound =
+                ic_stable_structures::storable::Bound::Unbounded;
+        }
+    };
+}
+pub(crate) use impl_storable_json;
+
+/// Bookkeeping for every value in `MODEL_MAP`, used for quotas and retention.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ArtifactMeta {
+    pub owner: Principal,
+    // Bytes held in stable memory, after compression.
+    pub size: u64,
+    pub updated_at: u64,
+    // Values are deflate-compressed when this is set and inflated again by `bytes`.
+    #[serde(default)]
+    pub compressed: bool,
+    // Size of the value as callers see it.
+    #[serde(default)]
+    pub raw_size: u64,
+}
+
+impl_storable_json!(ArtifactMeta);
+
+//Complete values at least `min_size` long are stored deflate-compressed when enabled.
+//Chunked uploads stay uncompressed until they are finished and compacted.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub min_size: u64,
+    // Deflate level, 0 (fastest) to 10 (smallest).
+    pub level: u8,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            min_size: 4096,
+            level: 6,
+        }
+    }
+}
+
+impl_storable_json!(CompressionConfig);
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -14,35 +117,456 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
         )
     );
+
+    static ARTIFACTS: RefCell<StableBTreeMap<String, ArtifactMeta, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ARTIFACTS_MEMORY_ID)),
+        )
+    );
+
+    static COMPRESSION: RefCell<StableCell<CompressionConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COMPRESSION_MEMORY_ID)),
+            CompressionConfig::default(),
+        ).expect("failed to init COMPRESSION cell")
+    );
+}
+
+/// Returns the virtual memory with the given id from the shared memory manager.
+pub(crate) fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Current time in nanoseconds, 0 outside of a canister.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> u64 {
+    0
+}
+
+// Largest chunk a model or snapshot is downloaded in. Stay well below the 2 MB message limit.
+pub(crate) const CHUNK_SIZE: usize = 1_500_000;
+pub(crate) const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Instructions a timer call may use before it hands over to the next one. Calls are capped at
+// 40B, and one more unit of work has to fit in the remainder.
+pub(crate) const INSTRUCTION_BUDGET: u64 = 20_000_000_000;
+
+/// Instructions the current call used so far, 0 outside of a canister.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn instructions() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn instructions() -> u64 {
+    0
+}
+
+/// The canister's own principal, recorded as the author of versions it publishes itself.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn canister_id() -> Principal {
+    ic_cdk::api::id()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn canister_id() -> Principal {
+    Principal::anonymous()
+}
+
+pub(crate) fn compression_config() -> CompressionConfig {
+    COMPRESSION.with(|c| c.borrow().get().clone())
+}
+
+pub(crate) fn set_compression(config: CompressionConfig) -> Result<(), String> {
+    if config.level > 10 {
+        return Err("Compression level must be between 0 and 10.".to_string());
+    }
+    COMPRESSION.with(|c| c.borrow_mut().set(config))
+        .map(|_| ())
+        .map_err(|e| format!("Failed to store compression config: {:?}", e))
+}
+
+// Compresses `bytes` when the config asks for it and it actually saves space.
+fn encode(bytes: Vec<u8>) -> (Vec<u8>, bool) {
+    let config = compression_config();
+    if !config.enabled || (bytes.len() as u64) < config.min_size {
+        return (bytes, false);
+    }
+    let compressed = miniz_oxide::deflate::compress_to_vec(&bytes, config.level);
+    if compressed.len() < bytes.len() {
+        (compressed, true)
+    } else {
+        (bytes, false)
+    }
+}
+
+fn decode(stored: Vec<u8>, compressed: bool) -> Vec<u8> {
+    if !compressed {
+        return stored;
+    }
+    miniz_oxide::inflate::decompress_to_vec(&stored)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decompress artifact: {:?}", e)))
 }
 
+fn is_compressed(key: &str) -> bool {
+    ARTIFACTS.with(|a| a.borrow().get(&key.to_string()).is_some_and(|meta| meta.compressed))
+}
+
+fn check_unreserved(key: &str) {
+    if key.starts_with(REGISTRY_PREFIX) {
+        ic_cdk::trap(&format!("Key {} is managed by the model registry.", key));
+    }
+    if [UPDATE_PREFIX, VALIDATION_PREFIX, HEADS_PREFIX, TRAINING_PREFIX].iter().any(|prefix| key.starts_with(prefix)) {
+        ic_cdk::trap(&format!("Key {} is managed by federated learning.", key));
+    }
+}
+
+/// Fails unless `caller` owns the value under `key`, or it does not exist yet.
+/// Controllers may write and delete any key.
+fn check_owner(key: &str, caller: &Principal) -> Result<(), String> {
+    if access::role_of(caller) == Role::Admin {
+        return Ok(());
+    }
+    match ARTIFACTS.with(|a| a.borrow().get(&key.to_string())) {
+        Some(meta) if meta.owner != *caller => Err(format!("Key {} belongs to {}.", key, meta.owner)),
+        _ => Ok(()),
+    }
+}
+
+fn check_writable(key: &str) {
+    check_unreserved(key);
+    if let Err(e) = check_owner(key, &access::caller()) {
+        ic_cdk::trap(&e);
+    }
+}
+
+fn check_quota(additional: u64) {
+    if let Err(e) = quota::check(&access::caller(), additional) {
+        ic_cdk::trap(&e);
+    }
+}
+
+/// Quotas count stored bytes, so the value is compressed before it is charged.
 #[ic_cdk_macros::update]
 pub fn store_bytes(key: String, bytes: Vec<u8>) {
-    MODEL_MAP.with(|map| {
-        map.borrow_mut().insert(key, bytes);
-    });
+    check_writable(&key);
+    let raw_size = bytes.len() as u64;
+    let (stored, compressed) = encode(bytes);
+    check_quota((stored.len() as u64).saturating_sub(len(&key)));
+    write(key, stored, raw_size, compressed);
 }
 
-#[ic_cdk_macros::query]
-pub fn bytes(key: String) -> Vec<u8> {
-    MODEL_MAP.with(|map| {
+/// The value under `key`, decompressed, empty when absent.
+pub(crate) fn bytes(key: String) -> Vec<u8> {
+    let stored = MODEL_MAP.with(|map| {
         map.borrow().get(&key.to_string()).unwrap_or_default()
-    })
+    });
+    decode(stored, is_compressed(&key))
+}
+
+/// Reserved keys hold client updates, validation sets and labelled patient images, so only
+/// controllers can read them here.
+#[ic_cdk_macros::query(name = "bytes")]
+pub fn read_bytes(key: String) -> Vec<u8> {
+    if access::role_of(&access::caller()) != Role::Admin {
+        check_unreserved(&key);
+    }
+    bytes(key)
 }
 
 #[ic_cdk_macros::update]
 pub fn clear_bytes(key: String) {
-    MODEL_MAP.with(|map| {
-        map.borrow_mut().remove(&key);
-    });
+    check_writable(&key);
+    remove_raw(&key);
 }
 
+/// Appending inflates a compressed value, so the quota is charged for its full size.
 #[ic_cdk_macros::update]
 pub fn append_bytes(key: String, bytes: Vec<u8>) {
+    check_writable(&key);
+    let grown = raw_len(&key) + bytes.len() as u64;
+    check_quota(grown.saturating_sub(len(&key)));
+    append_raw(key, bytes);
+}
+
+/// Stores `bytes` under `key` without the reserved-prefix check, compressing it if configured.
+pub(crate) fn insert_raw(key: String, bytes: Vec<u8>) {
+    let raw_size = bytes.len() as u64;
+    let (stored, compressed) = encode(bytes);
+    write(key, stored, raw_size, compressed);
+}
+
+fn write(key: String, stored: Vec<u8>, raw_size: u64, compressed: bool) {
+    record_write(&key, stored.len() as u64, raw_size, compressed);
+    MODEL_MAP.with(|map| {
+        map.borrow_mut().insert(key, stored);
+    });
+}
+
+/// Appends `bytes` to the value under `key` without the reserved-prefix check.
+/// The value is kept uncompressed until `compact` is called.
+pub(crate) fn append_raw(key: String, bytes: Vec<u8>) {
+    let compressed = is_compressed(&key);
     MODEL_MAP.with(|map| {
         let mut map = map.borrow_mut();
-        let mut existing = map.get(&key).unwrap_or(Vec::new()).clone();
+        let mut existing = decode(map.get(&key).unwrap_or(Vec::new()).clone(), compressed);
         existing.extend(bytes);
+        let size = existing.len() as u64;
+        record_write(&key, size, size, false);
         map.insert(key, existing);
     });
-}
\ No newline at end of file
+}
+
+/// Re-stores a finished value so that the compression config applies to it.
+/// Returns the number of bytes saved.
+pub(crate) fn compact(key: &str) -> u64 {
+    if is_compressed(key) {
+        return 0;
+    }
+    let before = len(key);
+    let value = bytes(key.to_string());
+    if value.is_empty() {
+        return 0;
+    }
+    insert_raw(key.to_string(), value);
+    before.saturating_sub(len(key))
+}
+
+/// Removes `key` without the reserved-prefix check.
+pub(crate) fn remove_raw(key: &str) {
+    ARTIFACTS.with(|a| a.borrow_mut().remove(&key.to_string()));
+    MODEL_MAP.with(|map| {
+        map.borrow_mut().remove(&key.to_string());
+    });
+}
+
+// The first writer of a key owns it; later writes only update the size.
+fn record_write(key: &str, size: u64, raw_size: u64, compressed: bool) {
+    ARTIFACTS.with(|a| {
+        let mut a = a.borrow_mut();
+        let owner = a
+            .get(&key.to_string())
+            .map(|meta| meta.owner)
+            .unwrap_or_else(access::caller);
+        a.insert(
+            key.to_string(),
+            ArtifactMeta { owner, size, updated_at: now(), compressed, raw_size },
+        );
+    });
+}
+
+/// Bytes the value under `key` occupies in stable memory, 0 when absent.
+pub(crate) fn len(key: &str) -> u64 {
+    ARTIFACTS.with(|a| a.borrow().get(&key.to_string()).map(|meta| meta.size).unwrap_or(0))
+}
+
+// Size of the value under `key` as callers see it, 0 when absent.
+fn raw_len(key: &str) -> u64 {
+    ARTIFACTS.with(|a| a.borrow().get(&key.to_string()))
+        .map_or(0, |meta| if meta.compressed { meta.raw_size } else { meta.size })
+}
+
+/// Metadata of every stored artifact, ordered by key.
+pub(crate) fn artifacts() -> Vec<(String, ArtifactMeta)> {
+    ARTIFACTS.with(|a| a.borrow().iter().collect())
+}
+
+/// The value under `key` exactly as it is held in stable memory, i.e. possibly compressed.
+pub(crate) fn stored_bytes(key: &str) -> Vec<u8> {
+    MODEL_MAP.with(|map| map.borrow().get(&key.to_string()).unwrap_or_default())
+}
+
+/// Replaces every stored value and its metadata, used when a snapshot is restored.
+pub(crate) fn replace_all(entries: Vec<(String, ArtifactMeta, Vec<u8>)>) {
+    let keys: Vec<String> = MODEL_MAP.with(|map| map.borrow().iter().map(|(key, _)| key).collect());
+    for key in keys {
+        remove_raw(&key);
+    }
+    for (key, meta, stored) in entries {
+        ARTIFACTS.with(|a| a.borrow_mut().insert(key.clone(), meta));
+        MODEL_MAP.with(|map| map.borrow_mut().insert(key, stored));
+    }
+}
+
+/// Every entry of a stable map, for snapshots.
+pub(crate) fn entries<K: Storable + Ord + Clone, V: Storable>(map: &RefCell<StableBTreeMap<K, V, Memory>>) -> Vec<(K, V)> {
+    map.borrow().iter().collect()
+}
+
+/// Replaces every entry of a stable map with `entries`, used when a snapshot is restored.
+pub(crate) fn replace_entries<K: Storable + Ord + Clone, V: Storable>(
+    map: &RefCell<StableBTreeMap<K, V, Memory>>,
+    entries: Vec<(K, V)>,
+) {
+    let mut map = map.borrow_mut();
+    let keys: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in keys {
+        map.remove(&key);
+    }
+    for (key, value) in entries {
+        map.insert(key, value);
+    }
+}
+
+/// Namespace of a key: everything before the first `/`, or `root` for flat keys.
+pub(crate) fn namespace(key: &str) -> &str {
+    key.split_once('/').map(|(ns, _)| ns).unwrap_or("root")
+}
+
+/// Creates metadata for values written before it was tracked, owned by `owner`.
+pub(crate) fn backfill_artifact_meta(owner: Principal) {
+    let untracked: Vec<(String, u64)> = MODEL_MAP.with(|map| {
+        map.borrow()
+            .iter()
+            .filter(|(key, _)| ARTIFACTS.with(|a| !a.borrow().contains_key(key)))
+            .map(|(key, value)| (key, value.len() as u64))
+            .collect()
+    });
+    ARTIFACTS.with(|a| {
+        let mut a = a.borrow_mut();
+        for (key, size) in untracked {
+            a.insert(
+                key,
+                ArtifactMeta { owner, size, updated_at: now(), compressed: false, raw_size: size },
+            );
+        }
+    });
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn set_compression_config(config: CompressionConfig) -> Result<(), String> {
+    set_compression(config)
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+pub fn get_compression_config() -> CompressionConfig {
+    compression_config()
+}
+
+/// Applies the compression config to a stored value, e.g. a config uploaded in chunks.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn compress_artifact(key: String) -> u64 {
+    compact(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn public_keys_are_readable_by_everyone() {
+        insert_raw("notes/readme".to_string(), vec![1, 2, 3]);
+        assert_eq!(read_bytes("notes/readme".to_string()), vec![1, 2, 3]);
+    }
+
+    fn owned_by_someone_else(key: &str) {
+        insert_raw(key.to_string(), vec![1]);
+        ARTIFACTS.with(|a| {
+            let mut meta = a.borrow().get(&key.to_string()).unwrap();
+            meta.owner = Principal::from_slice(&[7]);
+            a.borrow_mut().insert(key.to_string(), meta);
+        });
+    }
+
+    #[test]
+    fn only_the_owner_may_write_a_key() {
+        let caller = access::caller();
+        owned_by_someone_else("notes/theirs");
+        assert!(check_owner("notes/theirs", &caller).is_err());
+        insert_raw("notes/mine".to_string(), vec![1]);
+        assert!(check_owner("notes/mine", &caller).is_ok());
+        assert!(check_owner("notes/new", &caller).is_ok());
+    }
+
+    #[test]
+    #[should_panic]
+    fn appending_to_a_foreign_key_traps() {
+        owned_by_someone_else("notes/appended");
+        append_bytes("notes/appended".to_string(), vec![2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn clearing_a_foreign_key_traps() {
+        owned_by_someone_else("notes/cleared");
+        clear_bytes("notes/cleared".to_string());
+    }
+
+    fn compress_everything() {
+        set_compression(CompressionConfig { enabled: true, min_size: 0, level: 6 }).unwrap();
+    }
+
+    fn meta(key: &str) -> ArtifactMeta {
+        ARTIFACTS.with(|a| a.borrow().get(&key.to_string()).unwrap())
+    }
+
+    #[test]
+    fn compressed_values_round_trip() {
+        compress_everything();
+        let value: Vec<u8> = (0..10_000u32).map(|i| (i % 7) as u8).collect();
+        insert_raw("notes/compressed".to_string(), value.clone());
+        let stored = meta("notes/compressed");
+        assert!(stored.compressed && stored.size < 10_000);
+        assert_eq!(stored.raw_size, 10_000);
+        assert_eq!(bytes("notes/compressed".to_string()), value);
+
+        // Appending inflates the value, compacting deflates it again.
+        append_raw("notes/compressed".to_string(), vec![9; 100]);
+        assert!(!meta("notes/compressed").compressed);
+        assert_eq!(len("notes/compressed"), 10_100);
+        assert!(compact("notes/compressed") > 0);
+        let compacted = bytes("notes/compressed".to_string());
+        assert_eq!(compacted.len(), 10_100);
+        assert_eq!(&compacted[..10_000], &value[..]);
+
+        // Values that do not shrink are kept as they are.
+        let random: Vec<u8> = (0..64u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        insert_raw("notes/random".to_string(), random.clone());
+        assert!(!meta("notes/random").compressed);
+        assert_eq!(bytes("notes/random".to_string()), random);
+    }
+
+    #[test]
+    fn quotas_charge_the_compressed_size() {
+        compress_everything();
+        store_bytes("notes/quota".to_string(), vec![0; 100_000]);
+        let stored = len("notes/quota");
+        assert!(stored < 1_000);
+        // The limit only covers the stored bytes, rewriting the raw value still fits.
+        let principal_limits = vec![(access::caller(), Some(stored + 10))];
+        quota::set_config(quota::QuotaConfig { principal_limits, ..Default::default() }).unwrap();
+        store_bytes("notes/quota".to_string(), vec![0; 100_000]);
+        assert_eq!(bytes("notes/quota".to_string()).len(), 100_000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn appending_to_a_compressed_value_charges_its_full_size() {
+        compress_everything();
+        store_bytes("notes/appended".to_string(), vec![0; 100_000]);
+        let principal_limits = vec![(access::caller(), Some(len("notes/appended") + 10))];
+        quota::set_config(quota::QuotaConfig { principal_limits, ..Default::default() }).unwrap();
+        append_bytes("notes/appended".to_string(), vec![1]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn storing_over_the_quota_traps() {
+        let principal_limits = vec![(access::caller(), Some(10))];
+        quota::set_config(quota::QuotaConfig { principal_limits, ..Default::default() }).unwrap();
+        store_bytes("notes/large".to_string(), vec![1; 11]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn staged_updates_are_not_readable_by_everyone() {
+        let key = format!("{}malaria/clinic", UPDATE_PREFIX);
+        insert_raw(key.clone(), vec![1, 2, 3]);
+        read_bytes(key);
+    }
+}
@@ -0,0 +1,117 @@
+use ic_stable_structures::{StableCell, Storable};
+use ic_stable_structures::storable::Bound;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::agent;
+use crate::biogpt;
+use crate::client::FILE_STORAGE;
+use crate::evaluation;
+use crate::finetune;
+use crate::privacy;
+use crate::retention;
+use crate::storage::{self, Memory};
+
+//Heap state that has to survive `dfx deploy` upgrades. It is written to a stable cell in
+//`pre_upgrade` and read back in `post_upgrade`. Models are not part of it: the classifiers
+//read their weights from stable storage on every call and BioGPT is reloaded by a timer.
+//Federated learning keeps its round submissions in stable storage as well.
+
+#[derive(Serialize, Deserialize, Default)]
+struct HeapState {
+    #[serde(with = "serde_bytes")]
+    file_storage: Vec<u8>,
+}
+
+impl Storable for HeapState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(postcard::to_allocvec(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        postcard::from_bytes(&bytes).unwrap_or_default()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static HEAP_STATE: RefCell<StableCell<HeapState, Memory>> = RefCell::new(
+        StableCell::init(storage::memory(storage::HEAP_STATE_MEMORY_ID), HeapState::default())
+            .expect("failed to init HEAP_STATE cell")
+    );
+}
+
+fn init_wasi() {
+    let wasi_memory = storage::memory(storage::WASI_MEMORY_ID);
+    ic_wasi_polyfill::init_with_memory(&[0u8; 32], &[], wasi_memory);
+}
+
+/// Copies the heap state into stable memory.
+fn save_heap_state() {
+    let state = HeapState {
+        file_storage: FILE_STORAGE.with(|storage| storage.borrow().clone()),
+    };
+    HEAP_STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(state)
+            .expect("failed to persist heap state");
+    });
+}
+
+/// Restores the heap state saved by `save_heap_state` and releases the stable copy.
+fn restore_heap_state() {
+    let state = HEAP_STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(HeapState::default())
+            .expect("failed to reset heap state")
+    });
+    FILE_STORAGE.with(|storage| *storage.borrow_mut() = state.file_storage);
+}
+
+#[ic_cdk::init]
+fn init() {
+    init_wasi();
+    retention::schedule_gc();
+    privacy::refresh_noise_seed();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    save_heap_state();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    init_wasi();
+    restore_heap_state();
+    // Values stored before artifact metadata existed are attributed to the canister itself.
+    storage::backfill_artifact_meta(ic_cdk::api::id());
+    retention::schedule_gc();
+    agent::schedule_deadlines();
+    evaluation::schedule_deadlines();
+    finetune::resume_jobs();
+    agent::resume_scoring();
+    privacy::refresh_noise_seed();
+    biogpt::warm_up();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heap_state_round_trips_across_upgrade() {
+        FILE_STORAGE.with(|storage| *storage.borrow_mut() = vec![1, 2, 3]);
+
+        save_heap_state();
+
+        // Everything on the heap is gone after the new wasm module is installed.
+        FILE_STORAGE.with(|storage| storage.borrow_mut().clear());
+
+        restore_heap_state();
+
+        FILE_STORAGE.with(|storage| assert_eq!(*storage.borrow(), vec![1, 2, 3]));
+    }
+}
@@ -0,0 +1,307 @@
+use candid::CandidType;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::ops::{sigmoid, softmax};
+use candle_nn::VarBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::access::is_controller;
+use crate::checkpoint::Checkpoint;
+use crate::client::{MalariaModelV3, ModelConfig, MALARIA_TASK};
+use crate::client_type::{MalariaModelV3Types as StageModel, ModelConfigStage, MALARIA_STAGE_TASK};
+use crate::malaria_types::{MalariaModelV3Types as TypeModel, ModelConfiguration, MALARIA_TYPE_TASK};
+use crate::storage;
+
+//Validation gate for federated rounds. Every candidate global model is evaluated on a held-out
+//labelled set before it is published, next to the production model it would replace. The
+//candidate is rejected when it falls behind production by more than the configured tolerances.
+//
+//The validation set of a task is a safetensors artifact with two tensors: `features`, one row
+//per sample in the layout the model reads (U8 pixels are scaled to [0, 1] like the predict
+//endpoints do), and `labels`, the class index of every sample.
+
+const DEVICE: Device = Device::Cpu;
+// Rows evaluated per forward pass.
+const BATCH_SIZE: usize = 32;
+// The gate runs both models over the whole set inside the call that closes the round, next to
+// the aggregation, so the set has to stay small.
+const MAX_SAMPLES: usize = 256;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ValidationGate {
+    // Largest drops versus production that are still published, as fractions.
+    pub max_accuracy_drop: f64,
+    // Applies to the recall of every class on its own.
+    pub max_recall_drop: f64,
+    pub max_auc_drop: f64,
+}
+
+impl Default for ValidationGate {
+    fn default() -> Self {
+        ValidationGate {
+            max_accuracy_drop: 0.01,
+            max_recall_drop: 0.02,
+            max_auc_drop: 0.01,
+        }
+    }
+}
+
+impl ValidationGate {
+    pub fn validate(&self) -> Result<(), String> {
+        if [self.max_accuracy_drop, self.max_recall_drop, self.max_auc_drop].iter().any(|t| !(0.0..=1.0).contains(t)) {
+            return Err("Validation tolerances must lie in [0, 1].".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Metrics {
+    pub accuracy: f64,
+    // Recall of every class, by class index. `None` for classes missing from the set.
+    pub recall: Vec<Option<f64>>,
+    // ROC AUC, macro-averaged one-vs-rest for more than two classes.
+    pub auc: Option<f64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ValidationReport {
+    pub samples: u64,
+    pub candidate: Metrics,
+    pub production: Metrics,
+    // Why the candidate was rejected, empty when it passed.
+    pub regressions: Vec<String>,
+}
+
+fn validation_key(task: &str) -> String {
+    format!("{}{}", storage::VALIDATION_PREFIX, task)
+}
+
+/// Features and labels of the validation set of `task`, `None` when there is none.
+fn load_set(task: &str) -> Result<Option<(Tensor, Vec<u32>)>, String> {
+    let bytes = storage::bytes(validation_key(task));
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    let mut tensors = candle_core::safetensors::load_buffer(&bytes, &DEVICE)
+        .map_err(|e| format!("Failed to load the validation set of {}: {:?}", task, e))?;
+    let (features, labels) = match (tensors.remove("features"), tensors.remove("labels")) {
+        (Some(features), Some(labels)) => (features, labels),
+        _ => return Err("The validation set needs a features and a labels tensor.".to_string()),
+    };
+    let features = match features.dtype() {
+        DType::U8 => features.to_dtype(DType::F32).and_then(|f| f / 255.0),
+        _ => features.to_dtype(DType::F32),
+    }
+    .and_then(|f| f.flatten_from(1))
+    .map_err(|e| format!("Invalid validation features: {:?}", e))?;
+    let labels = labels
+        .to_dtype(DType::U32)
+        .and_then(|l| l.flatten_all())
+        .and_then(|l| l.to_vec1::<u32>())
+        .map_err(|e| format!("Invalid validation labels: {:?}", e))?;
+    if features.dim(0).ok() != Some(labels.len()) || labels.is_empty() {
+        return Err("The validation set needs one label per row of features.".to_string());
+    }
+    if labels.len() > MAX_SAMPLES {
+        return Err(format!(
+            "The validation set of {} has {} samples, at most {} are allowed.",
+            task,
+            labels.len(),
+            MAX_SAMPLES
+        ));
+    }
+    Ok(Some((features, labels)))
+}
+
+/// Logits of `checkpoint` for a batch of `features`, built like the predict endpoint of `task`.
+fn logits(task: &str, config: &[u8], checkpoint: &Checkpoint, features: &Tensor) -> Result<Tensor, String> {
+    let tensors: HashMap<String, Tensor> = checkpoint.tensors.clone().into_iter().collect();
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, &DEVICE);
+    let parse_error = |e: serde_json::Error| format!("Failed to deserialize model config: {:?}", e);
+    let output = match task {
+        MALARIA_TASK => MalariaModelV3::new(serde_json::from_slice::<ModelConfig>(config).map_err(parse_error)?, vb)
+            .and_then(|m| m.forward(features)),
+        MALARIA_STAGE_TASK => StageModel::new(serde_json::from_slice::<ModelConfigStage>(config).map_err(parse_error)?, vb)
+            .and_then(|m| m.forward(features)),
+        MALARIA_TYPE_TASK => TypeModel::new(serde_json::from_slice::<ModelConfiguration>(config).map_err(parse_error)?, vb)
+            .and_then(|m| m.forward(features)),
+        _ => return Err(format!("Unknown task {}.", task)),
+    };
+    output.map_err(|e| format!("Validation forward pass failed: {:?}", e))
+}
+
+/// Class scores of every row: one sigmoid column for binary models, softmax otherwise.
+fn scores(task: &str, config: &[u8], checkpoint: &Checkpoint, features: &Tensor) -> Result<Vec<Vec<f32>>, String> {
+    let rows = features.dim(0).map_err(|e| format!("{:?}", e))?;
+    let mut scores = Vec::with_capacity(rows);
+    for start in (0..rows).step_by(BATCH_SIZE) {
+        let batch = features
+            .narrow(0, start, BATCH_SIZE.min(rows - start))
+            .map_err(|e| format!("{:?}", e))?;
+        let logits = logits(task, config, checkpoint, &batch)?;
+        let probabilities = match logits.dim(1) {
+            Ok(1) => sigmoid(&logits),
+            _ => softmax(&logits, 1),
+        };
+        scores.extend(
+            probabilities
+                .and_then(|p| p.to_vec2::<f32>())
+                .map_err(|e| format!("Failed to read validation scores: {:?}", e))?,
+        );
+    }
+    Ok(scores)
+}
+
+/// Features and labels of the validation set of `task`.
+pub fn validation_set(task: &str) -> Result<(Tensor, Vec<u32>), String> {
+    load_set(task)?.ok_or_else(|| format!("No validation set was uploaded for {}.", task))
+}
+
+/// Accuracy of `checkpoint` on a validation set of `task`.
+pub fn accuracy(task: &str, config: &[u8], checkpoint: &Checkpoint, features: &Tensor, labels: &[u32]) -> Result<f64, String> {
+    Ok(metrics(&scores(task, config, checkpoint, features)?, labels).accuracy)
+}
+
+/// Mann-Whitney estimate of the probability that a positive outranks a negative.
+fn roc_auc(scores: &[f32], positive: &[bool]) -> Option<f64> {
+    let positives = positive.iter().filter(|p| **p).count();
+    let negatives = positive.len() - positives;
+    if positives == 0 || negatives == 0 {
+        return None;
+    }
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| scores[*a].total_cmp(&scores[*b]));
+    // Tied scores share their average rank.
+    let mut rank_sum = 0.0;
+    let mut i = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && scores[order[j + 1]] == scores[order[i]] {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        rank_sum += order[i..=j].iter().filter(|k| positive[**k]).count() as f64 * rank;
+        i = j + 1;
+    }
+    let positives = positives as f64;
+    Some((rank_sum - positives * (positives + 1.0) / 2.0) / (positives * negatives as f64))
+}
+
+/// Accuracy, per-class recall and AUC of class `scores` against `labels`.
+pub fn metrics(scores: &[Vec<f32>], labels: &[u32]) -> Metrics {
+    let binary = scores.first().is_some_and(|s| s.len() == 1);
+    let predictions: Vec<u32> = scores
+        .iter()
+        .map(|s| match binary {
+            true => (s[0] >= 0.5) as u32,
+            false => (0..s.len()).max_by(|a, b| s[*a].total_cmp(&s[*b])).unwrap_or_default() as u32,
+        })
+        .collect();
+    let classes = if binary { 2 } else { scores.first().map_or(0, |s| s.len()) };
+    let correct = predictions.iter().zip(labels).filter(|(p, l)| p == l).count();
+    let recall = (0..classes as u32)
+        .map(|class| {
+            let members: Vec<u32> = labels.iter().zip(&predictions).filter(|(l, _)| **l == class).map(|(_, p)| *p).collect();
+            (!members.is_empty()).then(|| members.iter().filter(|p| **p == class).count() as f64 / members.len() as f64)
+        })
+        .collect();
+    let auc = if binary {
+        let positive: Vec<f32> = scores.iter().map(|s| s[0]).collect();
+        roc_auc(&positive, &labels.iter().map(|l| *l == 1).collect::<Vec<_>>())
+    } else {
+        let per_class: Vec<f64> = (0..classes)
+            .filter_map(|class| {
+                let column: Vec<f32> = scores.iter().map(|s| s[class]).collect();
+                roc_auc(&column, &labels.iter().map(|l| *l as usize == class).collect::<Vec<_>>())
+            })
+            .collect();
+        (!per_class.is_empty()).then(|| per_class.iter().sum::<f64>() / per_class.len() as f64)
+    };
+    Metrics { accuracy: correct as f64 / labels.len().max(1) as f64, recall, auc }
+}
+
+/// Differences of `candidate` to `production` that exceed the tolerances of `gate`.
+pub fn regressions(gate: &ValidationGate, candidate: &Metrics, production: &Metrics) -> Vec<String> {
+    let mut found = Vec::new();
+    if candidate.accuracy < production.accuracy - gate.max_accuracy_drop {
+        found.push(format!("Accuracy dropped from {:.4} to {:.4}.", production.accuracy, candidate.accuracy));
+    }
+    for (class, (new, old)) in candidate.recall.iter().zip(&production.recall).enumerate() {
+        if let (Some(new), Some(old)) = (new, old) {
+            if *new < old - gate.max_recall_drop {
+                found.push(format!("Recall of class {} dropped from {:.4} to {:.4}.", class, old, new));
+            }
+        }
+    }
+    if let (Some(new), Some(old)) = (candidate.auc, production.auc) {
+        if new < old - gate.max_auc_drop {
+            found.push(format!("AUC dropped from {:.4} to {:.4}.", old, new));
+        }
+    }
+    found
+}
+
+/// Evaluates `candidate` and `production` of `task` on its validation set.
+pub fn evaluate(
+    task: &str,
+    gate: &ValidationGate,
+    config: &[u8],
+    production: &Checkpoint,
+    candidate: &Checkpoint,
+) -> Result<ValidationReport, String> {
+    let (features, labels) = validation_set(task)?;
+    let production = metrics(&scores(task, config, production, &features)?, &labels);
+    let candidate = metrics(&scores(task, config, candidate, &features)?, &labels);
+    Ok(ValidationReport {
+        samples: labels.len() as u64,
+        regressions: regressions(gate, &candidate, &production),
+        candidate,
+        production,
+    })
+}
+
+/// Uploads the safetensors validation set of `task` in chunks.
+#[ic_cdk::update(guard = "is_controller")]
+pub fn append_validation_bytes(task: String, bytes: Vec<u8>) -> Result<(), String> {
+    crate::agent::global_model(&task)?;
+    storage::append_raw(validation_key(&task), bytes);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+pub fn clear_validation_set(task: String) {
+    storage::remove_raw(&validation_key(&task));
+}
+
+/// Number of samples in the validation set of `task`.
+#[ic_cdk::query(guard = "is_controller")]
+pub fn get_validation_set_size(task: String) -> Result<u64, String> {
+    Ok(load_set(&task)?.map_or(0, |(_, labels)| labels.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_metrics_count_recall_and_rank_ties() {
+        let scores = vec![vec![0.9], vec![0.4], vec![0.6], vec![0.2], vec![0.6]];
+        let labels = [1, 1, 0, 0, 1];
+        let m = metrics(&scores, &labels);
+        assert!((m.accuracy - 0.6).abs() < 1e-9);
+        assert_eq!(m.recall, vec![Some(0.5), Some(2.0 / 3.0)]);
+        // Pairs (positive, negative) ranked correctly: 0.9 beats both, 0.6 beats 0.2 and
+        // ties 0.6, 0.4 beats 0.2.
+        assert!((m.auc.unwrap() - 4.5 / 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn gate_flags_drops_beyond_tolerance() {
+        let production = Metrics { accuracy: 0.9, recall: vec![Some(0.9), Some(0.8)], auc: Some(0.95) };
+        let within = Metrics { accuracy: 0.895, recall: vec![Some(0.89), Some(0.8)], auc: Some(0.945) };
+        assert!(regressions(&ValidationGate::default(), &within, &production).is_empty());
+        let worse = Metrics { accuracy: 0.9, recall: vec![Some(0.9), Some(0.7)], auc: Some(0.95) };
+        assert_eq!(regressions(&ValidationGate::default(), &worse, &production).len(), 1);
+    }
+}
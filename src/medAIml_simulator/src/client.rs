@@ -0,0 +1,115 @@
+use medAIml_backend::checkpoint::Checkpoint;
+use medAIml_backend::client::{ClientUpdate, TrainingConfig};
+use medAIml_backend::delta::UpdateEncoding;
+
+use crate::data::Sample;
+use crate::training::train_local;
+use crate::transport::{poison, Transport};
+
+//A federated client: fetches the global model, trains on its local samples and submits the
+//result. Malicious clients submit a poisoned update instead, to test robust aggregators.
+
+pub struct FederatedClient {
+    pub id: usize,
+    pub samples: Vec<Sample>,
+    // Scale of the flipped update a malicious client submits, `None` for honest clients.
+    pub attack_scale: Option<f64>,
+}
+
+impl FederatedClient {
+    /// Takes part in round `round` of `task`, returning the local training loss.
+    pub fn participate(
+        &self,
+        transport: &mut impl Transport,
+        task: &str,
+        training: &TrainingConfig,
+        round: u64,
+    ) -> Result<f64, String> {
+        let global = transport.global_model(task)?;
+        let base = Checkpoint::from_safetensors(&global.weights)?;
+        let seed = training.seed ^ ((self.id as u64) << 32) ^ round;
+        let local = train_local(task, &global.config, &base, &self.samples, training, seed)?;
+        let checkpoint = match self.attack_scale {
+            Some(scale) => poison(&base, &local.checkpoint, scale)?,
+            None => local.checkpoint,
+        };
+        let update = ClientUpdate {
+            task: task.to_string(),
+            num_samples: self.samples.len() as u64,
+            loss: local.loss,
+            model_version: global.version,
+            proximal_mu: training.proximal_mu,
+            encoding: UpdateEncoding::Full,
+        };
+        transport.submit_update(update, checkpoint.to_safetensors()?)?;
+        Ok(local.loss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::training::evaluate;
+    use crate::transport::{LocalCanister, LocalTransport};
+    use candle_core::{DType, Device, Tensor};
+    use medAIml_backend::aggregation::Aggregator;
+    use medAIml_backend::client::MALARIA_TASK;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    // A four-pixel binary model in the layout of the malaria config.
+    const CONFIG: &str = r#"{"model_type": "mlp", "input_shape": [1, 1, 1, 4], "num_classes": 1,
+        "activation": "relu", "pooling": "none", "hidden_units": [4], "framework": "candle",
+        "pretrained_base": "none", "trainable_base": true,
+        "classifier_head": {"dense_1": {"units": 4, "activation": "relu"}, "output": {"units": 1, "activation": "sigmoid"}}}"#;
+
+    fn initial() -> Checkpoint {
+        let tensor = |values: Vec<f32>, shape: &[usize]| Tensor::from_vec(values, shape, &Device::Cpu).unwrap();
+        let identity: Vec<f32> = (0..16).map(|i| if i % 5 == 0 { 1.0 } else { 0.1 }).collect();
+        let tensors = BTreeMap::from([
+            ("hidden_0.weight".to_string(), tensor(identity.clone(), &[4, 4])),
+            ("hidden_0.bias".to_string(), Tensor::zeros(4, DType::F32, &Device::Cpu).unwrap()),
+            ("classifier.dense_1.weight".to_string(), tensor(identity, &[4, 4])),
+            ("classifier.dense_1.bias".to_string(), Tensor::zeros(4, DType::F32, &Device::Cpu).unwrap()),
+            ("classifier.output.weight".to_string(), tensor(vec![0.1, 0.1, -0.1, -0.1], &[1, 4])),
+            ("classifier.output.bias".to_string(), Tensor::zeros(1, DType::F32, &Device::Cpu).unwrap()),
+        ]);
+        Checkpoint { tensors }
+    }
+
+    #[test]
+    fn simulated_rounds_converge_despite_an_attacker() {
+        // Bright first pixels mean class 0, bright last pixels class 1.
+        let sample = |label: u32| Sample {
+            pixels: if label == 0 { vec![255, 200, 0, 10] } else { vec![10, 0, 200, 255] },
+            label,
+        };
+        let canister = LocalCanister::new(
+            MALARIA_TASK,
+            Aggregator::Median,
+            initial().to_safetensors().unwrap(),
+            CONFIG.as_bytes().to_vec(),
+        )
+        .unwrap();
+        let canister = Rc::new(RefCell::new(canister));
+        let clients: Vec<FederatedClient> = (0..5)
+            .map(|id| FederatedClient {
+                id,
+                samples: (0..8).map(|i| sample(((i + id) % 2) as u32)).collect(),
+                attack_scale: (id == 4).then_some(10.0),
+            })
+            .collect();
+        let training = TrainingConfig { num_epochs: 5, batch_size: 4, learning_rate: 0.5, ..TrainingConfig::default() };
+        for round in 0..5 {
+            for client in &clients {
+                let mut transport = LocalTransport::new(canister.clone(), client.id);
+                client.participate(&mut transport, MALARIA_TASK, &training, round).unwrap();
+            }
+            canister.borrow_mut().close_round().unwrap();
+        }
+        let test = [sample(0), sample(1)];
+        let accuracy = evaluate(MALARIA_TASK, CONFIG.as_bytes(), canister.borrow().global(), &test).unwrap();
+        assert_eq!(accuracy, 1.0);
+    }
+}
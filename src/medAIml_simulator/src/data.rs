@@ -0,0 +1,121 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_distr::{Distribution, Gamma};
+use std::fs;
+use std::path::Path;
+
+//Labelled image folders and their split over the simulated clients. A folder holds one
+//subfolder per class, ordered by name, so `healthy/` and `infected/` become labels 0 and 1.
+
+// Side length the predict endpoints resize images to.
+pub const IMAGE_SIZE: u32 = 224;
+
+/// One labelled image, as the RGB bytes the model reads after scaling to [0, 1].
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub pixels: Vec<u8>,
+    pub label: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Partition {
+    // Every client gets a uniform random share of every class.
+    Iid,
+    // Class proportions per client drawn from Dir(alpha), smaller alphas are more skewed.
+    Dirichlet { alpha: f64 },
+}
+
+impl Partition {
+    /// Parses `iid` or `dirichlet:<alpha>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.split_once(':') {
+            None if value == "iid" => Ok(Partition::Iid),
+            Some(("dirichlet", alpha)) => {
+                let alpha: f64 = alpha.parse().map_err(|_| format!("Invalid Dirichlet alpha {}.", alpha))?;
+                if alpha <= 0.0 {
+                    return Err("The Dirichlet alpha must be positive.".to_string());
+                }
+                Ok(Partition::Dirichlet { alpha })
+            }
+            _ => Err(format!("Unknown partition {}, expected iid or dirichlet:<alpha>.", value)),
+        }
+    }
+}
+
+/// Loads every image below `root`, returning the samples and the class names.
+pub fn load_folder(root: &Path) -> Result<(Vec<Sample>, Vec<String>), String> {
+    let read = |path: &Path| fs::read_dir(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e));
+    let mut classes: Vec<_> = read(root)?.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()).collect();
+    classes.sort();
+    let mut samples = Vec::new();
+    for (label, class) in classes.iter().enumerate() {
+        let mut files: Vec<_> = read(class)?.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect();
+        files.sort();
+        for file in files {
+            let bytes = fs::read(&file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+            // Anything that is not an image is skipped.
+            let Ok(image) = image::load_from_memory(&bytes) else {
+                continue;
+            };
+            let pixels = image
+                .resize_exact(IMAGE_SIZE, IMAGE_SIZE, image::imageops::FilterType::Triangle)
+                .to_rgb8()
+                .into_raw();
+            samples.push(Sample { pixels, label: label as u32 });
+        }
+    }
+    if samples.is_empty() {
+        return Err(format!("No images found below {}.", root.display()));
+    }
+    let names = classes.iter().map(|c| c.file_name().unwrap_or_default().to_string_lossy().into_owned()).collect();
+    Ok((samples, names))
+}
+
+/// Splits the indexes of `labels` over `clients`. Every sample goes to exactly one client.
+pub fn partition(labels: &[u32], clients: usize, partition: &Partition, rng: &mut impl Rng) -> Vec<Vec<usize>> {
+    let mut shares = vec![Vec::new(); clients];
+    let classes = labels.iter().max().map_or(0, |max| *max + 1);
+    for class in 0..classes {
+        let mut members: Vec<usize> = (0..labels.len()).filter(|i| labels[*i] == class).collect();
+        members.shuffle(rng);
+        let proportions: Vec<f64> = match partition {
+            Partition::Iid => vec![1.0; clients],
+            Partition::Dirichlet { alpha } => {
+                // Normalised Gamma(alpha) draws are Dirichlet distributed.
+                let gamma = Gamma::new(*alpha, 1.0).expect("alpha is positive");
+                (0..clients).map(|_| gamma.sample(rng)).collect()
+            }
+        };
+        let total: f64 = proportions.iter().sum();
+        let mut start = 0;
+        let mut cumulative = 0.0;
+        for (client, proportion) in proportions.iter().enumerate() {
+            cumulative += proportion;
+            let end = match client + 1 == clients {
+                true => members.len(),
+                false => ((cumulative / total) * members.len() as f64).round() as usize,
+            };
+            shares[client].extend_from_slice(&members[start..end.max(start)]);
+            start = end.max(start);
+        }
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn partitions_cover_every_sample_once() {
+        let labels: Vec<u32> = (0..300).map(|i| i % 3).collect();
+        for scheme in [Partition::Iid, Partition::parse("dirichlet:0.1").unwrap()] {
+            let shares = partition(&labels, 7, &scheme, &mut StdRng::seed_from_u64(1));
+            let mut all: Vec<usize> = shares.concat();
+            all.sort();
+            assert_eq!(all, (0..300).collect::<Vec<_>>());
+        }
+    }
+}
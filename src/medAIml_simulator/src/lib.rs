@@ -0,0 +1,18 @@
+//Reference federated learning client and an offline simulator. Clients hold a partition of a
+//labelled image folder, train the model definitions of the canister locally with candle and
+//submit their updates through a `Transport`. `LocalCanister` stands in for the canister in
+//process, aggregating with its aggregators, so convergence, non-IID splits and robustness
+//strategies can be benchmarked without clinics or a replica.
+
+// The crate is named like the canister crates.
+#![allow(non_snake_case)]
+
+pub mod client;
+pub mod data;
+pub mod training;
+pub mod transport;
+
+pub use client::FederatedClient;
+pub use data::{load_folder, partition, Partition, Sample};
+pub use training::{evaluate, initial_weights, train_local, LocalResult};
+pub use transport::{poison, GlobalModel, LocalCanister, LocalTransport, RoundSummary, Transport};
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, bail, Context, Result};
+use medAIml_backend::aggregation::Aggregator;
+use medAIml_backend::client::TrainingConfig;
+use medAIml_simulator::{evaluate, initial_weights, load_folder, partition, FederatedClient, LocalCanister, LocalTransport, Partition, Sample};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+//Simulates federated training of one task offline and prints one CSV line per round:
+//
+//    medAIml_simulator --data <folder> --config <config.json> [--weights <model.safetensors>]
+//        [--task malaria] [--clients 10] [--rounds 20] [--partition iid|dirichlet:<alpha>]
+//        [--aggregator fedavg|median|trimmed:<fraction>|krum:<f>|multikrum:<f>:<m>|normbound:<norm>]
+//        [--malicious 0] [--attack-scale 10] [--epochs 1] [--batch-size 32] [--lr 0.01]
+//        [--mu 0] [--test-fraction 0.2] [--seed 42] [--output <results.csv>]
+//
+//The model constructors log to stdout, so `--output` keeps the CSV apart from them.
+
+const USAGE: &str = "usage: medAIml_simulator --data <folder> --config <config.json> [--weights <model.safetensors>] [options]";
+
+fn parse_aggregator(value: &str) -> Result<Aggregator> {
+    let parts: Vec<&str> = value.split(':').collect();
+    let number = |i: usize| -> Result<f64> {
+        parts.get(i).ok_or_else(|| anyhow!("{} needs more parameters", value))?.parse().context(value.to_string())
+    };
+    Ok(match parts[0] {
+        "fedavg" => Aggregator::FedAvg,
+        "median" => Aggregator::Median,
+        "trimmed" => Aggregator::TrimmedMean { trim_fraction: number(1)? },
+        "krum" => Aggregator::Krum { byzantine: number(1)? as u64 },
+        "multikrum" => Aggregator::MultiKrum { byzantine: number(1)? as u64, select: number(2)? as u64 },
+        "normbound" => Aggregator::NormBounded { max_norm: number(1)? },
+        _ => bail!("unknown aggregator {}", value),
+    })
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.len().is_multiple_of(2) || args.iter().any(|a| a == "--help") {
+        bail!(USAGE);
+    }
+    let options: HashMap<&str, &str> = args
+        .chunks(2)
+        .map(|pair| Ok((pair[0].strip_prefix("--").ok_or_else(|| anyhow!(USAGE))?, pair[1].as_str())))
+        .collect::<Result<_>>()?;
+    let required = |key: &str| options.get(key).copied().ok_or_else(|| anyhow!("--{} is required\n{}", key, USAGE));
+    let number = |key: &str, default: f64| -> Result<f64> {
+        options.get(key).map_or(Ok(default), |v| v.parse().with_context(|| format!("--{}", key)))
+    };
+
+    let task = options.get("task").copied().unwrap_or("malaria");
+    let clients = number("clients", 10.0)? as usize;
+    let rounds = number("rounds", 20.0)? as u64;
+    let malicious = number("malicious", 0.0)? as usize;
+    let attack_scale = number("attack-scale", 10.0)?;
+    let test_fraction = number("test-fraction", 0.2)?;
+    let seed = number("seed", 42.0)? as u64;
+    let partition_scheme = Partition::parse(options.get("partition").copied().unwrap_or("iid")).map_err(|e| anyhow!(e))?;
+    let aggregator = parse_aggregator(options.get("aggregator").copied().unwrap_or("fedavg"))?;
+    let training = TrainingConfig {
+        num_epochs: number("epochs", 1.0)? as u64,
+        batch_size: number("batch-size", 32.0)? as u64,
+        learning_rate: number("lr", 0.01)?,
+        proximal_mu: number("mu", 0.0)?,
+        seed,
+        ..TrainingConfig::default()
+    };
+    if clients == 0 || malicious > clients {
+        bail!("--clients must be positive and at least --malicious");
+    }
+    if !(0.0..1.0).contains(&test_fraction) {
+        bail!("--test-fraction must lie in [0, 1)");
+    }
+
+    let (mut samples, classes) = load_folder(&PathBuf::from(required("data")?)).map_err(|e| anyhow!(e))?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    samples.shuffle(&mut rng);
+    let test: Vec<Sample> = samples.split_off(samples.len() - (samples.len() as f64 * test_fraction) as usize);
+    let labels: Vec<u32> = samples.iter().map(|s| s.label).collect();
+    let shares = partition(&labels, clients, &partition_scheme, &mut rng);
+    eprintln!(
+        "{} training and {} test images of classes {:?}, client sizes {:?}",
+        samples.len(),
+        test.len(),
+        classes,
+        shares.iter().map(|s| s.len()).collect::<Vec<_>>()
+    );
+    let federated: Vec<FederatedClient> = shares
+        .into_iter()
+        .enumerate()
+        .map(|(id, share)| FederatedClient {
+            id,
+            samples: share.into_iter().map(|i| samples[i].clone()).collect(),
+            // The last clients are the malicious ones.
+            attack_scale: (id >= clients - malicious).then_some(attack_scale),
+        })
+        .collect();
+
+    let config = std::fs::read(required("config")?).context("--config")?;
+    // Without a starting checkpoint the model starts from random weights.
+    let weights = match options.get("weights") {
+        Some(path) => std::fs::read(path).context("--weights")?,
+        None => initial_weights(task, &config).and_then(|c| c.to_safetensors()).map_err(|e| anyhow!(e))?,
+    };
+    let canister = LocalCanister::new(task, aggregator, weights, config.clone()).map_err(|e| anyhow!(e))?;
+    let canister = Rc::new(RefCell::new(canister));
+
+    let mut output: Box<dyn Write> = match options.get("output") {
+        Some(path) => Box::new(std::fs::File::create(path).context("--output")?),
+        None => Box::new(std::io::stdout()),
+    };
+    writeln!(output, "round,version,participants,num_samples,mean_loss,test_accuracy,excluded,clipped")?;
+    for round in 1..=rounds {
+        for client in federated.iter().filter(|c| !c.samples.is_empty()) {
+            let mut transport = LocalTransport::new(canister.clone(), client.id);
+            if let Err(e) = client.participate(&mut transport, task, &training, round) {
+                eprintln!("Client {} skipped round {}: {}", client.id, round, e);
+            }
+        }
+        let summary = canister.borrow_mut().close_round().map_err(|e| anyhow!(e))?;
+        let accuracy = evaluate(task, &config, canister.borrow().global(), &test).map_err(|e| anyhow!(e))?;
+        let ids = |clients: &[usize]| clients.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(" ");
+        writeln!(
+            output,
+            "{},{},{},{},{:.6},{:.4},{},{}",
+            round,
+            summary.version,
+            summary.participants.len(),
+            summary.num_samples,
+            summary.mean_loss,
+            accuracy,
+            ids(&summary.excluded),
+            ids(&summary.clipped)
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use candle_core::{DType, Device, Module, Tensor, Var};
+use candle_nn::{loss, Optimizer, Sequential, VarBuilder, VarMap, SGD};
+use medAIml_backend::checkpoint::Checkpoint;
+use medAIml_backend::client::{proximal_term, MalariaModelV3, ModelConfig, TrainingConfig, MALARIA_TASK};
+use medAIml_backend::client_type::{MalariaModelV3Types as StageModel, ModelConfigStage, MALARIA_STAGE_TASK};
+use medAIml_backend::malaria_types::{MalariaModelV3Types as TypeModel, ModelConfiguration, MALARIA_TYPE_TASK};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::data::Sample;
+
+//Local training and evaluation with the model definitions of the canister, the way a clinic
+//trains before it submits an update.
+
+const DEVICE: Device = Device::Cpu;
+
+/// A locally trained checkpoint and its mean training loss over the last epoch.
+pub struct LocalResult {
+    pub checkpoint: Checkpoint,
+    pub loss: f64,
+}
+
+/// Builds the model of `task` from its config, with the variables of `vb`.
+fn model(task: &str, config: &[u8], vb: VarBuilder) -> Result<Sequential, String> {
+    let parse_error = |e: serde_json::Error| format!("Failed to deserialize model config: {:?}", e);
+    let model = match task {
+        MALARIA_TASK => MalariaModelV3::new(serde_json::from_slice::<ModelConfig>(config).map_err(parse_error)?, vb)
+            .map(|m| m.model),
+        MALARIA_STAGE_TASK => StageModel::new(serde_json::from_slice::<ModelConfigStage>(config).map_err(parse_error)?, vb)
+            .map(|m| m.model),
+        MALARIA_TYPE_TASK => TypeModel::new(serde_json::from_slice::<ModelConfiguration>(config).map_err(parse_error)?, vb)
+            .map(|m| m.model),
+        _ => return Err(format!("Unknown task {}.", task)),
+    };
+    model.map_err(|e| format!("Model creation error: {:?}", e))
+}
+
+/// Variables holding `checkpoint`, so the model is built on top of it.
+fn varmap(checkpoint: &Checkpoint) -> Result<VarMap, String> {
+    let varmap = VarMap::new();
+    {
+        let mut data = varmap.data().lock().unwrap();
+        for (name, tensor) in &checkpoint.tensors {
+            let var = tensor
+                .to_dtype(DType::F32)
+                .and_then(|t| Var::from_tensor(&t))
+                .map_err(|e| format!("{:?}", e))?;
+            data.insert(name.clone(), var);
+        }
+    }
+    Ok(varmap)
+}
+
+fn batch(samples: &[&Sample]) -> Result<(Tensor, Tensor), String> {
+    let width = samples.first().map_or(0, |s| s.pixels.len());
+    let pixels: Vec<f32> = samples.iter().flat_map(|s| s.pixels.iter().map(|p| *p as f32 / 255.0)).collect();
+    let labels: Vec<u32> = samples.iter().map(|s| s.label).collect();
+    let inputs = Tensor::from_vec(pixels, (samples.len(), width), &DEVICE).map_err(|e| format!("{:?}", e))?;
+    let labels = Tensor::new(labels.as_slice(), &DEVICE).map_err(|e| format!("{:?}", e))?;
+    Ok((inputs, labels))
+}
+
+// Binary models have one sigmoid output, the others a softmax over their classes.
+fn objective(logits: &Tensor, labels: &Tensor) -> candle_core::Result<Tensor> {
+    match logits.dim(1)? {
+        1 => loss::binary_cross_entropy_with_logit(logits, &labels.to_dtype(DType::F32)?.unsqueeze(1)?),
+        _ => loss::cross_entropy(logits, labels),
+    }
+}
+
+/// Trains `global` of `task` on `samples` with SGD, adding the FedProx term when
+/// `training.proximal_mu` is positive. `seed` orders the batches.
+pub fn train_local(
+    task: &str,
+    config: &[u8],
+    global: &Checkpoint,
+    samples: &[Sample],
+    training: &TrainingConfig,
+    seed: u64,
+) -> Result<LocalResult, String> {
+    training.validate()?;
+    if samples.is_empty() {
+        return Err("No local samples to train on.".to_string());
+    }
+    let varmap = varmap(global)?;
+    let model = model(task, config, VarBuilder::from_varmap(&varmap, DType::F32, &DEVICE))?;
+    let mut optimizer = SGD::new(varmap.all_vars(), training.learning_rate).map_err(|e| format!("{:?}", e))?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut order: Vec<usize> = (0..samples.len()).collect();
+    let mut loss = 0.0;
+    for _ in 0..training.num_epochs {
+        order.shuffle(&mut rng);
+        let mut loss_sum = 0.0;
+        for chunk in order.chunks(training.batch_size as usize) {
+            let (inputs, labels) = batch(&chunk.iter().map(|i| &samples[*i]).collect::<Vec<_>>())?;
+            let objective = model
+                .forward(&inputs)
+                .and_then(|logits| objective(&logits, &labels))
+                .map_err(|e| format!("Training forward pass failed: {:?}", e))?;
+            loss_sum += objective.to_scalar::<f32>().map_err(|e| format!("{:?}", e))? as f64 * chunk.len() as f64;
+            let total = proximal_term(&varmap, global, training.proximal_mu)
+                .and_then(|term| objective.add(&term))
+                .and_then(|total| optimizer.backward_step(&total))
+                .map_err(|e| format!("Optimizer step failed: {:?}", e));
+            total?;
+        }
+        loss = loss_sum / samples.len() as f64;
+    }
+    let data = varmap.data().lock().unwrap();
+    let tensors = data.iter().map(|(name, var)| (name.clone(), var.as_tensor().detach())).collect();
+    Ok(LocalResult { checkpoint: Checkpoint { tensors }, loss })
+}
+
+/// Freshly initialised weights for the model of `task`.
+pub fn initial_weights(task: &str, config: &[u8]) -> Result<Checkpoint, String> {
+    let varmap = VarMap::new();
+    model(task, config, VarBuilder::from_varmap(&varmap, DType::F32, &DEVICE))?;
+    let data = varmap.data().lock().unwrap();
+    Ok(Checkpoint { tensors: data.iter().map(|(name, var)| (name.clone(), var.as_tensor().detach())).collect() })
+}
+
+/// Accuracy of `checkpoint` of `task` on `samples`.
+pub fn evaluate(task: &str, config: &[u8], checkpoint: &Checkpoint, samples: &[Sample]) -> Result<f64, String> {
+    let tensors = checkpoint.tensors.clone().into_iter().collect();
+    let model = model(task, config, VarBuilder::from_tensors(tensors, DType::F32, &DEVICE))?;
+    let mut correct = 0;
+    for chunk in samples.chunks(64) {
+        let (inputs, labels) = batch(&chunk.iter().collect::<Vec<_>>())?;
+        let logits = model.forward(&inputs).map_err(|e| format!("Evaluation forward pass failed: {:?}", e))?;
+        let predictions = match logits.dim(1).map_err(|e| format!("{:?}", e))? {
+            1 => logits.ge(0.0).and_then(|p| p.squeeze(1)).and_then(|p| p.to_dtype(DType::U32)),
+            _ => logits.argmax(1),
+        }
+        .and_then(|p| p.to_vec1::<u32>())
+        .map_err(|e| format!("{:?}", e))?;
+        let labels = labels.to_vec1::<u32>().map_err(|e| format!("{:?}", e))?;
+        correct += predictions.iter().zip(&labels).filter(|(p, l)| p == l).count();
+    }
+    Ok(correct as f64 / samples.len().max(1) as f64)
+}
@@ -0,0 +1,154 @@
+use candle_core::Tensor;
+use medAIml_backend::aggregation::{self, Aggregator, WeightedUpdate};
+use medAIml_backend::checkpoint::Checkpoint;
+use medAIml_backend::client::ClientUpdate;
+use medAIml_backend::delta::UpdateEncoding;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+//How clients talk to the coordinator. `Transport` covers the calls a client makes to the
+//canister, and `LocalCanister` answers them in process. Only the per-update checks and the
+//aggregators are the code of the canister. Quorum, deadlines, staleness, delta encodings, the
+//server optimiser, privacy, anomaly screening and the validation gate are not simulated.
+
+/// What `get_global_model` serves: the production version, its weights and config.
+#[derive(Clone, Debug)]
+pub struct GlobalModel {
+    pub version: u64,
+    pub weights: Vec<u8>,
+    pub config: Vec<u8>,
+}
+
+pub trait Transport {
+    fn global_model(&mut self, task: &str) -> Result<GlobalModel, String>;
+
+    /// Uploads the safetensors `weights` of an update and submits it, like
+    /// `append_update_bytes` followed by `submit_update`.
+    fn submit_update(&mut self, update: ClientUpdate, weights: Vec<u8>) -> Result<(), String>;
+}
+
+/// Outcome of a round closed by `LocalCanister`.
+#[derive(Clone, Debug)]
+pub struct RoundSummary {
+    pub version: u64,
+    pub participants: Vec<usize>,
+    pub num_samples: u64,
+    pub mean_loss: f64,
+    // Clients the aggregator left out, and those whose update it scaled down.
+    pub excluded: Vec<usize>,
+    pub clipped: Vec<usize>,
+}
+
+struct Submission {
+    client: usize,
+    update: ClientUpdate,
+    checkpoint: Checkpoint,
+}
+
+/// In-process stand-in for the federated learning endpoints of the canister, for one task.
+pub struct LocalCanister {
+    task: String,
+    aggregator: Aggregator,
+    model: GlobalModel,
+    global: Checkpoint,
+    submissions: Vec<Submission>,
+}
+
+impl LocalCanister {
+    pub fn new(task: &str, aggregator: Aggregator, weights: Vec<u8>, config: Vec<u8>) -> Result<Self, String> {
+        aggregator.validate()?;
+        let global = Checkpoint::from_safetensors(&weights)?;
+        Ok(LocalCanister {
+            task: task.to_string(),
+            aggregator,
+            model: GlobalModel { version: 1, weights, config },
+            global,
+            submissions: Vec::new(),
+        })
+    }
+
+    pub fn global(&self) -> &Checkpoint {
+        &self.global
+    }
+
+    fn submit(&mut self, client: usize, update: ClientUpdate, weights: Vec<u8>) -> Result<(), String> {
+        if update.task != self.task {
+            return Err(format!("Unknown task {}.", update.task));
+        }
+        update.check(self.model.version, 0)?;
+        if update.encoding != UpdateEncoding::Full {
+            return Err("The local canister only accepts full checkpoints.".to_string());
+        }
+        if self.submissions.iter().any(|s| s.client == client) {
+            return Err(format!("Client {} already submitted an update for this round.", client));
+        }
+        let checkpoint = Checkpoint::from_update(&weights, &self.global)?;
+        self.submissions.push(Submission { client, update, checkpoint });
+        Ok(())
+    }
+
+    /// Aggregates the submissions into the next global version.
+    pub fn close_round(&mut self) -> Result<RoundSummary, String> {
+        let submissions = std::mem::take(&mut self.submissions);
+        let updates: Vec<WeightedUpdate> = submissions
+            .iter()
+            .map(|s| WeightedUpdate { checkpoint: s.checkpoint.clone(), weight: s.update.num_samples as f64 })
+            .collect();
+        let result = aggregation::aggregate(&self.aggregator, &self.global, &updates)?;
+        self.global = result.checkpoint;
+        self.model.version += 1;
+        self.model.weights = self.global.to_safetensors()?;
+        let num_samples = submissions.iter().map(|s| s.update.num_samples).sum::<u64>();
+        let weighted_loss = submissions.iter().map(|s| s.update.loss * s.update.num_samples as f64).sum::<f64>();
+        Ok(RoundSummary {
+            version: self.model.version,
+            participants: submissions.iter().map(|s| s.client).collect(),
+            num_samples,
+            mean_loss: weighted_loss / num_samples.max(1) as f64,
+            excluded: result.excluded.iter().map(|i| submissions[*i].client).collect(),
+            clipped: result.clipped.iter().map(|i| submissions[*i].client).collect(),
+        })
+    }
+}
+
+/// The transport of one simulated client to a shared `LocalCanister`.
+pub struct LocalTransport {
+    canister: Rc<RefCell<LocalCanister>>,
+    client: usize,
+}
+
+impl LocalTransport {
+    pub fn new(canister: Rc<RefCell<LocalCanister>>, client: usize) -> Self {
+        LocalTransport { canister, client }
+    }
+}
+
+impl Transport for LocalTransport {
+    fn global_model(&mut self, task: &str) -> Result<GlobalModel, String> {
+        let canister = self.canister.borrow();
+        if task != canister.task {
+            return Err(format!("Unknown task {}.", task));
+        }
+        Ok(canister.model.clone())
+    }
+
+    fn submit_update(&mut self, update: ClientUpdate, weights: Vec<u8>) -> Result<(), String> {
+        self.canister.borrow_mut().submit(self.client, update, weights)
+    }
+}
+
+/// Turns a trained checkpoint into a model-poisoning update: the change to `global` flipped
+/// and multiplied by `scale`.
+pub fn poison(global: &Checkpoint, trained: &Checkpoint, scale: f64) -> Result<Checkpoint, String> {
+    let tensors = trained
+        .tensors
+        .iter()
+        .map(|(name, tensor)| {
+            let base = &global.tensors[name];
+            let poisoned: candle_core::Result<Tensor> =
+                tensor.sub(base).and_then(|delta| delta.affine(-scale, 0.0)).and_then(|delta| base.add(&delta));
+            poisoned.map(|t| (name.clone(), t)).map_err(|e| format!("{:?}", e))
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(Checkpoint { tensors })
+}